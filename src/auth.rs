@@ -0,0 +1,111 @@
+use crate::hmac::{hex, sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+#[derive(PartialEq)]
+pub enum AuthResult {
+    Granted,
+    Denied,
+}
+
+// Checks the credentials given to /oper
+pub trait Authenticator {
+    fn authenticate(&self, user: &str, secret: &str) -> AuthResult;
+}
+
+// One password for everyone, whatever the user name
+pub struct SharedPassword(pub String);
+
+impl Authenticator for SharedPassword {
+    fn authenticate(&self, _user: &str, secret: &str) -> AuthResult {
+        granted_if(constant_time_eq(self.0.as_bytes(), secret.as_bytes()))
+    }
+}
+
+// Users from a file of `user:<hex SHA-256 of the password>` lines, like an
+// htpasswd file. Blank lines and lines starting with `#` are skipped.
+pub struct FileBackedUsers {
+    users: HashMap<String, String>,
+}
+
+impl FileBackedUsers {
+    pub fn load(path: &Path) -> Result<Self, io::Error> {
+        let mut users = HashMap::new();
+        for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((user, hash)) = line.split_once(':') else {
+                let msg = format!("{}:{}: expected user:hash", path.display(), i + 1);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            };
+            users.insert(user.to_string(), hash.to_ascii_lowercase());
+        }
+        Ok(Self { users })
+    }
+}
+
+impl Authenticator for FileBackedUsers {
+    fn authenticate(&self, user: &str, secret: &str) -> AuthResult {
+        let hash = hex(&sha256(secret.as_bytes()));
+        let ok = self
+            .users
+            .get(user)
+            .is_some_and(|expected| constant_time_eq(expected.as_bytes(), hash.as_bytes()));
+        granted_if(ok)
+    }
+}
+
+fn granted_if(ok: bool) -> AuthResult {
+    if ok {
+        AuthResult::Granted
+    } else {
+        AuthResult::Denied
+    }
+}
+
+// Takes as long whichever byte differs, so timing doesn't reveal the secret
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// A hard to guess secret handed out to be presented later, as hex
+pub fn random_token() -> io::Result<String> {
+    let mut bytes = [0; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(hex(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_password_ignores_the_user() {
+        let auth = SharedPassword("hunter2".into());
+        assert!(auth.authenticate("", "hunter2") == AuthResult::Granted);
+        assert!(auth.authenticate("anyone", "hunter2") == AuthResult::Granted);
+        assert!(auth.authenticate("", "hunter") == AuthResult::Denied);
+        assert!(auth.authenticate("", "") == AuthResult::Denied);
+    }
+
+    #[test]
+    fn users_file_checks_each_user() {
+        let path = std::env::temp_dir().join(format!("smallchat-users-{}", std::process::id()));
+        let hash = hex(&sha256(b"hunter2"));
+        let text = format!("# admins\n\nalice:{hash}\nbob:{}\n", hash.to_uppercase());
+        fs::write(&path, text).unwrap();
+        let auth = FileBackedUsers::load(&path).unwrap();
+        assert!(auth.authenticate("alice", "hunter2") == AuthResult::Granted);
+        assert!(auth.authenticate("bob", "hunter2") == AuthResult::Granted);
+        assert!(auth.authenticate("alice", "hunter3") == AuthResult::Denied);
+        assert!(auth.authenticate("carol", "hunter2") == AuthResult::Denied);
+        fs::write(&path, "alice\n").unwrap();
+        let error = FileBackedUsers::load(&path).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+use std::time::{Duration, Instant};
+
+// Fewer bytes than this aren't worth a wakeup, unless the rate is lower
+const MIN_BURST: usize = 1024;
+
+// Allows `rate` bytes, or messages, per second on average, with bursts of up
+// to a second's worth
+pub struct TokenBucket {
+    rate: usize,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: usize) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            updated: Instant::now(),
+        }
+    }
+    // How many bytes may be sent right now
+    pub fn available(&mut self) -> usize {
+        let now = Instant::now();
+        let elapsed = (now - self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.updated = now;
+        self.tokens as usize
+    }
+    // Refills at the old rate up to now, so only what follows uses the new one
+    pub fn set_rate(&mut self, rate: usize) {
+        if rate != self.rate {
+            self.available();
+            self.rate = rate;
+            self.tokens = self.tokens.min(rate as f64);
+        }
+    }
+    pub fn consume(&mut self, n: usize) {
+        self.tokens -= n as f64;
+    }
+    // When enough will have accumulated to be worth sending again
+    pub fn ready_at(&self) -> Instant {
+        let burst = self.rate.min(MIN_BURST) as f64;
+        let missing = (burst - self.tokens).max(0.0);
+        self.updated + Duration::from_secs_f64(missing / self.rate as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refills_at_the_rate_up_to_a_second() {
+        let mut bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.available(), 1000);
+        bucket.consume(1000);
+        assert!(bucket.available() < 10);
+        bucket.updated -= Duration::from_millis(500);
+        assert!((500..510).contains(&bucket.available()));
+        bucket.updated -= Duration::from_secs(10);
+        assert_eq!(bucket.available(), 1000);
+    }
+
+    #[test]
+    fn ready_once_a_burst_is_back() {
+        let mut bucket = TokenBucket::new(100_000);
+        bucket.consume(100_000);
+        let wait = bucket.ready_at() - bucket.updated;
+        assert_eq!(wait, Duration::from_secs_f64(MIN_BURST as f64 / 100_000.0));
+        // A rate below a burst waits for the whole of it
+        let mut slow = TokenBucket::new(100);
+        slow.consume(100);
+        assert_eq!(slow.ready_at() - slow.updated, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn a_lower_rate_caps_what_was_saved_up() {
+        let mut bucket = TokenBucket::new(10);
+        bucket.set_rate(2);
+        assert_eq!(bucket.available(), 2);
+        bucket.consume(2);
+        bucket.updated -= Duration::from_millis(500);
+        assert_eq!(bucket.available(), 1);
+    }
+}
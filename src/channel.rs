@@ -0,0 +1,113 @@
+use mio::Token;
+use std::collections::{HashSet, VecDeque};
+use std::time::Instant;
+
+// Past topics kept for /topic history and /topic undo
+const MAX_TOPIC_HISTORY: usize = 8;
+
+pub struct Topic {
+    pub text: String,
+    pub set_by: String,
+    pub at: Instant,
+}
+
+// Metadata of a channel with at least one member. Membership itself is
+// `Client::channel`.
+#[derive(Default)]
+pub struct Channel {
+    // Only admins and invited clients may join
+    pub invite_only: bool,
+    pub key: Option<String>,
+    pub invited: HashSet<Token>,
+    pub topic: Option<Topic>,
+    // Topics replaced by later ones, oldest first
+    pub topic_history: VecDeque<Topic>,
+    // Shown to everyone joining, set by admins with /pin
+    pub pinned: Option<String>,
+}
+
+impl Channel {
+    pub fn set_topic(&mut self, text: String, set_by: String) {
+        let topic = Topic {
+            text,
+            set_by,
+            at: Instant::now(),
+        };
+        if let Some(old) = self.topic.replace(topic) {
+            if self.topic_history.len() == MAX_TOPIC_HISTORY {
+                self.topic_history.pop_front();
+            }
+            self.topic_history.push_back(old);
+        }
+    }
+    // Brings back the previous topic, if there's one
+    pub fn undo_topic(&mut self) -> Option<&Topic> {
+        self.topic = Some(self.topic_history.pop_back()?);
+        self.topic.as_ref()
+    }
+    pub fn check_join(
+        &self,
+        token: Token,
+        key: Option<&str>,
+        admin: bool,
+    ) -> Result<(), &'static str> {
+        if admin {
+            return Ok(());
+        }
+        if self.invite_only && !self.invited.contains(&token) {
+            return Err("is invite-only");
+        }
+        if self.key.is_some() && self.key.as_deref() != key {
+            return Err("needs the right key (/join #name <key>)");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_checks_invites_and_keys() {
+        let (guest, invited) = (Token(20), Token(21));
+        let mut channel = Channel::default();
+        assert!(channel.check_join(guest, None, false).is_ok());
+        channel.key = Some("sesame".into());
+        assert!(channel.check_join(guest, None, false).is_err());
+        assert!(channel.check_join(guest, Some("wrong"), false).is_err());
+        assert!(channel.check_join(guest, Some("sesame"), false).is_ok());
+        channel.key = None;
+        channel.invite_only = true;
+        channel.invited.insert(invited);
+        assert_eq!(
+            channel.check_join(guest, None, false),
+            Err("is invite-only")
+        );
+        assert!(channel.check_join(invited, None, false).is_ok());
+        // Admins get in regardless
+        channel.key = Some("sesame".into());
+        assert!(channel.check_join(guest, None, true).is_ok());
+    }
+
+    #[test]
+    fn topic_history_is_bounded_and_undone_in_order() {
+        let mut channel = Channel::default();
+        assert!(channel.undo_topic().is_none());
+        for n in 0..MAX_TOPIC_HISTORY + 3 {
+            channel.set_topic(format!("topic {n}"), "alice".into());
+        }
+        assert_eq!(channel.topic_history.len(), MAX_TOPIC_HISTORY);
+        assert_eq!(channel.topic_history[0].text, "topic 2");
+        let last = MAX_TOPIC_HISTORY + 2;
+        assert_eq!(
+            channel.undo_topic().unwrap().text,
+            format!("topic {}", last - 1)
+        );
+        assert_eq!(
+            channel.undo_topic().unwrap().text,
+            format!("topic {}", last - 2)
+        );
+        assert_eq!(channel.topic_history.len(), MAX_TOPIC_HISTORY - 2);
+    }
+}
@@ -0,0 +1,565 @@
+use crate::json::Value;
+use std::fmt;
+
+// A line received from a client, classified by the command it starts with.
+// Anything that isn't a known command is a chat message.
+pub enum Command<'a> {
+    Alias(&'a [u8], &'a [u8]),
+    Binary,
+    Buffers,
+    Channels,
+    Commands,
+    // Nicks starting with the prefix, all of them if it's empty
+    Complete(&'a [u8]),
+    Crlf(bool),
+    Debug(bool),
+    // Takes admin rights away from a nick
+    Deop(&'a [u8]),
+    Drain,
+    Encoding(&'a [u8]),
+    ForceNick(&'a [u8], &'a [u8]),
+    Fortune,
+    // Empty to list every setting
+    Get(&'a [u8]),
+    // Empty to show it, or the messages the room's history keeps
+    HistoryLimit(&'a [u8]),
+    Invite(&'a [u8], &'a [u8]),
+    // Mints a one-time token for /login-token
+    InviteToken,
+    Json,
+    Lag,
+    LoginToken(&'a [u8]),
+    Join(&'a [u8], Option<&'a [u8]>),
+    Maintenance(bool),
+    Mode(&'a [u8], &'a [u8]),
+    Motd,
+    Msg(&'a [u8], &'a [u8]),
+    Nick(&'a [u8]),
+    // Gives admin rights to a nick
+    Op(&'a [u8]),
+    // An optional user name, then the secret
+    Oper(Option<&'a [u8]>, &'a [u8]),
+    Part,
+    // `None` to show it, empty to clear it, or the text to pin
+    Pin(Option<&'a [u8]>),
+    // Empty to show it, `close`, or a new poll
+    Poll(&'a [u8]),
+    Quit,
+    Raw(bool),
+    Readline(bool),
+    React(u64, &'a [u8]),
+    Read,
+    Receipts(bool),
+    Recent,
+    // A nick and why it's reported to the admins
+    Report(&'a [u8], &'a [u8]),
+    // A chat message answering the one with this id
+    Reply(u64, &'a [u8]),
+    // Every client as JSON, for admins
+    Roster,
+    // Recorded messages containing the term
+    Search(&'a [u8]),
+    Seen(&'a [u8]),
+    // A chat message with an id of the client's choosing, optionally a reply
+    Send(&'a [u8], Option<u64>, &'a [u8]),
+    Set(&'a [u8], &'a [u8]),
+    SetPrompt(&'a [u8]),
+    Status(&'a [u8], &'a [u8]),
+    Stats,
+    // Empty to show it, `history`, `undo`, or a new topic
+    Topic(&'a [u8]),
+    Unalias(&'a [u8]),
+    Time,
+    Version,
+    // The option voted for, `None` if it isn't a number
+    Vote(Option<usize>),
+    Whois(&'a [u8]),
+    Message(&'a [u8]),
+}
+
+// Who may use a command
+#[derive(Clone, Copy, PartialEq)]
+pub enum Access {
+    Everyone,
+    // Clients that set a nick, when `--require-nick` asks for one
+    Named,
+    Admins,
+}
+
+// A slash command: its name, who may use it, and how its arguments are
+// read. A line whose arguments don't parse is a chat message.
+pub struct Spec {
+    pub name: &'static str,
+    pub access: Access,
+    parse: for<'a> fn(&'a [u8]) -> Option<Command<'a>>,
+}
+
+const fn spec(
+    name: &'static str,
+    access: Access,
+    parse: for<'a> fn(&'a [u8]) -> Option<Command<'a>>,
+) -> Spec {
+    Spec {
+        name,
+        access,
+        parse,
+    }
+}
+
+fn on_off(args: &[u8]) -> Option<bool> {
+    match args {
+        b"on" => Some(true),
+        b"off" => Some(false),
+        _ => None,
+    }
+}
+
+fn required(args: &[u8]) -> Option<&[u8]> {
+    (!args.is_empty()).then_some(args)
+}
+
+// Every slash command, for both `Command::parse` and /commands. The
+// permission checks are where each is run.
+pub const COMMANDS: &[Spec] = &[
+    spec("/alias", Access::Everyone, |args| {
+        let (alias, text) = Command::split(required(args)?);
+        Some(Command::Alias(alias, text))
+    }),
+    spec("/binary", Access::Everyone, |_| Some(Command::Binary)),
+    spec("/buffers", Access::Admins, |_| Some(Command::Buffers)),
+    spec("/channels", Access::Everyone, |_| Some(Command::Channels)),
+    spec("/commands", Access::Everyone, |_| Some(Command::Commands)),
+    spec("/complete", Access::Everyone, |args| {
+        Some(Command::Complete(args))
+    }),
+    spec("/crlf", Access::Everyone, |args| {
+        Some(Command::Crlf(on_off(args)?))
+    }),
+    spec("/debug", Access::Everyone, |args| {
+        Some(Command::Debug(on_off(args)?))
+    }),
+    spec("/deop", Access::Admins, |args| {
+        Some(Command::Deop(required(args)?))
+    }),
+    spec("/drain", Access::Admins, |_| Some(Command::Drain)),
+    spec("/encoding", Access::Everyone, |args| {
+        Some(Command::Encoding(required(args)?))
+    }),
+    spec("/forcenick", Access::Admins, |args| {
+        let (old, new) = Command::split(required(args)?);
+        Some(Command::ForceNick(old, new))
+    }),
+    spec("/fortune", Access::Named, |_| Some(Command::Fortune)),
+    spec("/get", Access::Admins, |args| Some(Command::Get(args))),
+    spec("/history-limit", Access::Admins, |args| {
+        Some(Command::HistoryLimit(args))
+    }),
+    spec("/invite", Access::Admins, |args| match required(args)? {
+        b"new" => Some(Command::InviteToken),
+        args => {
+            let (nick, channel) = Command::split(args);
+            Some(Command::Invite(nick, channel))
+        }
+    }),
+    spec("/join", Access::Everyone, |args| {
+        let (channel, key) = Command::split(required(args)?);
+        Some(Command::Join(channel, required(key)))
+    }),
+    spec("/json", Access::Everyone, |_| Some(Command::Json)),
+    spec("/lag", Access::Everyone, |_| Some(Command::Lag)),
+    spec("/login-token", Access::Everyone, |args| {
+        Some(Command::LoginToken(required(args)?))
+    }),
+    spec("/maintenance", Access::Admins, |args| {
+        Some(Command::Maintenance(on_off(args)?))
+    }),
+    spec("/mode", Access::Admins, |args| {
+        let (channel, modes) = Command::split(required(args)?);
+        Some(Command::Mode(channel, modes))
+    }),
+    spec("/motd", Access::Everyone, |_| Some(Command::Motd)),
+    spec("/msg", Access::Named, |args| {
+        let (nick, text) = Command::split(required(args)?);
+        Some(Command::Msg(nick, text))
+    }),
+    spec("/nick", Access::Everyone, |args| {
+        Some(Command::Nick(required(args)?))
+    }),
+    spec("/op", Access::Admins, |args| {
+        Some(Command::Op(required(args)?))
+    }),
+    spec("/oper", Access::Everyone, |args| {
+        Some(match Command::split(required(args)?) {
+            (user, secret) if !secret.is_empty() => Command::Oper(Some(user), secret),
+            _ => Command::Oper(None, args),
+        })
+    }),
+    spec("/part", Access::Everyone, |_| Some(Command::Part)),
+    spec("/pin", Access::Admins, |args| {
+        Some(Command::Pin(Some(required(args)?)))
+    }),
+    spec("/pinned", Access::Everyone, |_| Some(Command::Pin(None))),
+    spec("/poll", Access::Named, |args| Some(Command::Poll(args))),
+    spec("/quit", Access::Everyone, |_| Some(Command::Quit)),
+    spec("/raw", Access::Everyone, |args| {
+        Some(Command::Raw(on_off(args)?))
+    }),
+    spec("/react", Access::Everyone, |args| {
+        let (id, emoji) = Command::split(args);
+        let id = core::str::from_utf8(id).ok()?.parse().ok()?;
+        Some(Command::React(id, emoji))
+    }),
+    spec("/read", Access::Everyone, |_| Some(Command::Read)),
+    spec("/readline", Access::Everyone, |args| {
+        Some(Command::Readline(on_off(args)?))
+    }),
+    spec("/receipts", Access::Everyone, |args| {
+        Some(Command::Receipts(on_off(args)?))
+    }),
+    spec("/recent", Access::Admins, |_| Some(Command::Recent)),
+    spec("/report", Access::Everyone, |args| {
+        let (nick, reason) = Command::split(required(args)?);
+        Some(Command::Report(nick, reason))
+    }),
+    spec("/roster", Access::Admins, |_| Some(Command::Roster)),
+    spec("/search", Access::Everyone, |args| {
+        Some(Command::Search(required(args)?))
+    }),
+    spec("/seen", Access::Everyone, |args| {
+        Some(Command::Seen(required(args)?))
+    }),
+    spec("/send", Access::Named, |args| {
+        let (id, text) = Command::split(required(args)?);
+        Some(Command::Send(id, None, text))
+    }),
+    spec("/set", Access::Admins, |args| {
+        let (key, value) = Command::split(required(args)?);
+        Some(Command::Set(key, value))
+    }),
+    spec("/setprompt", Access::Everyone, |args| {
+        Some(Command::SetPrompt(args))
+    }),
+    spec("/stats", Access::Everyone, |_| Some(Command::Stats)),
+    spec("/status", Access::Everyone, |args| {
+        let (status, text) = Command::split(required(args)?);
+        Some(Command::Status(status, text))
+    }),
+    spec("/time", Access::Everyone, |_| Some(Command::Time)),
+    spec("/topic", Access::Everyone, |args| {
+        Some(Command::Topic(args))
+    }),
+    spec("/unalias", Access::Everyone, |args| {
+        Some(Command::Unalias(required(args)?))
+    }),
+    spec("/unpin", Access::Admins, |_| Some(Command::Pin(Some(b"")))),
+    spec("/version", Access::Everyone, |_| Some(Command::Version)),
+    spec("/vote", Access::Everyone, |args| {
+        Some(Command::Vote(
+            core::str::from_utf8(args).ok().and_then(|n| n.parse().ok()),
+        ))
+    }),
+    spec("/whois", Access::Everyone, |args| {
+        Some(Command::Whois(required(args)?))
+    }),
+];
+
+// Why a JSON mode request couldn't be turned into a command
+pub enum RequestError {
+    MethodNotFound,
+    InvalidParams,
+}
+
+impl<'a> Command<'a> {
+    // The command a JSON mode request stands for
+    pub fn from_request(method: &str, params: &'a Value) -> Result<Self, RequestError> {
+        let param = |name| {
+            params
+                .get(name)
+                .and_then(Value::as_str)
+                .map(str::as_bytes)
+                .ok_or(RequestError::InvalidParams)
+        };
+        let command = match method {
+            "alias" => Command::Alias(param("name")?, param("text")?),
+            "buffers" => Command::Buffers,
+            "channels" => Command::Channels,
+            "commands" => Command::Commands,
+            "complete" => Command::Complete(param("prefix").unwrap_or_default()),
+            "drain" => Command::Drain,
+            "encoding" => Command::Encoding(param("encoding")?),
+            "forcenick" => Command::ForceNick(param("old")?, param("new")?),
+            "fortune" => Command::Fortune,
+            "get" => Command::Get(param("key").unwrap_or_default()),
+            "history_limit" => Command::HistoryLimit(param("limit").unwrap_or_default()),
+            "invite" => Command::Invite(param("nick")?, param("channel")?),
+            "invite_token" => Command::InviteToken,
+            "join" => Command::Join(param("channel")?, param("key").ok()),
+            "lag" => Command::Lag,
+            "login_token" => Command::LoginToken(param("token")?),
+            "maintenance" => match params.get("on") {
+                Some(Value::Bool(on)) => Command::Maintenance(*on),
+                _ => return Err(RequestError::InvalidParams),
+            },
+            "message" => {
+                let reply_to = match params.get("reply_to") {
+                    None | Some(Value::Null) => None,
+                    Some(parent) => Some(parent.as_u64().ok_or(RequestError::InvalidParams)?),
+                };
+                match (param("client_id").ok(), reply_to) {
+                    (Some(id), reply_to) => Command::Send(id, reply_to, param("text")?),
+                    (None, Some(parent)) => Command::Reply(parent, param("text")?),
+                    (None, None) => Command::Message(param("text")?),
+                }
+            }
+            "mode" => Command::Mode(param("channel")?, param("modes")?),
+            "motd" => Command::Motd,
+            "msg" => Command::Msg(param("nick")?, param("text")?),
+            "nick" => Command::Nick(param("nick")?),
+            "op" => Command::Op(param("nick")?),
+            "deop" => Command::Deop(param("nick")?),
+            "oper" => Command::Oper(param("user").ok(), param("password")?),
+            "part" => Command::Part,
+            "pin" => Command::Pin(Some(param("text")?)),
+            "pinned" => Command::Pin(None),
+            "unpin" => Command::Pin(Some(b"")),
+            "poll" => Command::Poll(param("poll").unwrap_or_default()),
+            "poll_close" => Command::Poll(b"close"),
+            "quit" => Command::Quit,
+            "read" => Command::Read,
+            "receipts" => match params.get("on") {
+                Some(Value::Bool(on)) => Command::Receipts(*on),
+                _ => return Err(RequestError::InvalidParams),
+            },
+            "recent" => Command::Recent,
+            "report" => Command::Report(param("nick")?, param("reason")?),
+            "roster" => Command::Roster,
+            "react" => Command::React(
+                params
+                    .get("msgid")
+                    .and_then(Value::as_u64)
+                    .ok_or(RequestError::InvalidParams)?,
+                param("emoji")?,
+            ),
+            "search" => Command::Search(param("term")?),
+            "seen" => Command::Seen(param("nick")?),
+            "set" => Command::Set(param("key")?, param("value")?),
+            "stats" => Command::Stats,
+            "status" => Command::Status(param("status")?, param("text").unwrap_or_default()),
+            "topic" => Command::Topic(param("topic").unwrap_or_default()),
+            "unalias" => Command::Unalias(param("name")?),
+            "time" => Command::Time,
+            "version" => Command::Version,
+            "vote" => Command::Vote(Some(
+                params
+                    .get("option")
+                    .and_then(Value::as_u64)
+                    .ok_or(RequestError::InvalidParams)? as usize,
+            )),
+            "whois" => Command::Whois(param("nick")?),
+            _ => return Err(RequestError::MethodNotFound),
+        };
+        Ok(command)
+    }
+    // The name of a slash command and its arguments
+    pub fn split(line: &[u8]) -> (&[u8], &[u8]) {
+        match line.iter().position(|b| *b == b' ') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => (line, &[][..]),
+        }
+    }
+    pub fn parse(line: &'a [u8]) -> Self {
+        let (name, args) = Self::split(line);
+        COMMANDS
+            .iter()
+            .find(|spec| spec.name.as_bytes() == name)
+            .and_then(|spec| (spec.parse)(args))
+            .unwrap_or(Command::Message(line))
+    }
+}
+
+// Arguments shown as strings rather than byte lists
+struct Text<'a>(&'a [u8]);
+
+impl fmt::Debug for Text<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", String::from_utf8_lossy(self.0))
+    }
+}
+
+// How the server understood a line, echoed by /debug
+impl fmt::Debug for Command<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::Alias(name, text) => f
+                .debug_tuple("Alias")
+                .field(&Text(name))
+                .field(&Text(text))
+                .finish(),
+            Command::Binary => f.write_str("Binary"),
+            Command::Buffers => f.write_str("Buffers"),
+            Command::Channels => f.write_str("Channels"),
+            Command::Commands => f.write_str("Commands"),
+            Command::Complete(prefix) => f.debug_tuple("Complete").field(&Text(prefix)).finish(),
+            Command::Crlf(on) => f.debug_tuple("Crlf").field(on).finish(),
+            Command::Debug(on) => f.debug_tuple("Debug").field(on).finish(),
+            Command::Deop(nick) => f.debug_tuple("Deop").field(&Text(nick)).finish(),
+            Command::Drain => f.write_str("Drain"),
+            Command::Encoding(name) => f.debug_tuple("Encoding").field(&Text(name)).finish(),
+            Command::ForceNick(old, new) => f
+                .debug_tuple("ForceNick")
+                .field(&Text(old))
+                .field(&Text(new))
+                .finish(),
+            Command::Fortune => f.write_str("Fortune"),
+            Command::Get(key) => f.debug_tuple("Get").field(&Text(key)).finish(),
+            Command::HistoryLimit(limit) => {
+                f.debug_tuple("HistoryLimit").field(&Text(limit)).finish()
+            }
+            Command::Invite(nick, channel) => f
+                .debug_tuple("Invite")
+                .field(&Text(nick))
+                .field(&Text(channel))
+                .finish(),
+            Command::Json => f.write_str("Json"),
+            // Room keys stay out of the echo too
+            Command::Join(channel, key) => f
+                .debug_tuple("Join")
+                .field(&Text(channel))
+                .field(&key.map(|_| "***"))
+                .finish(),
+            Command::Lag => f.write_str("Lag"),
+            // The token is a secret, like the /oper password
+            Command::LoginToken(_) => f.write_str("LoginToken(..)"),
+            Command::InviteToken => f.write_str("InviteToken"),
+            Command::Maintenance(on) => f.debug_tuple("Maintenance").field(on).finish(),
+            Command::Mode(channel, modes) => {
+                let mut tuple = f.debug_tuple("Mode");
+                tuple.field(&Text(channel));
+                match Self::split(modes) {
+                    (b"+k", key) if !key.is_empty() => tuple.field(&"+k ***"),
+                    _ => tuple.field(&Text(modes)),
+                };
+                tuple.finish()
+            }
+            Command::Motd => f.write_str("Motd"),
+            Command::Msg(nick, text) => f
+                .debug_tuple("Msg")
+                .field(&Text(nick))
+                .field(&Text(text))
+                .finish(),
+            Command::Nick(nick) => f.debug_tuple("Nick").field(&Text(nick)).finish(),
+            Command::Op(nick) => f.debug_tuple("Op").field(&Text(nick)).finish(),
+            // The secret stays out of the echo
+            Command::Oper(user, _) => f
+                .debug_tuple("Oper")
+                .field(&user.map(Text))
+                .field(&"***")
+                .finish(),
+            Command::Part => f.write_str("Part"),
+            Command::Pin(text) => f.debug_tuple("Pin").field(&text.map(Text)).finish(),
+            Command::Poll(arg) => f.debug_tuple("Poll").field(&Text(arg)).finish(),
+            Command::Quit => f.write_str("Quit"),
+            Command::Raw(on) => f.debug_tuple("Raw").field(on).finish(),
+            Command::React(id, emoji) => f
+                .debug_tuple("React")
+                .field(id)
+                .field(&Text(emoji))
+                .finish(),
+            Command::Read => f.write_str("Read"),
+            Command::Readline(on) => f.debug_tuple("Readline").field(on).finish(),
+            Command::Receipts(on) => f.debug_tuple("Receipts").field(on).finish(),
+            Command::Recent => f.write_str("Recent"),
+            Command::Report(nick, reason) => f
+                .debug_tuple("Report")
+                .field(&Text(nick))
+                .field(&Text(reason))
+                .finish(),
+            Command::Reply(parent, text) => f
+                .debug_tuple("Reply")
+                .field(parent)
+                .field(&Text(text))
+                .finish(),
+            Command::Roster => f.write_str("Roster"),
+            Command::Search(term) => f.debug_tuple("Search").field(&Text(term)).finish(),
+            Command::Seen(nick) => f.debug_tuple("Seen").field(&Text(nick)).finish(),
+            Command::Send(id, parent, text) => f
+                .debug_tuple("Send")
+                .field(&Text(id))
+                .field(parent)
+                .field(&Text(text))
+                .finish(),
+            Command::Set(key, value) => f
+                .debug_tuple("Set")
+                .field(&Text(key))
+                .field(&Text(value))
+                .finish(),
+            Command::SetPrompt(prompt) => f.debug_tuple("SetPrompt").field(&Text(prompt)).finish(),
+            Command::Status(status, text) => f
+                .debug_tuple("Status")
+                .field(&Text(status))
+                .field(&Text(text))
+                .finish(),
+            Command::Stats => f.write_str("Stats"),
+            Command::Topic(arg) => f.debug_tuple("Topic").field(&Text(arg)).finish(),
+            Command::Unalias(name) => f.debug_tuple("Unalias").field(&Text(name)).finish(),
+            Command::Time => f.write_str("Time"),
+            Command::Version => f.write_str("Version"),
+            Command::Vote(n) => f.debug_tuple("Vote").field(n).finish(),
+            Command::Whois(nick) => f.debug_tuple("Whois").field(&Text(nick)).finish(),
+            Command::Message(text) => f.debug_tuple("Message").field(&Text(text)).finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo(line: &[u8]) -> String {
+        format!("{:?}", Command::parse(line))
+    }
+
+    #[test]
+    fn debug_echo_hides_secrets() {
+        assert_eq!(
+            echo(b"/oper admin hunter2"),
+            r##"Oper(Some("admin"), "***")"##
+        );
+        assert_eq!(
+            echo(b"/join #ops hunter2"),
+            r##"Join("#ops", Some("***"))"##
+        );
+        assert_eq!(echo(b"/join #ops"), r##"Join("#ops", None)"##);
+        assert_eq!(
+            echo(b"/mode #ops +k hunter2"),
+            r##"Mode("#ops", "+k ***")"##
+        );
+        assert_eq!(echo(b"/mode #ops +i"), r##"Mode("#ops", "+i")"##);
+        assert_eq!(echo(b"/login-token hunter2"), "LoginToken(..)");
+    }
+
+    #[test]
+    fn every_listed_command_parses() {
+        for spec in COMMANDS {
+            let parsed = ["", "on", "1 x", "a b"].iter().any(|args| {
+                let line = format!("{} {args}", spec.name);
+                !matches!(
+                    Command::parse(line.trim_end().as_bytes()),
+                    Command::Message(_)
+                )
+            });
+            assert!(parsed, "{} never parses", spec.name);
+        }
+        let mut names: Vec<&str> = COMMANDS.iter().map(|spec| spec.name).collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), COMMANDS.len(), "a command is listed twice");
+        // Anything else is chat
+        for line in ["/nosuch", "/crlf maybe", "/react x y", "/join", "hello"] {
+            assert!(matches!(
+                Command::parse(line.as_bytes()),
+                Command::Message(_)
+            ));
+        }
+    }
+}
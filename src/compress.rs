@@ -0,0 +1,96 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use std::io;
+
+// Room made in the output at a time
+const CHUNK: usize = 1024;
+
+// Raw deflate both ways on a connection, negotiated with `CAP compress`.
+// Every write is sync flushed, so each message can be inflated as soon as
+// it arrives.
+pub struct Deflate {
+    compress: Compress,
+    decompress: Decompress,
+}
+
+impl Deflate {
+    pub fn new() -> Self {
+        Self {
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+        }
+    }
+    pub fn compress(&mut self, mut input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(input.len() / 2 + CHUNK);
+        loop {
+            let before = self.compress.total_in();
+            // Can't fail on valid state, which only we touch
+            self.compress
+                .compress_vec(input, &mut out, FlushCompress::Sync)
+                .expect("deflate failed");
+            input = &input[(self.compress.total_in() - before) as usize..];
+            // Spare room left over means the flush is complete
+            if input.is_empty() && out.len() < out.capacity() {
+                return out;
+            }
+            out.reserve(CHUNK);
+        }
+    }
+    // Inflates `input` onto `out`, failing if it's corrupt or `out` would
+    // grow past `max` bytes
+    pub fn decompress(
+        &mut self,
+        mut input: &[u8],
+        out: &mut Vec<u8>,
+        max: usize,
+    ) -> io::Result<()> {
+        loop {
+            if out.len() > max {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "compressed input inflates too much",
+                ));
+            }
+            out.reserve(CHUNK);
+            let before = self.decompress.total_in();
+            let status = self
+                .decompress
+                .decompress_vec(input, out, FlushDecompress::Sync)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            input = &input[(self.decompress.total_in() - before) as usize..];
+            if status == Status::StreamEnd || (input.is_empty() && out.len() < out.capacity()) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_write_inflates_on_arrival() {
+        let (mut server, mut client) = (Deflate::new(), Deflate::new());
+        let mut inflated = Vec::new();
+        for line in [&b"hello\n"[..], &[b'x'; 5000], b"bye\n"] {
+            let wire = server.compress(line);
+            inflated.clear();
+            client.decompress(&wire, &mut inflated, 1 << 20).unwrap();
+            assert_eq!(inflated, line);
+        }
+    }
+
+    #[test]
+    fn refuses_to_inflate_past_the_limit() {
+        let wire = Deflate::new().compress(&[0; 100_000]);
+        let mut inflated = Vec::new();
+        let err = Deflate::new()
+            .decompress(&wire, &mut inflated, 10_000)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = Deflate::new()
+            .decompress(b"not deflate at all", &mut Vec::new(), 10_000)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,465 @@
+use crate::nickgen::NickScheme;
+use crate::quiet::QuietHours;
+use log::LevelFilter;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub struct Config {
+    pub addr: SocketAddr,
+    // Allows binding again right after a restart, while old connections linger in TIME_WAIT
+    pub reuse_addr: bool,
+    pub backlog: i32,
+    // Take the listening socket from systemd when it passes one
+    pub systemd: bool,
+    // Check that clients can talk through the server, then exit
+    pub self_test: bool,
+    // File with the message of the day
+    pub motd: Option<PathBuf>,
+    // SQLite database chat messages are recorded to, for /search
+    pub db: Option<PathBuf>,
+    // Messages the database keeps per room, unless set with /history-limit
+    pub history_limit: Option<usize>,
+    // Directory each room's chat is appended to, a file per room
+    pub transcript_dir: Option<PathBuf>,
+    // Size past which a transcript file is rotated
+    pub max_log_size: u64,
+    // One quote per line, for /fortune
+    pub fortunes: Option<PathBuf>,
+    // Disconnect clients whose oldest queued output is older than this
+    pub send_timeout: Option<Duration>,
+    // Only channels: messages outside of one aren't broadcast
+    pub no_global: bool,
+    // Longer messages are truncated
+    pub max_message_len: Option<usize>,
+    // How long the nick of a disconnected client stays reserved
+    pub nick_grace: Option<Duration>,
+    // How long the ids given with /send are remembered to suppress replays
+    pub send_id_window: Duration,
+    // Suppress a message identical to the sender's previous one within this window
+    pub dedup_window: Option<Duration>,
+    // Lets clients become admins with /oper
+    pub admin_password: Option<String>,
+    // Lets the users in this file become admins with /oper, instead of
+    // `admin_password`
+    pub admin_users: Option<PathBuf>,
+    // A command no human would send; clients that do are tarpitted
+    pub honeypot: Option<String>,
+    // How long pending output may take to go out on shutdown
+    pub shutdown_timeout: Duration,
+    // Clients must send a complete first line within this of connecting
+    pub handshake_timeout: Option<Duration>,
+    // How long a drain waits for clients to leave before exiting
+    pub drain_timeout: Duration,
+    // Key for the HMAC appended to every chat message
+    pub sign_key: Option<String>,
+    // Where to serve the browser client
+    pub web: Option<SocketAddr>,
+    // Simultaneous connections allowed from a single address
+    pub max_per_ip: usize,
+    // Channels that may exist at once; joining a new one past it is refused
+    pub max_channels: usize,
+    // Chat messages per second each client may send, in bursts of as many
+    pub message_rate: Option<usize>,
+    // Time each client must leave between its chat messages
+    pub slow_mode: Option<Duration>,
+    // Nicks, or `user:` and a /oper user name, not held to `message_rate`.
+    // `*` matches anything.
+    pub exempt: Vec<String>,
+    // Bytes per second each client is sent at most
+    pub egress_rate: Option<usize>,
+    // Disconnect clients whose outbox keeps more bytes than this alive
+    pub max_retained: Option<usize>,
+    // Disconnect the clients with the most queued output while all the
+    // outboxes together hold more than this
+    pub max_total_queued: Option<usize>,
+    // Clients a broadcast is handed to per loop iteration
+    pub fanout_batch: Option<usize>,
+    // Lines starting with `/` must be known commands, never chat
+    pub strict_commands: bool,
+    // Said to the founder, the first client to connect
+    pub founder_greeting: Option<String>,
+    // The founder is an admin without /oper
+    pub founder_admin: bool,
+    // Handle one line per client at a time, round-robin, rather than a batch
+    pub fair: bool,
+    // Leading and trailing whitespace is cut from chat messages
+    pub trim_messages: bool,
+    // Daily windows during which only admins can talk
+    pub quiet_hours: Vec<QuietHours>,
+    // Chat is refused until the client picks a nick with /nick
+    pub require_nick: bool,
+    pub log_level: LevelFilter,
+    // Show each connection's session id in logs and /whois
+    pub session_ids: bool,
+    // Addresses are shown hashed in logs and replies
+    pub anonymize_ips: bool,
+    // Except to admins, in /roster
+    pub admins_see_ips: bool,
+    // How often activity is summarized in the log, `None` for never
+    pub heartbeat_interval: Option<Duration>,
+    // Only one in this many connections is logged at info level
+    pub log_connects: u64,
+    // End lines sent to clients in CRLF by default
+    pub crlf_out: bool,
+    // How nicks are made up for clients that haven't picked one
+    pub default_nick: NickScheme,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:7711".parse().unwrap(),
+            reuse_addr: true,
+            backlog: 1024,
+            systemd: false,
+            self_test: false,
+            motd: None,
+            db: None,
+            history_limit: None,
+            transcript_dir: None,
+            max_log_size: 10 * 1024 * 1024,
+            fortunes: None,
+            send_timeout: None,
+            no_global: false,
+            max_message_len: None,
+            nick_grace: None,
+            dedup_window: None,
+            send_id_window: Duration::from_secs(300),
+            admin_password: None,
+            admin_users: None,
+            honeypot: None,
+            shutdown_timeout: Duration::from_secs(5),
+            handshake_timeout: Some(Duration::from_secs(10)),
+            drain_timeout: Duration::from_secs(600),
+            sign_key: None,
+            web: None,
+            max_per_ip: 5,
+            max_channels: 100,
+            message_rate: None,
+            slow_mode: None,
+            exempt: Vec::new(),
+            egress_rate: None,
+            max_retained: None,
+            max_total_queued: None,
+            fanout_batch: None,
+            strict_commands: false,
+            require_nick: false,
+            quiet_hours: Vec::new(),
+            trim_messages: false,
+            fair: false,
+            founder_greeting: None,
+            founder_admin: false,
+            log_level: LevelFilter::Info,
+            session_ids: false,
+            anonymize_ips: false,
+            admins_see_ips: false,
+            heartbeat_interval: Some(Duration::from_secs(60)),
+            log_connects: 1,
+            crlf_out: false,
+            default_nick: NickScheme::Sequential("guest".into()),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut config = Config::default();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{arg} needs a value"));
+            match arg.as_str() {
+                "--addr" => {
+                    config.addr = parse(&arg, &value()?)?;
+                }
+                "--no-reuseaddr" => {
+                    config.reuse_addr = false;
+                }
+                "--backlog" => {
+                    config.backlog = parse(&arg, &value()?)?;
+                }
+                "--systemd" => {
+                    config.systemd = true;
+                }
+                "--self-test" => {
+                    config.self_test = true;
+                }
+                "--motd" => {
+                    config.motd = Some(value()?.into());
+                }
+                "--db" => {
+                    config.db = Some(value()?.into());
+                }
+                "--history-limit" => {
+                    config.history_limit = Some(parse(&arg, &value()?)?);
+                }
+                "--transcript-dir" => {
+                    config.transcript_dir = Some(value()?.into());
+                }
+                "--max-log-size" => {
+                    let value = value()?;
+                    config.max_log_size = match parse_size(&value) {
+                        Some(size) if size > 0 => size,
+                        _ => return Err(format!("invalid value for {arg}: {value}")),
+                    };
+                }
+                "--fortunes" => {
+                    config.fortunes = Some(value()?.into());
+                }
+                "--send-timeout" => {
+                    config.send_timeout = Some(Duration::from_secs(parse(&arg, &value()?)?));
+                }
+                "--no-global" => {
+                    config.no_global = true;
+                }
+                "--max-message-len" => {
+                    config.max_message_len = Some(parse(&arg, &value()?)?);
+                }
+                "--nick-grace" => {
+                    config.nick_grace = Some(Duration::from_secs(parse(&arg, &value()?)?));
+                }
+                "--dedup-window" => {
+                    config.dedup_window = Some(Duration::from_millis(parse(&arg, &value()?)?));
+                }
+                "--send-id-window" => {
+                    config.send_id_window = Duration::from_secs(parse(&arg, &value()?)?);
+                }
+                "--admin-password" => {
+                    config.admin_password = Some(value()?);
+                }
+                "--admin-users" => {
+                    config.admin_users = Some(value()?.into());
+                }
+                "--honeypot" => {
+                    config.honeypot = Some(value()?);
+                }
+                "--shutdown-timeout" => {
+                    config.shutdown_timeout = Duration::from_secs(parse(&arg, &value()?)?);
+                }
+                "--handshake-timeout" => match parse(&arg, &value()?)? {
+                    0 => config.handshake_timeout = None,
+                    secs => config.handshake_timeout = Some(Duration::from_secs(secs)),
+                },
+                "--drain-timeout" => {
+                    config.drain_timeout = Duration::from_secs(parse(&arg, &value()?)?);
+                }
+                "--sign" => {
+                    config.sign_key = Some(value()?);
+                }
+                "--web" => {
+                    config.web = Some(parse(&arg, &value()?)?);
+                }
+                "--max-per-ip" => {
+                    config.max_per_ip = parse(&arg, &value()?)?;
+                }
+                "--max-channels" => {
+                    config.max_channels = parse(&arg, &value()?)?;
+                }
+                "--message-rate" => match parse(&arg, &value()?)? {
+                    0 => return Err(format!("{arg} must be positive")),
+                    rate => config.message_rate = Some(rate),
+                },
+                "--slow-mode" => match parse(&arg, &value()?)? {
+                    0 => config.slow_mode = None,
+                    secs => config.slow_mode = Some(Duration::from_secs(secs)),
+                },
+                "--exempt" => {
+                    config.exempt.push(value()?);
+                }
+                "--egress-rate" => match parse(&arg, &value()?)? {
+                    0 => return Err(format!("{arg} must be positive")),
+                    rate => config.egress_rate = Some(rate),
+                },
+                "--max-retained" => {
+                    config.max_retained = Some(parse(&arg, &value()?)?);
+                }
+                "--max-total-queued" => {
+                    config.max_total_queued = Some(parse(&arg, &value()?)?);
+                }
+                "--fanout-batch" => match parse(&arg, &value()?)? {
+                    0 => return Err(format!("{arg} must be positive")),
+                    batch => config.fanout_batch = Some(batch),
+                },
+                "--strict-commands" => {
+                    config.strict_commands = true;
+                }
+                "--founder-greeting" => {
+                    config.founder_greeting = Some(value()?);
+                }
+                "--founder-admin" => {
+                    config.founder_admin = true;
+                }
+                "--fair" => {
+                    config.fair = true;
+                }
+                "--trim" => {
+                    config.trim_messages = true;
+                }
+                "--require-nick" => {
+                    config.require_nick = true;
+                }
+                "--quiet-hours" => {
+                    let window = value()?;
+                    let hours = QuietHours::parse(&window)
+                        .ok_or_else(|| format!("invalid value for {arg}: {window}"))?;
+                    config.quiet_hours.push(hours);
+                }
+                "--log-level" => {
+                    config.log_level = parse(&arg, &value()?)?;
+                }
+                "--heartbeat-interval" => match parse(&arg, &value()?)? {
+                    0 => config.heartbeat_interval = None,
+                    secs => config.heartbeat_interval = Some(Duration::from_secs(secs)),
+                },
+                "--session-ids" => {
+                    config.session_ids = true;
+                }
+                "--anonymize-ips" => {
+                    config.anonymize_ips = true;
+                }
+                "--admins-see-ips" => {
+                    config.admins_see_ips = true;
+                }
+                "--log-connects" => {
+                    let value = value()?;
+                    // Either `N` or `1/N`
+                    let n = value.strip_prefix("1/").unwrap_or(&value);
+                    config.log_connects = match parse(&arg, n)? {
+                        0 => return Err(format!("invalid value for {arg}: {value}")),
+                        n => n,
+                    };
+                }
+                "--crlf-out" => {
+                    config.crlf_out = true;
+                }
+                "--default-nick" => {
+                    let scheme = value()?;
+                    config.default_nick = NickScheme::parse(&scheme)
+                        .ok_or_else(|| format!("invalid value for {arg}: {scheme}"))?;
+                }
+                _ => return Err(format!("unknown argument {arg}")),
+            }
+        }
+        Ok(config)
+    }
+    // The settings admins can read and change while running with /get and
+    // /set, named like their options. `off` stands for an unset one.
+    pub const TUNABLES: &'static [&'static str] = &[
+        "max-message-len",
+        "dedup-window",
+        "send-timeout",
+        "nick-grace",
+        "max-per-ip",
+        "max-channels",
+        "max-retained",
+        "max-total-queued",
+        "message-rate",
+        "slow-mode",
+    ];
+    pub fn get(&self, key: &str) -> Option<String> {
+        let optional = |v: Option<String>| v.unwrap_or_else(|| "off".into());
+        let value = match key {
+            "max-message-len" => optional(self.max_message_len.map(|n| n.to_string())),
+            "dedup-window" => optional(self.dedup_window.map(|d| d.as_millis().to_string())),
+            "send-timeout" => optional(self.send_timeout.map(|d| d.as_secs().to_string())),
+            "nick-grace" => optional(self.nick_grace.map(|d| d.as_secs().to_string())),
+            "max-per-ip" => self.max_per_ip.to_string(),
+            "max-channels" => self.max_channels.to_string(),
+            "max-retained" => optional(self.max_retained.map(|n| n.to_string())),
+            "max-total-queued" => optional(self.max_total_queued.map(|n| n.to_string())),
+            "message-rate" => optional(self.message_rate.map(|n| n.to_string())),
+            "slow-mode" => optional(self.slow_mode.map(|d| d.as_secs().to_string())),
+            _ => return None,
+        };
+        Some(value)
+    }
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let optional = |value: &str| -> Result<Option<u64>, String> {
+            match value {
+                "off" => Ok(None),
+                _ => parse(key, value).map(Some),
+            }
+        };
+        match key {
+            "max-message-len" => self.max_message_len = optional(value)?.map(|n| n as usize),
+            "dedup-window" => self.dedup_window = optional(value)?.map(Duration::from_millis),
+            "send-timeout" => self.send_timeout = optional(value)?.map(Duration::from_secs),
+            "nick-grace" => self.nick_grace = optional(value)?.map(Duration::from_secs),
+            "max-per-ip" => self.max_per_ip = parse(key, value)?,
+            "max-channels" => self.max_channels = parse(key, value)?,
+            "max-retained" => self.max_retained = optional(value)?.map(|n| n as usize),
+            "max-total-queued" => self.max_total_queued = optional(value)?.map(|n| n as usize),
+            "message-rate" => match optional(value)? {
+                Some(0) => return Err(format!("{key} must be positive")),
+                rate => self.message_rate = rate.map(|n| n as usize),
+            },
+            "slow-mode" => self.slow_mode = optional(value)?.map(Duration::from_secs),
+            _ => return Err(format!("no setting {key}")),
+        }
+        Ok(())
+    }
+}
+
+// Bytes, optionally in KB, MB or GB (powers of 1024), like `10MB`
+fn parse_size(value: &str) -> Option<u64> {
+    let units = [("KB", 1 << 10), ("MB", 1 << 20), ("GB", 1 << 30)];
+    let (number, unit) = units
+        .iter()
+        .find_map(|(suffix, unit)| Some((value.strip_suffix(suffix)?, *unit)))
+        .unwrap_or((value, 1));
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+fn parse<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {arg}: {value}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(args: &[&str]) -> Result<Config, String> {
+        Config::from_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn listen_options() {
+        let defaults = config(&[]).unwrap();
+        assert!(defaults.reuse_addr);
+        assert_eq!(defaults.backlog, 1024);
+        let config = config(&["--no-reuseaddr", "--backlog", "16"]).unwrap();
+        assert!(!config.reuse_addr);
+        assert_eq!(config.backlog, 16);
+    }
+
+    #[test]
+    fn bad_values_are_refused() {
+        assert_eq!(
+            config(&["--backlog", "many"]).err().unwrap(),
+            "invalid value for --backlog: many"
+        );
+        assert_eq!(
+            config(&["--backlog"]).err().unwrap(),
+            "--backlog needs a value"
+        );
+    }
+
+    #[test]
+    fn every_tunable_reads_back_what_was_set() {
+        let mut config = config(&[]).unwrap();
+        for key in Config::TUNABLES {
+            config.set(key, "7").unwrap();
+            assert_eq!(config.get(key).unwrap(), "7", "{key}");
+        }
+        config.set("max-retained", "off").unwrap();
+        assert_eq!(config.get("max-retained").unwrap(), "off");
+        assert!(config.set("max-per-ip", "off").is_err());
+        assert!(config.set("max-message-len", "-1").is_err());
+        assert!(config.set("message-rate", "0").is_err());
+        config.set("message-rate", "off").unwrap();
+        assert!(config.message_rate.is_none());
+        assert_eq!(config.set("port", "1").err().unwrap(), "no setting port");
+        assert!(config.get("port").is_none());
+    }
+}
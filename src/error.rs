@@ -0,0 +1,44 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+// Why the server couldn't start or had to stop. Errors with a single
+// client are handled where they happen and never end up here.
+#[derive(Debug)]
+pub enum ServerError {
+    // Invalid arguments or settings that can't work together
+    Config(String),
+    // Listening on an address failed, e.g. because it's in use
+    Bind(SocketAddr, io::Error),
+    // Reading a configured file, or the event loop itself, failed
+    Io(io::Error),
+    // What `--self-test` found broken
+    SelfTest(String),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::Config(e) => f.write_str(e),
+            ServerError::Bind(addr, e) => write!(f, "can't listen on {addr}: {e}"),
+            ServerError::Io(e) => write!(f, "{e}"),
+            ServerError::SelfTest(e) => write!(f, "self-test failed: {e}"),
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServerError::Config(_) | ServerError::SelfTest(_) => None,
+            ServerError::Bind(_, e) | ServerError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> Self {
+        ServerError::Io(e)
+    }
+}
@@ -0,0 +1,175 @@
+// How messages are cut out of the bytes read from a connection and wrapped
+// into the bytes written to it. Each connection has the framer of its
+// framing; the read loop only deals with the frames it finds.
+use crate::websocket::{self, Decoded};
+use std::ops::Range;
+
+// What's at the start of the bytes read so far. Positions are relative to
+// the buffer given to `Framer::decode`.
+pub enum Frame {
+    // The payload and where the next frame starts
+    Complete(Range<usize>, usize),
+    // A WebSocket ping, with the payload to echo back
+    Ping(Range<usize>, usize),
+    // Nothing to act on, up to where the next frame starts
+    Ignored(usize),
+    // The peer is closing the connection
+    Close,
+    Partial,
+    // Can't ever fit in the read buffer
+    TooLarge,
+}
+
+pub trait Framer {
+    // The frame at the start of `buf`, which may be rewritten in place,
+    // like WebSocket payloads being unmasked
+    fn decode(&mut self, buf: &mut [u8]) -> Frame;
+    // `payload` ready to be sent
+    fn encode(&self, payload: &[u8]) -> Vec<u8>;
+}
+
+// Newline terminated lines, also used for JSON requests
+pub struct LineFramer;
+
+impl Framer for LineFramer {
+    fn decode(&mut self, buf: &mut [u8]) -> Frame {
+        match buf.iter().position(|x| *x == b'\n') {
+            Some(len) => Frame::Complete(0..len, len + 1),
+            None => Frame::Partial,
+        }
+    }
+    fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(payload.len() + 1);
+        data.extend_from_slice(payload);
+        data.push(b'\n');
+        data
+    }
+}
+
+// A 4-byte big-endian length followed by that many bytes
+pub struct LengthPrefixedFramer {
+    // Longest payload accepted
+    pub max_len: usize,
+}
+
+impl Framer for LengthPrefixedFramer {
+    fn decode(&mut self, buf: &mut [u8]) -> Frame {
+        let Some(header) = buf.get(..4) else {
+            return Frame::Partial;
+        };
+        let len = u32::from_be_bytes(header.try_into().unwrap()) as usize;
+        if len > self.max_len {
+            Frame::TooLarge
+        } else if buf.len() < 4 + len {
+            Frame::Partial
+        } else {
+            Frame::Complete(4..4 + len, 4 + len)
+        }
+    }
+    fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(payload.len() + 4);
+        data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+}
+
+// An HTTP request head on the web listener. Responses are written as they are.
+pub struct HttpFramer {
+    // Longest request head accepted
+    pub max_len: usize,
+}
+
+impl Framer for HttpFramer {
+    fn decode(&mut self, buf: &mut [u8]) -> Frame {
+        match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(len) => Frame::Complete(0..len, len + 4),
+            None if buf.len() >= self.max_len => Frame::TooLarge,
+            None => Frame::Partial,
+        }
+    }
+    fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        payload.to_vec()
+    }
+}
+
+pub struct WebSocketFramer {
+    // Longest payload accepted
+    pub max_len: usize,
+}
+
+impl Framer for WebSocketFramer {
+    fn decode(&mut self, buf: &mut [u8]) -> Frame {
+        match websocket::decode(buf, self.max_len) {
+            Decoded::Data(range, len) => Frame::Complete(range, len),
+            Decoded::Ping(range, len) => Frame::Ping(range, len),
+            Decoded::Ignored(len) => Frame::Ignored(len),
+            Decoded::Close => Frame::Close,
+            Decoded::Partial => Frame::Partial,
+            Decoded::TooLarge => Frame::TooLarge,
+        }
+    }
+    // Text frames must be valid UTF-8
+    fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        websocket::encode(
+            websocket::OP_TEXT,
+            String::from_utf8_lossy(payload).as_bytes(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_prefixed_frames() {
+        let mut framer = LengthPrefixedFramer { max_len: 8 };
+        let mut data = framer.encode(b"hi\n\0");
+        assert_eq!(data, b"\0\0\0\x04hi\n\0");
+        data.extend_from_slice(&framer.encode(b""));
+        assert!(matches!(framer.decode(&mut data), Frame::Complete(r, 8) if r == (4..8)));
+        assert!(matches!(framer.decode(&mut data[8..]), Frame::Complete(r, 4) if r.is_empty()));
+        assert!(matches!(framer.decode(&mut data[..6]), Frame::Partial));
+        assert!(matches!(framer.decode(&mut data[..3]), Frame::Partial));
+        let mut long = framer.encode(b"123456789");
+        assert!(matches!(framer.decode(&mut long[..4]), Frame::TooLarge));
+    }
+
+    // Feeds the encoded messages one byte at a time, decoding whatever is
+    // complete after each, as the read loop would
+    fn round_trip(framer: &mut dyn Framer, messages: &[&[u8]]) -> Vec<Vec<u8>> {
+        let wire: Vec<u8> = messages.iter().flat_map(|m| framer.encode(m)).collect();
+        let mut buf = Vec::new();
+        let mut decoded = Vec::new();
+        for &b in &wire {
+            buf.push(b);
+            loop {
+                match framer.decode(&mut buf) {
+                    Frame::Complete(range, len) => {
+                        decoded.push(buf[range].to_vec());
+                        buf.drain(..len);
+                    }
+                    Frame::Partial => break,
+                    _ => panic!("unexpected frame"),
+                }
+            }
+        }
+        assert!(buf.is_empty());
+        decoded
+    }
+
+    #[test]
+    fn each_framer_round_trips_fragmented_input() {
+        let long = vec![b'x'; 300];
+        let messages: [&[u8]; 4] = [b"hello", b"", "héllo wörld".as_bytes(), &long];
+        let framers: [Box<dyn Framer>; 3] = [
+            Box::new(LineFramer),
+            Box::new(LengthPrefixedFramer { max_len: 1024 }),
+            Box::new(WebSocketFramer { max_len: 1024 }),
+        ];
+        for mut framer in framers {
+            assert_eq!(round_trip(framer.as_mut(), &messages), messages);
+        }
+    }
+}
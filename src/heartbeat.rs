@@ -0,0 +1,84 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// Activity between two heartbeats
+pub struct Beat {
+    pub messages: u64,
+    pub bytes_sent: u64,
+}
+
+// Counts activity for the periodic heartbeat log line
+pub struct Heartbeat {
+    // `None` when there's no heartbeat
+    interval: Option<Duration>,
+    last: Instant,
+    messages: u64,
+    // Shared by all clients, which add what they write
+    bytes_sent: Rc<Cell<u64>>,
+}
+
+impl Heartbeat {
+    pub fn new(interval: Option<Duration>) -> Self {
+        Self {
+            interval,
+            last: Instant::now(),
+            messages: 0,
+            bytes_sent: Default::default(),
+        }
+    }
+    // The counter clients add the bytes they write to
+    pub fn bytes_sent(&self) -> Rc<Cell<u64>> {
+        self.bytes_sent.clone()
+    }
+    pub fn message(&mut self) {
+        self.messages += 1;
+    }
+    pub fn due_at(&self) -> Option<Instant> {
+        Some(self.last + self.interval?)
+    }
+    // The activity since the previous heartbeat, if one is due by `now`.
+    // Counting starts over from there.
+    pub fn tick(&mut self, now: Instant) -> Option<Beat> {
+        if self.due_at()? > now {
+            return None;
+        }
+        self.last = now;
+        Some(Beat {
+            messages: std::mem::take(&mut self.messages),
+            bytes_sent: self.bytes_sent.replace(0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_start_over_each_beat() {
+        let mut heartbeat = Heartbeat::new(Some(Duration::from_secs(60)));
+        let start = heartbeat.last;
+        let sent = heartbeat.bytes_sent();
+        heartbeat.message();
+        heartbeat.message();
+        sent.set(sent.get() + 100);
+        assert!(heartbeat.tick(start + Duration::from_secs(59)).is_none());
+        let beat = heartbeat.tick(start + Duration::from_secs(60)).unwrap();
+        assert_eq!((beat.messages, beat.bytes_sent), (2, 100));
+        assert_eq!(heartbeat.due_at(), Some(start + Duration::from_secs(120)));
+        heartbeat.message();
+        let beat = heartbeat.tick(start + Duration::from_secs(120)).unwrap();
+        assert_eq!((beat.messages, beat.bytes_sent), (1, 0));
+    }
+
+    #[test]
+    fn no_interval_no_beats() {
+        let mut heartbeat = Heartbeat::new(None);
+        heartbeat.message();
+        assert!(heartbeat.due_at().is_none());
+        assert!(heartbeat
+            .tick(Instant::now() + Duration::from_secs(3600))
+            .is_none());
+    }
+}
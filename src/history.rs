@@ -0,0 +1,253 @@
+use log::error;
+use rusqlite::{params, Connection};
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Messages waiting to be written, past which they're written right away
+const MAX_QUEUED: usize = 1024;
+// How long a message may wait in the queue
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Entry {
+    // Seconds since the Unix epoch
+    pub at: u64,
+    // `None` for the global room
+    pub channel: Option<String>,
+    pub nick: String,
+    pub text: String,
+}
+
+// Chat messages recorded to SQLite, with `--db`. They're queued and written
+// in one transaction at a time, so the event loop doesn't wait on the disk
+// for each of them.
+pub struct History {
+    conn: Connection,
+    queue: Vec<Entry>,
+    // When the oldest queued message was queued
+    oldest: Option<Instant>,
+    // Messages kept per room, `--history-limit`, unless the room has a
+    // limit of its own. `None` keeps them all.
+    default_limit: Option<usize>,
+    // Limits set with /history-limit, kept in the database too so they
+    // outlive the room and the server. The global room is `None`.
+    limits: HashMap<Option<String>, usize>,
+}
+
+impl History {
+    pub fn open(path: &Path, default_limit: Option<usize>) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY,
+                at INTEGER NOT NULL,
+                channel TEXT,
+                nick TEXT NOT NULL,
+                text TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS messages_channel ON messages (channel, id);
+            -- The global room is the empty string, the primary key can't be null
+            CREATE TABLE IF NOT EXISTS limits (
+                channel TEXT PRIMARY KEY,
+                keep INTEGER NOT NULL
+            )",
+        )?;
+        let limits = conn
+            .prepare("SELECT channel, keep FROM limits")?
+            .query_map([], |row| {
+                let channel: String = row.get(0)?;
+                let keep: i64 = row.get(1)?;
+                Ok(((!channel.is_empty()).then_some(channel), keep as usize))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(Self {
+            conn,
+            queue: Vec::new(),
+            oldest: None,
+            default_limit,
+            limits,
+        })
+    }
+    // How many messages `channel` keeps, `None` for all of them
+    pub fn limit(&self, channel: Option<&str>) -> Option<usize> {
+        self.limits
+            .get(&channel.map(String::from))
+            .copied()
+            .or(self.default_limit)
+    }
+    // Sets how many messages `channel` keeps from now on, dropping the
+    // older ones right away
+    pub fn set_limit(&mut self, channel: Option<&str>, keep: usize) -> rusqlite::Result<()> {
+        self.flush();
+        self.conn.execute(
+            "INSERT INTO limits (channel, keep) VALUES (?1, ?2)
+            ON CONFLICT (channel) DO UPDATE SET keep = ?2",
+            params![channel.unwrap_or_default(), keep as i64],
+        )?;
+        self.limits.insert(channel.map(String::from), keep);
+        self.trim(channel, keep)
+    }
+    // Deletes all but the latest `keep` messages of `channel`
+    fn trim(&self, channel: Option<&str>, keep: usize) -> rusqlite::Result<()> {
+        self.conn
+            .prepare_cached(
+                "DELETE FROM messages WHERE channel IS ?1 AND id <= (
+                    SELECT id FROM messages WHERE channel IS ?1
+                    ORDER BY id DESC LIMIT 1 OFFSET ?2
+                )",
+            )?
+            .execute(params![channel, keep as i64])?;
+        Ok(())
+    }
+    pub fn record(&mut self, channel: Option<&str>, nick: &str, text: &str) {
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.queue.push(Entry {
+            at,
+            channel: channel.map(String::from),
+            nick: nick.to_string(),
+            text: text.to_string(),
+        });
+        self.oldest.get_or_insert_with(Instant::now);
+        if self.queue.len() >= MAX_QUEUED {
+            self.flush();
+        }
+    }
+    // When the queue should be written
+    pub fn flush_at(&self) -> Option<Instant> {
+        Some(self.oldest? + FLUSH_INTERVAL)
+    }
+    // Writes what's queued. On failure the messages are dropped rather than
+    // kept piling up.
+    pub fn flush(&mut self) {
+        self.oldest = None;
+        if self.queue.is_empty() {
+            return;
+        }
+        let queue = std::mem::take(&mut self.queue);
+        let written = self.conn.transaction().and_then(|tx| {
+            {
+                let mut insert = tx.prepare_cached(
+                    "INSERT INTO messages (at, channel, nick, text) VALUES (?1, ?2, ?3, ?4)",
+                )?;
+                for e in &queue {
+                    insert.execute(params![e.at, e.channel, e.nick, e.text])?;
+                }
+            }
+            tx.commit()
+        });
+        if let Err(e) = written {
+            error!("Can't record {} messages to the history: {e}", queue.len());
+        }
+        // Only the rooms that just grew can be over their limit
+        let channels: BTreeSet<Option<&str>> = queue.iter().map(|e| e.channel.as_deref()).collect();
+        for channel in channels {
+            if let Some(keep) = self.limit(channel) {
+                if let Err(e) = self.trim(channel, keep) {
+                    error!("Can't trim the history: {e}");
+                }
+            }
+        }
+    }
+    // The latest messages said in `channel` that contain `term`, newest first
+    pub fn search(
+        &mut self,
+        channel: Option<&str>,
+        term: &str,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<Entry>> {
+        // What was just said should be found too
+        self.flush();
+        let pattern = format!(
+            "%{}%",
+            term.replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+        let mut select = self.conn.prepare_cached(
+            "SELECT at, channel, nick, text FROM messages
+            WHERE channel IS ?1 AND text LIKE ?2 ESCAPE '\\'
+            ORDER BY id DESC LIMIT ?3",
+        )?;
+        let rows = select.query_map(params![channel, pattern, limit as i64], |row| {
+            Ok(Entry {
+                at: row.get(0)?,
+                channel: row.get(1)?,
+                nick: row.get(2)?,
+                text: row.get(3)?,
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+impl Drop for History {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.text.as_str()).collect()
+    }
+
+    #[test]
+    fn finds_what_was_said_in_the_room() {
+        let mut history = History::open(Path::new(":memory:"), None).unwrap();
+        history.record(None, "alice", "lunch at noon?");
+        history.record(None, "bob", "100% yes");
+        history.record(Some("#room"), "carol", "lunch elsewhere");
+        history.record(None, "alice", "lunch it is");
+        // Found before the queue is written
+        let found = history.search(None, "lunch", 10).unwrap();
+        assert_eq!(texts(&found), ["lunch it is", "lunch at noon?"]);
+        assert_eq!(found[0].nick, "alice");
+        assert_eq!(
+            texts(&history.search(None, "lunch", 1).unwrap()),
+            ["lunch it is"]
+        );
+        assert_eq!(texts(&history.search(None, "%", 10).unwrap()), ["100% yes"]);
+        assert!(history.search(None, "dinner", 10).unwrap().is_empty());
+        let found = history.search(Some("#room"), "lunch", 10).unwrap();
+        assert_eq!(texts(&found), ["lunch elsewhere"]);
+        assert_eq!(found[0].channel.as_deref(), Some("#room"));
+    }
+
+    #[test]
+    fn limits_trim_what_is_there_and_what_comes() {
+        let path = std::env::temp_dir().join(format!(
+            "smallchat-history-limits-{}.db",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let mut history = History::open(&path, Some(3)).unwrap();
+        for n in 0..5 {
+            history.record(None, "alice", &format!("global {n}"));
+            history.record(Some("#room"), "bob", &format!("room {n}"));
+        }
+        history.flush();
+        let found = history.search(None, "global", 10).unwrap();
+        assert_eq!(texts(&found), ["global 4", "global 3", "global 2"]);
+        history.set_limit(Some("#room"), 1).unwrap();
+        assert_eq!(history.limit(Some("#room")), Some(1));
+        assert_eq!(history.limit(None), Some(3));
+        let found = history.search(Some("#room"), "room", 10).unwrap();
+        assert_eq!(texts(&found), ["room 4"]);
+        history.record(Some("#room"), "bob", "room 5");
+        let found = history.search(Some("#room"), "room", 10).unwrap();
+        assert_eq!(texts(&found), ["room 5"]);
+        // Kept in the database
+        drop(history);
+        let history = History::open(&path, None).unwrap();
+        assert_eq!(history.limit(Some("#room")), Some(1));
+        assert_eq!(history.limit(None), None);
+        drop(history);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
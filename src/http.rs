@@ -0,0 +1,104 @@
+// The few HTTP requests the web listener answers: the page of the built-in
+// web client and the WebSocket upgrade it connects back with.
+use crate::websocket;
+
+const PAGE: &str = include_str!("../web/index.html");
+
+pub enum Response {
+    // Sent, then the connection is closed
+    Close(Vec<u8>),
+    // Sent, then the connection speaks WebSocket
+    Upgrade(Vec<u8>),
+}
+
+// Answers the request head, without the final empty line
+pub fn respond(head: &[u8]) -> Response {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split("\r\n");
+    let request = lines.next().unwrap_or_default();
+    let mut parts = request.split(' ');
+    let (method, path) = (parts.next(), parts.next());
+    let header = |name: &str| {
+        head.split("\r\n").skip(1).find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim()
+                .eq_ignore_ascii_case(name)
+                .then_some(value.trim().to_string())
+        })
+    };
+    match (method, path) {
+        (Some("GET"), Some("/")) => Response::Close(
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{PAGE}",
+                PAGE.len()
+            )
+            .into_bytes(),
+        ),
+        (Some("GET"), Some("/ws"))
+            if header("upgrade").is_some_and(|u| u.eq_ignore_ascii_case("websocket")) =>
+        {
+            let Some(key) = header("sec-websocket-key") else {
+                return status("400 Bad Request");
+            };
+            Response::Upgrade(
+                format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                    websocket::accept_key(&key)
+                )
+                .into_bytes(),
+            )
+        }
+        (Some("GET"), _) => status("404 Not Found"),
+        _ => status("405 Method Not Allowed"),
+    }
+}
+
+fn status(status: &str) -> Response {
+    Response::Close(
+        format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").into_bytes(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status_line(response: &Response) -> String {
+        let (Response::Close(data) | Response::Upgrade(data)) = response;
+        let text = String::from_utf8_lossy(data);
+        text.lines().next().unwrap().to_string()
+    }
+
+    #[test]
+    fn serves_the_page() {
+        let response = respond(b"GET / HTTP/1.1\r\nHost: localhost");
+        assert!(matches!(response, Response::Close(_)));
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let Response::Close(data) = response else {
+            unreachable!()
+        };
+        assert!(data.ends_with(PAGE.as_bytes()));
+    }
+
+    #[test]
+    fn upgrades_to_websocket() {
+        let head = b"GET /ws HTTP/1.1\r\nHost: localhost\r\nupgrade: WebSocket\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==";
+        let Response::Upgrade(data) = respond(head) else {
+            panic!("expected an upgrade");
+        };
+        let text = String::from_utf8(data).unwrap();
+        assert!(text.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(text.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[test]
+    fn refuses_the_rest() {
+        let no_key = respond(b"GET /ws HTTP/1.1\r\nUpgrade: websocket");
+        assert_eq!(status_line(&no_key), "HTTP/1.1 400 Bad Request");
+        let missing = respond(b"GET /favicon.ico HTTP/1.1");
+        assert_eq!(status_line(&missing), "HTTP/1.1 404 Not Found");
+        let post = respond(b"POST / HTTP/1.1");
+        assert_eq!(status_line(&post), "HTTP/1.1 405 Method Not Allowed");
+    }
+}
@@ -0,0 +1,146 @@
+use mio::net::TcpListener;
+use socket2::{Domain, SockRef, Socket, Type};
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{BorrowedFd, FromRawFd, OwnedFd, RawFd};
+
+// `TcpListener::bind` with control over SO_REUSEADDR and the listen backlog,
+// which std and mio don't expose. socket2 makes it close-on-exec.
+pub fn bind(addr: SocketAddr, reuse_addr: bool, backlog: i32) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_nonblocking(true)?;
+    socket.set_reuse_address(reuse_addr)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+    Ok(TcpListener::from_std(socket.into()))
+}
+
+// First fd passed by systemd socket activation
+const LISTEN_FDS_START: RawFd = 3;
+
+// The listening socket systemd passed with socket activation, if any: as
+// `sd_listen_fds` does, `LISTEN_FDS` must be set and `LISTEN_PID` match us.
+pub fn inherited() -> io::Result<Option<TcpListener>> {
+    let ours = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<libc::pid_t>().ok())
+        == Some(unsafe { libc::getpid() });
+    let fds = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    if !ours || fds < 1 {
+        return Ok(None);
+    }
+    // Not for any child to pick up again
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    adopt(LISTEN_FDS_START).map(Some)
+}
+
+// Takes over `fd`, which must be a listening socket. On failure it's left
+// open.
+fn adopt(fd: RawFd) -> io::Result<TcpListener> {
+    let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+    let socket = SockRef::from(&borrowed);
+    if !socket.is_listener()? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the inherited fd {fd} isn't a listening socket"),
+        ));
+    }
+    // mio needs it non-blocking, and it shouldn't leak into anything we spawn
+    socket.set_nonblocking(true)?;
+    socket.set_cloexec(true)?;
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    Ok(TcpListener::from_std(socket.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bound_socket_is_nonblocking_and_cloexec() {
+        let listener = bind("127.0.0.1:0".parse().unwrap(), true, 16).unwrap();
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&listener);
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        assert_ne!(flags & libc::O_NONBLOCK, 0);
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        assert_ne!(flags & libc::FD_CLOEXEC, 0);
+        // Nothing is waiting, so accepting doesn't block
+        let e = listener.accept().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn rebinds_a_port_left_in_time_wait() {
+        use std::io::Read;
+        let listener = bind("127.0.0.1:0".parse().unwrap(), true, 16).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        let started = std::time::Instant::now();
+        let (conn, _) = loop {
+            match listener.accept() {
+                Ok(accepted) => break accepted,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(started.elapsed().as_secs() < 5);
+                }
+                Err(e) => panic!("{e}"),
+            }
+        };
+        // Closed on the server's side first, which leaves it in TIME_WAIT
+        drop(conn);
+        client.read_exact(&mut [0]).unwrap_err();
+        drop(client);
+        drop(listener);
+        assert_eq!(
+            bind(addr, false, 16).unwrap_err().kind(),
+            io::ErrorKind::AddrInUse
+        );
+        let listener = bind(addr, true, 16).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn adopts_an_inherited_listener() {
+        use std::os::fd::IntoRawFd;
+        let std_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = std_listener.local_addr().unwrap();
+        // Blocking, as systemd may pass it
+        let listener = adopt(std_listener.into_raw_fd()).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+        let e = listener.accept().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        let _client = std::net::TcpStream::connect(addr).unwrap();
+        let started = std::time::Instant::now();
+        loop {
+            match listener.accept() {
+                Ok(_) => break,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(started.elapsed().as_secs() < 5);
+                }
+                Err(e) => panic!("{e}"),
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_an_inherited_socket_that_isnt_listening() {
+        use std::os::fd::AsRawFd;
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        let e = adopt(socket.as_raw_fd()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        // Left open on failure, still ours
+        assert_eq!(socket.r#type().unwrap(), Type::STREAM);
+    }
+
+    #[test]
+    fn nothing_inherited_without_listen_fds() {
+        if env::var_os("LISTEN_FDS").is_none() {
+            assert!(inherited().unwrap().is_none());
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+// Prints records as they are: warnings and errors to stderr, the rest to
+// stdout
+struct StdoutLogger;
+
+impl Log for StdoutLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() <= Level::Warn {
+            eprintln!("{}", record.args());
+        } else {
+            println!("{}", record.args());
+        }
+    }
+    fn flush(&self) {}
+}
+
+pub fn init(level: LevelFilter) {
+    // Only fails if a logger is already set, and then that one is used
+    let _ = log::set_logger(&StdoutLogger);
+    log::set_max_level(level);
+}
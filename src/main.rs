@@ -1,11 +1,130 @@
-use mio::net::{TcpListener, TcpStream};
-use mio::{Events, Interest, Poll, Token};
-use std::collections::BTreeMap;
-use std::io::{self, prelude::*};
+use auth::{random_token, AuthResult, Authenticator, FileBackedUsers, SharedPassword};
+use bucket::TokenBucket;
+use channel::Channel;
+use command::{Access, Command, RequestError, COMMANDS};
+#[cfg(feature = "compress")]
+use compress::Deflate;
+use config::Config;
+use error::ServerError;
+use framer::{Frame, Framer, HttpFramer, LengthPrefixedFramer, LineFramer, WebSocketFramer};
+use heartbeat::Heartbeat;
+#[cfg(feature = "sqlite")]
+use history::History;
+use hmac::{hex, hmac_sha256};
+use http::Response;
+use json::Value;
+use log::{debug, error, info, warn};
+use message::{Message, Priority};
+use mio::event::Event;
+use mio::net::TcpListener;
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use nickgen::NickGenerator;
+use signal::Signals;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, prelude::*, IoSlice};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
+use std::process::ExitCode;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use stream::Stream;
+use text::{strip_ansi, truncate_utf8, wildcard_match, Encoding};
+use token::{TokenAllocator, SELF_TEST, SERVER, SIGNALS, WEB};
+use transcript::Transcripts;
+use vote::Ballot;
+
+mod auth;
+mod bucket;
+mod channel;
+mod command;
+#[cfg(feature = "compress")]
+mod compress;
+mod config;
+mod error;
+mod framer;
+mod heartbeat;
+#[cfg(feature = "sqlite")]
+mod history;
+mod hmac;
+mod http;
+mod json;
+mod listener;
+mod logger;
+mod message;
+// Only tests build clients on it
+#[cfg(any(test, feature = "test-transport"))]
+#[cfg_attr(not(test), allow(dead_code))]
+mod memstream;
+mod nickgen;
+mod quiet;
+mod selftest;
+mod signal;
+mod stream;
+mod template;
+mod text;
+mod token;
+mod transcript;
+mod vote;
+mod websocket;
 
-const SERVER: Token = Token(0);
 const BUFLEN: usize = 4096;
+const DEFAULT_MOTD: &str = "Welcome to Simple Chat!\nUse /nick <nick> to set your nick.";
+const DEFAULT_PROMPT: &str = "> ";
+// Back to the start of the line and erase it, for /readline
+const CLEAR_LINE: &[u8] = b"\r\x1b[K";
+const MAX_PROMPT_LEN: usize = 16;
+const MAX_ALIASES: usize = 16;
+const MAX_ALIAS_NAME_LEN: usize = 32;
+const MAX_ALIAS_LEN: usize = 256;
+const MAX_STATUS_LEN: usize = 64;
+const MAX_TOPIC_LEN: usize = 256;
+// Longest nick that can be picked, and longest shown in front of a message
+const MAX_NICK_LEN: usize = 64;
+const MAX_RENDERED_NICK: usize = 32;
+// How often a client may use /fortune
+const FORTUNE_COOLDOWN: Duration = Duration::from_secs(10);
+// How often a client may use /report, and the messages before it that
+// go along
+const REPORT_COOLDOWN: Duration = Duration::from_secs(30);
+const REPORT_CONTEXT: usize = 5;
+// Matches /search shows at most
+#[cfg(feature = "sqlite")]
+const MAX_SEARCH_RESULTS: usize = 10;
+// Nicks /complete offers at most
+const MAX_COMPLETIONS: usize = 10;
+// Chat messages remembered for reactions and reports
+const MAX_RECENT_MESSAGES: usize = 256;
+const MAX_REACTION_LEN: usize = 16;
+// How long a token minted with /invite new stays valid, and how many may
+// be outstanding
+const INVITE_TOKEN_TTL: Duration = Duration::from_secs(3600);
+const MAX_INVITE_TOKENS: usize = 64;
+// Ids given with /send that are remembered, and their length
+const MAX_SEND_IDS: usize = 4096;
+const MAX_SEND_ID_LEN: usize = 64;
+// How many disconnections /recent remembers, and for how long
+const MAX_DEPARTURES: usize = 32;
+const DEPARTURE_TTL: Duration = Duration::from_secs(3600);
+// Nicks /seen remembers
+const MAX_SEEN: usize = 1024;
+// How long output to a client caught by the honeypot is held back
+const TARPIT_DELAY: Duration = Duration::from_secs(5);
+// How long new clients may go past `max_retained`, while the welcome
+// banner and whatever else greets them is still queued
+const WELCOME_GRACE: Duration = Duration::from_secs(5);
+// Input inflated but not yet read, past which a client is dropped
+#[cfg(feature = "compress")]
+const MAX_INFLATED: usize = 16 * BUFLEN;
+// Outbox items handed to a single write, well under any IOV_MAX
+const MAX_IOVECS: usize = 64;
+// Lines handled per client before yielding to the others
+const MAX_LINES_PER_EVENT: usize = 64;
+// How long accepting stops when out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
 
 struct OutboxItem {
     // Using an Rc lets me share a single Buffer with multiple clients.
@@ -13,193 +132,6352 @@ struct OutboxItem {
     // shared `Vec<u8>` in memory
     data: Rc<Vec<u8>>,
     cursor: usize,
+    queued_at: Instant,
+    priority: Priority,
+}
+// The clients a broadcast still has to be handed to, with what each gets
+type Deliveries = VecDeque<(Token, Rc<Vec<u8>>)>;
+
+// How far `Client::flush_outbox` got
+enum FlushStatus {
+    // Nothing is left to send
+    Drained,
+    // Some output waits for the socket, or for the server to let it go
+    Pending,
+    // The connection is broken, whatever is left can't be delivered
+    Failed(io::Error),
+}
+
+enum ReadStatus {
+    Drained,
+    // The read buffer filled up before the socket was drained
+    Full,
+    Eof,
+}
+
+// How messages are delimited on a client's connection, both ways
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Framing {
+    // Newline terminated lines, with a prompt after each message sent
+    Line,
+    // A 4-byte big-endian length followed by that many bytes
+    LengthPrefixed,
+    // A JSON-RPC request per line, responses and notifications back
+    Json,
+    // Waiting for an HTTP request on the web listener
+    Http,
+    WebSocket,
+}
+
+impl Framing {
+    // Payloads are at most as long as the read buffer allows
+    fn framer(self) -> Box<dyn Framer> {
+        match self {
+            Framing::Line | Framing::Json => Box::new(LineFramer),
+            Framing::LengthPrefixed => Box::new(LengthPrefixedFramer {
+                max_len: BUFLEN - 4,
+            }),
+            Framing::Http => Box::new(HttpFramer { max_len: BUFLEN }),
+            Framing::WebSocket => Box::new(WebSocketFramer {
+                max_len: BUFLEN - 14,
+            }),
+        }
+    }
+}
+
+// The client settings that affect how a broadcast is rendered
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct RenderKey {
+    framing: Framing,
+    raw: bool,
+    crlf: bool,
+    readline: bool,
+}
+
+#[derive(Default)]
+struct OutboxStats {
+    // Bytes waiting in the outbox
+    queued: usize,
+    // Bytes of the buffers the outbox keeps alive, sent parts included
+    retained: usize,
+    peak: usize,
+    flushes: u64,
+    // Writes that didn't take all the bytes offered
+    partial_writes: u64,
+}
+
+impl fmt::Display for OutboxStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "queued {} bytes (peak {}), {} flushes, {} partial writes",
+            self.queued, self.peak, self.flushes, self.partial_writes
+        )
+    }
 }
+
 struct Client {
     nick: String,
-    listener: TcpStream,
+    // Chosen with /nick rather than assigned on connect
+    custom_nick: bool,
+    // `None` while in the global room
+    channel: Option<String>,
+    status: Status,
+    // Opted in to read receipts with /receipts
+    receipts: bool,
+    // Senders of private messages not read yet, waiting for a receipt
+    unread_from: BTreeSet<Token>,
+    // Said along with the status, like "in a meeting"
+    status_text: Option<String>,
+    // Defined with /alias, they last for the session
+    aliases: HashMap<String, String>,
+    listener: Stream,
+    ip: IpAddr,
     read_buf: Box<[u8; BUFLEN]>,
     read_buf_start: usize,
-    outbox: Vec<OutboxItem>,
+    discard_line: bool,
+    framing: Framing,
+    // Cuts and wraps messages the way `framing` says, changed with it
+    framer: Box<dyn Framer>,
+    // Still taking `CAP` lines, as nothing else was sent yet
+    negotiating: bool,
+    // Set once `CAP compress` is acked
+    #[cfg(feature = "compress")]
+    deflate: Option<Deflate>,
+    // Inflated input that didn't fit in the read buffer yet
+    #[cfg(feature = "compress")]
+    inflated: Vec<u8>,
+    // Whatever follows the `CAP compress` line is compressed already
+    #[cfg(feature = "compress")]
+    inflate_rest: bool,
+    // Plain `nick: text` lines, without prompt or ANSI sequences
+    raw: bool,
+    // Lines end in CRLF rather than LF
+    crlf: bool,
+    // Each message first wipes the line with the prompt and whatever was
+    // typed after it, set with /readline
+    readline: bool,
+    // What input lines are decoded from, set with /encoding
+    encoding: Encoding,
+    // Echo how each command was parsed
+    debug: bool,
+    // Sent after each message in line framing
+    prompt: String,
+    // Id of the JSON mode request being handled, until it's answered
+    request: Option<Value>,
+    // To be closed once the outbox is drained
+    closing: bool,
+    // Why sending failed, if it did. The client is dropped soon after.
+    failed: Option<io::ErrorKind>,
+    // Set when caught by the honeypot: output is held back this long
+    tarpit: Option<Duration>,
+    // Limits the rate output is sent at, with `--egress-rate`
+    egress: Option<TokenBucket>,
+    // Limits the chat messages sent, with `--message-rate`
+    messages: Option<TokenBucket>,
+    // The user name given to a successful /oper
+    oper_user: Option<String>,
+    // Hash of the last message broadcast, and when
+    last_message: Option<(u64, Instant)>,
+    // When /fortune was last used
+    last_fortune: Option<Instant>,
+    // When /report was last used
+    last_report: Option<Instant>,
+    // Complete lines or frames received so far
+    lines: u64,
+    connected_at: Instant,
+    // When the last complete line or frame was received
+    last_line: Instant,
+    // Unlike the token, never reused while the server runs
+    session_id: u64,
+    outbox: VecDeque<OutboxItem>,
+    stats: OutboxStats,
+    // Shared by all clients: the bytes queued in every outbox
+    queued_total: Rc<Cell<usize>>,
+    // Shared by all clients: the bytes written since the last heartbeat
+    sent_total: Rc<Cell<u64>>,
+    // False once a write would block, until the socket says otherwise
     writable: bool,
+    // Whether WRITABLE is part of the registered interest. It's only
+    // wanted while output is stuck, or idle clients would wake the loop.
+    write_interest: bool,
 }
 
 impl Client {
-    fn write(&mut self, data: impl Into<Rc<Vec<u8>>>) -> Result<(), io::Error> {
-        self.outbox.push(OutboxItem {
-            data: data.into(),
+    // Queues data, sending what it can right away. A failure to send is
+    // recorded in `failed` for `Chat::reap_failed` to act on.
+    fn write(&mut self, data: impl Into<Rc<Vec<u8>>>) {
+        self.write_with(data, Priority::Normal);
+    }
+    // Like `write`, but high priority data goes ahead of everything queued
+    // but earlier high priority data and the item being sent
+    fn write_with(&mut self, data: impl Into<Rc<Vec<u8>>>, priority: Priority) {
+        let data = data.into();
+        #[cfg(feature = "compress")]
+        let (data, priority) = match &mut self.deflate {
+            // The stream only inflates in the order it was compressed, so
+            // nothing may jump the queue
+            Some(deflate) => (Rc::new(deflate.compress(&data)), Priority::Normal),
+            None => (data, priority),
+        };
+        self.stats.queued += data.len();
+        self.stats.retained += data.len();
+        self.queued_total.set(self.queued_total.get() + data.len());
+        self.stats.peak = self.stats.peak.max(self.stats.queued);
+        let item = OutboxItem {
+            data,
             cursor: 0,
-        });
+            queued_at: Instant::now(),
+            priority,
+        };
+        match priority {
+            Priority::Normal => self.outbox.push_back(item),
+            Priority::High => {
+                // Splitting a partly written item would garble both
+                let started = self.outbox.front().is_some_and(|i| i.cursor > 0);
+                let at = self
+                    .outbox
+                    .iter()
+                    .enumerate()
+                    .position(|(n, i)| !(n == 0 && started) && i.priority != Priority::High)
+                    .unwrap_or(self.outbox.len());
+                self.outbox.insert(at, item);
+            }
+        }
         if self.writable {
-            self.flush_outbox()?;
+            // A failure is left for `Chat::reap`, the client can't drop itself
+            self.try_flush();
+        }
+    }
+    // Flushes, recording a failure for whoever can't act on the status
+    fn try_flush(&mut self) -> FlushStatus {
+        let status = self.flush_outbox();
+        if let FlushStatus::Failed(e) = &status {
+            if is_disconnect(e) {
+                debug!("{} went away: {e}", self.nick);
+            } else {
+                error!("Error writing to {}: {e}", self.nick);
+            }
+            self.failed = Some(e.kind());
+        }
+        status
+    }
+    // The status, with its text if there's one
+    fn presence(&self) -> String {
+        match &self.status_text {
+            Some(text) => format!("{}: {text}", self.status),
+            None => self.status.to_string(),
+        }
+    }
+    // The text an alias invocation like `/gm` or `/gm more` stands for
+    fn expand_alias(&self, line: &[u8]) -> Option<Vec<u8>> {
+        let (name, args) = Command::split(line.strip_prefix(b"/")?);
+        let text = self.aliases.get(core::str::from_utf8(name).ok()?)?;
+        let mut expanded = text.clone().into_bytes();
+        if !args.is_empty() {
+            expanded.push(b' ');
+            expanded.extend_from_slice(args);
+        }
+        Some(expanded)
+    }
+    // Private server response
+    fn reply(&mut self, text: &str) {
+        match self.request.take() {
+            Some(id) => self.write_response(id, "result", Value::String(text.to_string())),
+            None => self.write(self.render(&Message::system(text.as_bytes()))),
+        }
+    }
+    // A JSON client learns the id of its own message from the response
+    fn answer_msgid(&mut self, id: u64) {
+        if let Some(request) = self.request.take() {
+            let result = Value::Object(vec![("msgid".into(), Value::Number(id as f64))]);
+            self.write_response(request, "result", result);
+        }
+    }
+    // A reply saying the command failed. It's only told apart from other
+    // replies in JSON mode.
+    fn error(&mut self, text: &str) {
+        match self.request.take() {
+            Some(id) => self.write_error(id, -32000, text),
+            None => self.reply(text),
+        }
+    }
+    fn write_error(&mut self, id: Value, code: i32, message: &str) {
+        let error = Value::Object(vec![
+            ("code".into(), Value::Number(code.into())),
+            ("message".into(), Value::String(message.to_string())),
+        ]);
+        self.write_response(id, "error", error);
+    }
+    fn write_response(&mut self, id: Value, kind: &str, value: Value) {
+        let response = Value::Object(vec![
+            ("jsonrpc".into(), Value::String("2.0".into())),
+            ("id".into(), id),
+            (kind.into(), value),
+        ]);
+        self.write(format!("{response}\n").into_bytes());
+    }
+    // Formats and frames a message the way this client expects it
+    fn render(&self, msg: &Message) -> Vec<u8> {
+        let mut body = Vec::with_capacity(msg.text.len() + 32);
+        if self.raw {
+            if let Some(nick) = msg.nick {
+                push_nick(
+                    &mut body,
+                    &String::from_utf8_lossy(&strip_ansi(nick.as_bytes())),
+                );
+                if msg.private {
+                    body.extend_from_slice(b" (private)");
+                }
+                body.extend_from_slice(b": ");
+            }
+            body.extend_from_slice(&strip_ansi(msg.text));
+        } else {
+            if let Some(nick) = msg.nick {
+                push_nick(&mut body, nick);
+                if msg.private {
+                    body.extend_from_slice(b" (private)");
+                }
+                body.extend_from_slice(b"> ");
+            }
+            body.extend_from_slice(msg.text);
+        }
+        // Signed as sent, line endings included, so the bytes in front of
+        // the signature are the ones it covers
+        if self.framing == Framing::Line && self.crlf {
+            body = to_crlf(&body);
+        }
+        if let Some(sig) = msg.signature(&body) {
+            body.extend_from_slice(format!(" [sig:{sig}]").as_bytes());
+        }
+        match self.framing {
+            Framing::Line => {
+                body = self.framer.encode(&body);
+                if self.crlf {
+                    body = to_crlf(&body);
+                }
+                if !self.raw {
+                    if self.readline {
+                        body.splice(..0, CLEAR_LINE.iter().copied());
+                    }
+                    body.extend_from_slice(self.prompt.as_bytes());
+                }
+                body
+            }
+            Framing::Json => render_notification(msg),
+            // Http is never sent chat, only the response to its request
+            Framing::LengthPrefixed | Framing::WebSocket | Framing::Http => {
+                self.framer.encode(&body)
+            }
+        }
+    }
+    // Clients with the same key get the same bytes for a broadcast.
+    // `None` if this client's rendering is its own.
+    fn render_key(&self) -> Option<RenderKey> {
+        let key = RenderKey {
+            framing: self.framing,
+            raw: self.raw,
+            crlf: self.crlf,
+            readline: self.readline,
+        };
+        (self.raw || self.prompt == DEFAULT_PROMPT).then_some(key)
+    }
+    // The frame at `start` in the read buffer, positioned in the buffer
+    fn next_frame(&mut self, start: usize) -> Frame {
+        let offset = |r: Range<usize>| start + r.start..start + r.end;
+        match self
+            .framer
+            .decode(&mut self.read_buf[start..self.read_buf_start])
+        {
+            Frame::Complete(range, len) => Frame::Complete(offset(range), start + len),
+            Frame::Ping(range, len) => Frame::Ping(offset(range), start + len),
+            Frame::Ignored(len) => Frame::Ignored(start + len),
+            frame => frame,
+        }
+    }
+    // Whether `CAP <feature>` was acked already
+    fn has_feature(&self, feature: &str) -> bool {
+        match feature {
+            #[cfg(feature = "compress")]
+            "compress" => self.deflate.is_some(),
+            _ => false,
+        }
+    }
+    // Switches how messages are cut and wrapped from now on
+    fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+        self.framer = framing.framer();
+    }
+    fn fill_read_buf(&mut self) -> Result<ReadStatus, io::Error> {
+        #[cfg(feature = "compress")]
+        if self.deflate.is_some() {
+            return self.fill_read_buf_inflating();
+        }
+        while self.read_buf_start < BUFLEN {
+            match self
+                .listener
+                .read(&mut self.read_buf[self.read_buf_start..])
+            {
+                Ok(0) => return Ok(ReadStatus::Eof),
+                Ok(n) => {
+                    self.read_buf_start += n;
+                }
+                Err(e) if is_would_block(&e) => return Ok(ReadStatus::Drained),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(ReadStatus::Full)
+    }
+    // Like `fill_read_buf`, for a compressed connection
+    #[cfg(feature = "compress")]
+    fn fill_read_buf_inflating(&mut self) -> Result<ReadStatus, io::Error> {
+        let deflate = self.deflate.as_mut().unwrap();
+        let mut raw = [0; BUFLEN];
+        while self.read_buf_start < BUFLEN {
+            if !self.inflated.is_empty() {
+                let n = self.inflated.len().min(BUFLEN - self.read_buf_start);
+                self.read_buf[self.read_buf_start..self.read_buf_start + n]
+                    .copy_from_slice(&self.inflated[..n]);
+                self.inflated.drain(..n);
+                self.read_buf_start += n;
+                continue;
+            }
+            match self.listener.read(&mut raw) {
+                Ok(0) => return Ok(ReadStatus::Eof),
+                Ok(n) => deflate.decompress(&raw[..n], &mut self.inflated, MAX_INFLATED)?,
+                Err(e) if is_would_block(&e) => return Ok(ReadStatus::Drained),
+                Err(e) => return Err(e),
+            }
         }
+        Ok(ReadStatus::Full)
+    }
+    // Inflates what's in the read buffer from `start` on, which arrived
+    // compressed right after `CAP compress`
+    #[cfg(feature = "compress")]
+    fn inflate_from(&mut self, start: usize) -> Result<(), io::Error> {
+        let deflate = self.deflate.as_mut().unwrap();
+        let rest = &self.read_buf[start..self.read_buf_start];
+        deflate.decompress(rest, &mut self.inflated, MAX_INFLATED)?;
+        self.read_buf_start = start;
         Ok(())
     }
-    fn flush_outbox(&mut self) -> Result<(), io::Error> {
-        while !self.outbox.is_empty() {
-            let cursor = self.outbox[0].cursor;
-            match self.listener.write(&self.outbox[0].data[cursor..]) {
+    // Drop the first `consumed` bytes, moving any partial line to the front.
+    fn compact_read_buf(&mut self, consumed: usize) {
+        self.read_buf.copy_within(consumed..self.read_buf_start, 0);
+        self.read_buf_start -= consumed;
+        if self.read_buf_start == BUFLEN {
+            // A whole buffer without a newline: discard the over-long line,
+            // including the tail that is still to come
+            self.read_buf_start = 0;
+            self.discard_line = true;
+        }
+    }
+    fn flush_outbox(&mut self) -> FlushStatus {
+        self.stats.flushes += 1;
+        while let Some(item) = self.outbox.front_mut() {
+            debug_assert!(item.cursor <= item.data.len(), "outbox cursor past the end");
+            if item.cursor >= item.data.len() {
+                if item.cursor > item.data.len() {
+                    // A miscount; slicing from here would panic
+                    error!(
+                        "Outbox cursor of {} at {} past the end of {} bytes",
+                        self.nick,
+                        item.cursor,
+                        item.data.len()
+                    );
+                }
+                self.stats.retained -= item.data.len();
+                self.outbox.pop_front();
+                continue;
+            }
+            if self
+                .tarpit
+                .is_some_and(|delay| item.queued_at.elapsed() < delay)
+            {
+                // Held back, `Chat::release_held` comes back for it
+                break;
+            }
+            let mut budget = usize::MAX;
+            if let Some(bucket) = &mut self.egress {
+                budget = bucket.available();
+                if budget == 0 {
+                    // Shaped, `Chat::release_held` comes back for it
+                    break;
+                }
+            }
+            // As many items as fit in one write, up to the first one still
+            // held back
+            let mut slices = [IoSlice::new(&[]); MAX_IOVECS];
+            let mut count = 0;
+            let mut offered = 0;
+            for item in self.outbox.iter().take(MAX_IOVECS) {
+                if offered == budget
+                    || self
+                        .tarpit
+                        .is_some_and(|delay| item.queued_at.elapsed() < delay)
+                {
+                    break;
+                }
+                let len = (item.data.len() - item.cursor).min(budget - offered);
+                slices[count] = IoSlice::new(&item.data[item.cursor..item.cursor + len]);
+                count += 1;
+                offered += len;
+            }
+            match self.listener.write_vectored(&slices[..count]) {
                 Ok(0) => {
-                    self.outbox.remove(0);
+                    self.writable = false;
                     break;
                 }
                 Ok(n) => {
-                    self.outbox[0].cursor += n;
+                    if n < offered {
+                        self.stats.partial_writes += 1;
+                    }
+                    // Never past what was offered, even if `write_vectored` claimed more
+                    let n = n.min(offered);
+                    if let Some(bucket) = &mut self.egress {
+                        bucket.consume(n);
+                    }
+                    // Sent items are done, and the one the write stopped in
+                    // is sent from there next time
+                    let mut left = n;
+                    for item in self.outbox.iter_mut() {
+                        if left == 0 {
+                            break;
+                        }
+                        let advance = (item.data.len() - item.cursor).min(left);
+                        item.cursor += advance;
+                        left -= advance;
+                    }
+                    let sent = n.min(self.stats.queued);
+                    self.stats.queued -= sent;
+                    self.queued_total.set(self.queued_total.get() - sent);
+                    self.sent_total.set(self.sent_total.get() + n as u64);
                 }
-                Err(e) if is_would_block(&e) => {
+                Err(e) if is_would_block(&e) || is_transient(&e) => {
+                    self.writable = false;
                     break;
                 }
-                Err(e) => return Err(e.into()),
+                Err(e) => return FlushStatus::Failed(e),
             }
         }
-        Ok(())
+        if self.outbox.is_empty() {
+            FlushStatus::Drained
+        } else {
+            FlushStatus::Pending
+        }
+    }
+    // Bytes of the buffers the outbox keeps alive, sent parts included
+    fn retained(&self) -> usize {
+        self.stats.retained
+    }
+    // Whether it's too early to evict it for keeping too much queued
+    fn in_welcome_grace(&self) -> bool {
+        self.connected_at.elapsed() < WELCOME_GRACE
+    }
+    // When the oldest undelivered output was due to be sent
+    fn stalled_since(&self) -> Option<Instant> {
+        let item = self.outbox.front()?;
+        Some(item.queued_at + self.tarpit.unwrap_or_default())
+    }
+    // When held back output of a tarpitted client may go out
+    fn tarpit_release(&self) -> Option<Instant> {
+        let delay = self.tarpit?;
+        Some(self.outbox.front()?.queued_at + delay)
+    }
+    // When output held back by egress shaping may go out
+    fn shaping_release(&self) -> Option<Instant> {
+        if self.outbox.is_empty() {
+            return None;
+        }
+        Some(self.egress.as_ref()?.ready_at())
+    }
+    // When something held back by the server itself, rather than by the
+    // socket, is due
+    fn release_at(&self) -> Option<Instant> {
+        if !self.writable {
+            // The WRITABLE event comes first
+            return None;
+        }
+        [self.tarpit_release(), self.shaping_release()]
+            .into_iter()
+            .flatten()
+            .min()
     }
 }
 
-struct Chat {
-    clients: BTreeMap<Token, Client>,
-    max_client: Token,
+struct Departure {
+    nick: String,
+    ip: IpAddr,
+    reason: String,
+    at: Instant,
 }
 
-impl Chat {
-    fn new() -> Self {
-        Self {
-            clients: Default::default(),
-            max_client: Token(0),
-        }
-    }
-    fn push_from(&mut self, src: &Token, data: Vec<u8>) {
-        let data = Rc::new(data);
-        for (_, c) in self.clients.iter_mut().filter(|(k, _)| *k != src) {
-            c.write(data.clone()).unwrap();
-        }
+// One of the latest chat messages
+struct RecentMessage {
+    id: u64,
+    channel: Option<String>,
+    // `nick> text` on one line, shown along with a /report
+    line: String,
+}
+
+// A message sent with /send, under the id its sender gave it
+struct SendId {
+    nick: String,
+    client_id: String,
+    msgid: u64,
+    at: Instant,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // Whatever is still queued goes away with the client
+        self.queued_total
+            .set(self.queued_total.get() - self.stats.queued);
     }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut chat = Chat::new();
-    let mut poll = Poll::new()?;
-    let addr = "127.0.0.1:7711".parse().unwrap();
+// Presence advertised with /status
+#[derive(Clone, Copy, Default, PartialEq)]
+enum Status {
+    #[default]
+    Online,
+    Busy,
+    Away,
+}
 
-    let mut server = TcpListener::bind(addr)?;
-    println!("Server started at {addr}");
-    poll.registry()
-        .register(&mut server, SERVER, Interest::READABLE)?;
+impl Status {
+    fn parse(name: &[u8]) -> Option<Self> {
+        match name {
+            b"online" => Some(Status::Online),
+            b"busy" => Some(Status::Busy),
+            b"away" => Some(Status::Away),
+            _ => None,
+        }
+    }
+}
 
-    let mut events = Events::with_capacity(1024);
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Online => write!(f, "online"),
+            Status::Busy => write!(f, "busy"),
+            Status::Away => write!(f, "away"),
+        }
+    }
+}
 
-    loop {
-        poll.poll(&mut events, None)?;
-        for event in &events {
-            let token = event.token();
-            if token == Token(0) {
-                loop {
-                    let (mut conn, addr) = match server.accept() {
-                        Ok((conn, addr)) => (conn, addr),
-                        Err(e) if is_would_block(&e) => break,
-                        Err(e) => {
-                            return Err(e.into());
-                        }
-                    };
-                    let next_client = Token(chat.max_client.0 + 1);
-                    poll.registry().register(
-                        &mut conn,
-                        next_client,
-                        Interest::READABLE | Interest::WRITABLE,
-                    )?;
-                    let mut client = Client {
-                        nick: format!("user:{}", next_client.0),
-                        listener: conn,
-                        read_buf: Box::new([0; 4096]),
-                        read_buf_start: 0,
-                        outbox: Default::default(),
-                        writable: false,
-                    };
-                    client.write(
-                        "Welcome to Simple Chat!\nUse /nick <nick> to set your nick.\n> "
-                            .to_string()
-                            .into_bytes(),
-                    )?;
-                    chat.clients.insert(next_client, client);
-                    chat.max_client = next_client;
-                    println!("Connected client from {addr}");
-                }
-            } else {
-                if event.is_readable() {
-                    let mut finished = false;
-                    loop {
-                        let client = chat.clients.get_mut(&token).unwrap();
-                        match client
-                            .listener
-                            .read(&mut client.read_buf[client.read_buf_start..])
-                        {
-                            Ok(0) => {
-                                finished = true;
-                                break;
-                            }
-                            Ok(n) => {
-                                client.read_buf_start += n;
-                            }
-                            Err(e) if is_would_block(&e) => {
-                                break;
-                            }
-                            Err(e) => {
-                                return Err(e.into());
-                            }
-                        }
-                    }
-                    let mut start = 0;
-                    loop {
-                        let client = chat.clients.get_mut(&token).unwrap();
-                        let Some(len) = client.read_buf[start..client.read_buf_start]
-                            .iter()
-                            .enumerate()
-                            .find(|(_, x)| **x == '\n' as u8)
-                            .map(|(i, _)| i)
-                        else {
-                            break;
-                        };
+enum DisconnectReason {
+    // The peer closed the connection
+    Eof,
+    Error(io::ErrorKind),
+    // Output couldn't be delivered within `send_timeout`
+    SendTimeout,
+    // Kept too much shared output alive, with `--max-retained`
+    Lagging,
+    // Dropped to bring the memory used by all outboxes under the cap
+    MemoryPressure,
+    // Announced a binary frame larger than the read buffer
+    FrameTooLarge,
+    // No complete line within `handshake_timeout` of connecting
+    HandshakeTimeout,
+    // An HTTP response went out
+    Served,
+    Quit,
+}
 
-                        let msg = &client.read_buf[start..start + len];
+impl DisconnectReason {
+    // Said to the client itself before closing, when it can still hear it
+    fn peer_notice(&self) -> Option<&'static str> {
+        match self {
+            DisconnectReason::Quit => Some("Bye!"),
+            _ => None,
+        }
+    }
+}
 
-                        // Build response to send
-                        let mut res = Vec::new();
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DisconnectReason::Eof => write!(f, "connection closed"),
+            DisconnectReason::Error(kind) => write!(f, "error: {kind}"),
+            DisconnectReason::SendTimeout => write!(f, "send timeout"),
+            DisconnectReason::Lagging => write!(f, "lagging behind"),
+            DisconnectReason::MemoryPressure => write!(f, "server out of buffer space"),
+            DisconnectReason::FrameTooLarge => write!(f, "frame too large"),
+            DisconnectReason::HandshakeTimeout => write!(f, "handshake timeout"),
+            DisconnectReason::Served => write!(f, "served"),
+            DisconnectReason::Quit => write!(f, "quit"),
+        }
+    }
+}
 
-                        if let Some(nick) = msg.strip_prefix("/nick ".as_bytes()) {
-                            client.nick.clear();
-                            if let Ok(nick) = core::str::from_utf8(nick) {
-                                client.nick.push_str(nick);
-                                res.extend_from_slice("nick changed to ".as_bytes());
-                                res.extend_from_slice(nick.as_bytes());
-                                res.extend_from_slice("\n> ".as_bytes());
+struct Chat {
+    config: Config,
+    // Message of the day, sent to every client on connect
+    motd: String,
+    // Lines /fortune picks from, none when it's disabled
+    fortunes: Vec<String>,
+    clients: BTreeMap<Token, Client>,
+    tokens: TokenAllocator,
+    // Clients with complete lines left unprocessed in their read buffer
+    pending_input: BTreeSet<Token>,
+    // Unused /login-token tokens, until when they are valid
+    invite_tokens: HashMap<String, Instant>,
+    // Nicks of clients that left recently, until when they are reserved
+    reserved_nicks: HashMap<String, Instant>,
+    channels: HashMap<String, Channel>,
+    // The open /poll of each room, `None` being the global one
+    polls: HashMap<Option<String>, Ballot>,
+    admins: BTreeSet<Token>,
+    // The first client to connect, or the oldest once it left. Only with
+    // `founder_greeting` or `founder_admin`.
+    founder: Option<Token>,
+    // Makes up nicks for new clients
+    nicks: NickGenerator,
+    // Checks /oper credentials, `None` when nobody can become an admin
+    auth: Option<Box<dyn Authenticator>>,
+    next_message_id: u64,
+    // The latest chat messages, the ones that can be reacted to
+    recent_messages: VecDeque<RecentMessage>,
+    // Ids clients gave their messages with /send, to recognize replays
+    send_ids: VecDeque<SendId>,
+    // Latest disconnections, oldest first, for /recent
+    departures: VecDeque<Departure>,
+    // Set with /maintenance: only admins' messages go through
+    maintenance: bool,
+    // Inside one of the `--quiet-hours` windows: only admins' messages go
+    // through, like in maintenance
+    quiet: bool,
+    // Set by /drain or SIGUSR1: no new clients are taken, and the server
+    // exits by then
+    draining: Option<Instant>,
+    // Out of file descriptors: no connection is accepted until then
+    accept_paused: Option<Instant>,
+    // When each nick last sent a message or left
+    last_seen: HashMap<String, Instant>,
+    // Bytes queued across all outboxes, kept up to date by the clients
+    queued_total: Rc<Cell<usize>>,
+    heartbeat: Heartbeat,
+    // Where chat messages are recorded, with `--db`
+    #[cfg(feature = "sqlite")]
+    history: Option<History>,
+    // With `--transcript-dir`
+    transcripts: Option<Transcripts>,
+    // Broadcasts not handed to every recipient yet, oldest first
+    fanout: VecDeque<(Priority, Deliveries)>,
+    // Clients a broadcast would have taken past `max_retained`, to evict
+    laggards: BTreeSet<Token>,
+    // Connections accepted so far, for sampling their log lines
+    connects: u64,
+    // For the `{uptime}` of templates
+    started: Instant,
+    // Given to the next client, starting from 1
+    next_session_id: u64,
+    // Live connections per address, for `max_per_ip`
+    connections_per_ip: HashMap<IpAddr, usize>,
+    // Key addresses are hashed with, made up at startup, with `--anonymize-ips`
+    ip_salt: Option<String>,
+}
 
-                                client.write(res)?;
-                            } else {
-                                client.write("invalid nick".as_bytes().to_vec())?;
-                            }
-                        } else {
-                            res.extend_from_slice(client.nick.as_bytes());
-                            res.extend_from_slice("> ".as_bytes());
-                            res.extend(msg);
-                            res.extend_from_slice("\n> ".as_bytes());
-                            chat.push_from(&token, res);
-                        }
-                        start += len + 1;
-                    }
-                    if finished {
-                        let client = chat.clients.get_mut(&token).unwrap();
-                        client.read_buf_start = 0;
+impl Chat {
+    fn new(config: Config) -> Result<Self, ServerError> {
+        let motd = load_motd(&config)?;
+        let fortunes = match &config.fortunes {
+            Some(path) => std::fs::read_to_string(path)?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(String::from)
+                .collect(),
+            None => Vec::new(),
+        };
+        let auth: Option<Box<dyn Authenticator>> =
+            match (&config.admin_users, &config.admin_password) {
+                (Some(path), _) => Some(Box::new(FileBackedUsers::load(path)?)),
+                (None, Some(password)) => Some(Box::new(SharedPassword(password.clone()))),
+                (None, None) => None,
+            };
+        let nicks = NickGenerator::new(config.default_nick.clone());
+        let heartbeat = Heartbeat::new(config.heartbeat_interval);
+        #[cfg(feature = "sqlite")]
+        let history = match &config.db {
+            Some(path) => {
+                Some(History::open(path, config.history_limit).map_err(io::Error::other)?)
+            }
+            None => None,
+        };
+        #[cfg(not(feature = "sqlite"))]
+        if config.db.is_some() {
+            return Err(ServerError::Config(
+                "--db needs a build with the sqlite feature".into(),
+            ));
+        }
+        let transcripts = match &config.transcript_dir {
+            Some(dir) => Some(Transcripts::new(dir, config.max_log_size)?),
+            None => None,
+        };
+        let ip_salt = match config.anonymize_ips {
+            true => Some(random_token()?),
+            false => None,
+        };
+        Ok(Self {
+            ip_salt,
+            transcripts,
+            config,
+            motd,
+            fortunes,
+            clients: Default::default(),
+            tokens: TokenAllocator::new(),
+            pending_input: Default::default(),
+            reserved_nicks: Default::default(),
+            invite_tokens: Default::default(),
+            channels: Default::default(),
+            polls: Default::default(),
+            admins: Default::default(),
+            founder: None,
+            nicks,
+            auth,
+            next_message_id: 1,
+            recent_messages: Default::default(),
+            send_ids: Default::default(),
+            departures: Default::default(),
+            last_seen: Default::default(),
+            maintenance: false,
+            quiet: false,
+            draining: None,
+            accept_paused: None,
+            queued_total: Default::default(),
+            heartbeat,
+            #[cfg(feature = "sqlite")]
+            history,
+            fanout: Default::default(),
+            laggards: Default::default(),
+            connects: 0,
+            started: Instant::now(),
+            next_session_id: 1,
+            connections_per_ip: Default::default(),
+        })
+    }
+    // Re-reads what can safely change while running. Everything else, like
+    // the listen address, needs a restart.
+    fn reload(&mut self) {
+        match load_motd(&self.config) {
+            Ok(motd) if motd != self.motd => {
+                self.motd = motd;
+                info!("Reloaded: MOTD changed");
+            }
+            Ok(_) => info!("Reloaded: nothing changed"),
+            Err(e) => warn!("Can't reload the MOTD, keeping the old one: {e}"),
+        }
+    }
+    // How long the poller may sleep before some client deadline passes
+    fn poll_timeout(&self) -> Option<Duration> {
+        if !self.pending_input.is_empty() || !self.fanout.is_empty() {
+            return Some(Duration::ZERO);
+        }
+        let stall = self.config.send_timeout.and_then(|send_timeout| {
+            let oldest = self
+                .clients
+                .values()
+                .filter_map(Client::stalled_since)
+                .min()?;
+            Some(oldest + send_timeout)
+        });
+        let held = self.clients.values().filter_map(Client::release_at).min();
+        let deadline = [
+            stall,
+            held,
+            self.handshake_deadline(),
+            self.welcome_grace_end(),
+            self.history_flush_at(),
+            self.transcripts.as_ref().and_then(Transcripts::flush_at),
+            self.heartbeat.due_at(),
+            self.quiet_hours_change(unix_time()),
+            self.draining,
+            self.accept_paused,
+        ]
+        .into_iter()
+        .flatten()
+        .min()?;
+        Some(deadline.saturating_duration_since(Instant::now()))
+    }
+    // Enters or leaves quiet hours when the clock says so, telling everyone.
+    // `now` is the seconds since the Unix epoch.
+    fn update_quiet_hours(&mut self, now: u64) {
+        let now = quiet::time_of_day(now);
+        let window = self.config.quiet_hours.iter().find(|w| w.contains(now));
+        if window.is_some() == self.quiet {
+            return;
+        }
+        self.quiet = window.is_some();
+        let notice = match window {
+            Some(window) => {
+                format!(
+                    "* quiet hours until {} UTC, only admins can talk",
+                    window.end()
+                )
+            }
+            None => "* quiet hours over, everyone can talk again".into(),
+        };
+        info!(
+            "Quiet hours {}",
+            if self.quiet { "started" } else { "ended" }
+        );
+        self.push(&Message::system(notice.as_bytes()).urgent(), |_, _| true);
+    }
+    // When a quiet hours window next opens or closes, `now` being the
+    // seconds since the Unix epoch
+    fn quiet_hours_change(&self, now: u64) -> Option<Instant> {
+        let now = quiet::time_of_day(now);
+        let secs = self
+            .config
+            .quiet_hours
+            .iter()
+            .map(|w| w.until_change(now))
+            .min()?;
+        Some(Instant::now() + Duration::from_secs(secs.into()))
+    }
+    // When a new client already past `max_retained` runs out of grace
+    fn welcome_grace_end(&self) -> Option<Instant> {
+        let max = self.config.max_retained?;
+        self.clients
+            .values()
+            .filter(|c| c.retained() > max && c.in_welcome_grace())
+            .map(|c| c.connected_at + WELCOME_GRACE)
+            .min()
+    }
+    // Logs why a connection couldn't be accepted, and returns whether the
+    // next one may be. Out of file descriptors, accepting pauses for a
+    // while rather than spinning on the same error.
+    fn accept_failed(&mut self, e: &io::Error) -> bool {
+        warn!("Can't accept a connection: {e}");
+        match e.raw_os_error() {
+            Some(libc::EMFILE | libc::ENFILE) => {
+                self.accept_paused = Some(Instant::now() + ACCEPT_BACKOFF);
+                false
+            }
+            // The client gave up while waiting to be accepted
+            Some(libc::ECONNABORTED) => true,
+            _ => e.kind() == io::ErrorKind::Interrupted,
+        }
+    }
+    // Stops taking new clients, and has the server exit once the current
+    // ones are gone or `drain_timeout` passes
+    fn start_drain(&mut self) {
+        if self.draining.is_some() {
+            return;
+        }
+        self.draining = Some(Instant::now() + self.config.drain_timeout);
+        let notice = b"* server draining, reconnect to the new instance soon";
+        self.push(&Message::system(notice).urgent(), |_, _| true);
+    }
+    // Whether a drain is over, leaving nothing to wait for
+    fn drained(&self) -> bool {
+        self.draining
+            .is_some_and(|deadline| self.clients.is_empty() || Instant::now() >= deadline)
+    }
+    // Replies with the latest messages in the client's room containing `term`
+    #[cfg(feature = "sqlite")]
+    fn search(&mut self, token: Token, term: &[u8]) {
+        let client = self.clients.get_mut(&token).unwrap();
+        let Some(history) = &mut self.history else {
+            client.error("history is disabled");
+            return;
+        };
+        let term = String::from_utf8_lossy(term);
+        let found = match history.search(client.channel.as_deref(), &term, MAX_SEARCH_RESULTS) {
+            Ok(found) => found,
+            Err(e) => {
+                error!("Can't search the history: {e}");
+                client.error("search failed");
+                return;
+            }
+        };
+        if found.is_empty() {
+            client.reply(&format!("nothing found for {term}"));
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let lines: Vec<String> = found
+            .iter()
+            .map(|e| {
+                let ago = now.saturating_sub(Duration::from_secs(e.at));
+                format!("{} {}> {}", format_ago(ago), e.nick, e.text)
+            })
+            .collect();
+        client.reply(&lines.join("\n"));
+    }
+    #[cfg(not(feature = "sqlite"))]
+    fn search(&mut self, token: Token, _term: &[u8]) {
+        let client = self.clients.get_mut(&token).unwrap();
+        client.error("history is disabled");
+    }
+    // Shows or sets how many messages the history keeps for the client's room
+    #[cfg(feature = "sqlite")]
+    fn history_limit(&mut self, token: Token, arg: &[u8]) {
+        let client = self.clients.get_mut(&token).unwrap();
+        if !self.admins.contains(&token) {
+            client.error("permission denied");
+            return;
+        }
+        let Some(history) = &mut self.history else {
+            client.error("history is disabled");
+            return;
+        };
+        let channel = client.channel.as_deref();
+        let room = channel.unwrap_or("the global room");
+        if arg.is_empty() {
+            let text = match history.limit(channel) {
+                Some(keep) => format!("{room} keeps the latest {keep} messages"),
+                None => format!("{room} keeps every message"),
+            };
+            client.reply(&text);
+            return;
+        }
+        let Some(keep) = core::str::from_utf8(arg).ok().and_then(|n| n.parse().ok()) else {
+            client.error("usage: /history-limit <messages>");
+            return;
+        };
+        if let Err(e) = history.set_limit(channel, keep) {
+            error!("Can't set the history limit: {e}");
+            client.error("setting the limit failed");
+            return;
+        }
+        let text = format!("{room} now keeps the latest {keep} messages");
+        info!("{}: {text}", client.nick);
+        client.reply(&text);
+    }
+    #[cfg(not(feature = "sqlite"))]
+    fn history_limit(&mut self, token: Token, _arg: &[u8]) {
+        let client = self.clients.get_mut(&token).unwrap();
+        client.error("history is disabled");
+    }
+    // Writes the recorded messages that waited long enough
+    fn flush_history(&mut self) {
+        #[cfg(feature = "sqlite")]
+        if let Some(history) = &mut self.history {
+            if history.flush_at().is_some_and(|at| at <= Instant::now()) {
+                history.flush();
+            }
+        }
+    }
+    // Writes the transcript lines that waited long enough
+    fn flush_transcripts(&mut self) {
+        if let Some(transcripts) = &mut self.transcripts {
+            if transcripts
+                .flush_at()
+                .is_some_and(|at| at <= Instant::now())
+            {
+                transcripts.flush();
+            }
+        }
+    }
+    fn history_flush_at(&self) -> Option<Instant> {
+        #[cfg(feature = "sqlite")]
+        return self.history.as_ref()?.flush_at();
+        #[cfg(not(feature = "sqlite"))]
+        None
+    }
+    // Logs what happened since the last heartbeat, when one is due
+    fn heartbeat(&mut self) {
+        if let Some(beat) = self.heartbeat.tick(Instant::now()) {
+            info!(
+                "Heartbeat: {} clients, {} messages, {} bytes sent",
+                self.clients.len(),
+                beat.messages,
+                beat.bytes_sent
+            );
+        }
+    }
+    // Asks for WRITABLE events exactly for the clients whose output is stuck
+    fn update_interests(&mut self, registry: &Registry) {
+        for (token, c) in self.clients.iter_mut() {
+            let want = !c.writable && !c.outbox.is_empty();
+            if want == c.write_interest {
+                continue;
+            }
+            let interest = if want {
+                Interest::READABLE | Interest::WRITABLE
+            } else {
+                Interest::READABLE
+            };
+            match registry.reregister(&mut c.listener, *token, interest) {
+                Ok(()) => c.write_interest = want,
+                Err(e) => c.failed = Some(e.kind()),
+            }
+        }
+    }
+    // Disconnects the clients whose output couldn't be sent, and those done
+    // once their output is out. Leave notices can make more sends fail, so
+    // it goes on until none is left.
+    fn reap(&mut self, registry: &Registry) {
+        loop {
+            self.update_interests(registry);
+            let done: Vec<(Token, DisconnectReason)> = self
+                .clients
+                .iter()
+                .filter_map(|(token, c)| match c.failed {
+                    Some(kind) => Some((*token, DisconnectReason::Error(kind))),
+                    None if c.closing && c.outbox.is_empty() => {
+                        Some((*token, DisconnectReason::Served))
                     }
-                }
-                if event.is_writable() {
-                    let client = chat.clients.get_mut(&token).unwrap();
-                    client.writable = true;
-                    client.flush_outbox()?;
-                }
+                    None => None,
+                })
+                .collect();
+            if done.is_empty() {
+                return;
+            }
+            for (token, reason) in done {
+                self.disconnect(token, reason, registry);
             }
         }
     }
-}
-
-fn is_would_block(e: &io::Error) -> bool {
-    e.kind() == io::ErrorKind::WouldBlock
+    // Sends the output tarpitted clients had to wait for
+    fn release_held(&mut self) {
+        let now = Instant::now();
+        for client in self.clients.values_mut() {
+            if client.release_at().is_some_and(|t| t <= now) {
+                client.try_flush();
+            }
+        }
+    }
+    fn expire_stalled(&mut self, registry: &Registry) {
+        let Some(send_timeout) = self.config.send_timeout else {
+            return;
+        };
+        let stalled: Vec<Token> = self
+            .clients
+            .iter()
+            .filter(|(_, c)| {
+                c.stalled_since()
+                    .is_some_and(|t| t.elapsed() >= send_timeout)
+            })
+            .map(|(token, _)| *token)
+            .collect();
+        for token in stalled {
+            self.disconnect(token, DisconnectReason::SendTimeout, registry);
+        }
+    }
+    // When the first client still to send a complete line runs out of time
+    fn handshake_deadline(&self) -> Option<Instant> {
+        let timeout = self.config.handshake_timeout?;
+        self.clients
+            .values()
+            .filter(|c| c.lines == 0)
+            .map(|c| c.connected_at + timeout)
+            .min()
+    }
+    // Drops the clients that connected but never sent a complete line, so
+    // a slow trickle can't hold a connection slot forever
+    fn expire_handshakes(&mut self, registry: &Registry) {
+        let Some(timeout) = self.config.handshake_timeout else {
+            return;
+        };
+        let expired: Vec<Token> = self
+            .clients
+            .iter()
+            .filter(|(_, c)| c.lines == 0 && c.connected_at.elapsed() >= timeout)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired {
+            self.disconnect(token, DisconnectReason::HandshakeTimeout, registry);
+        }
+    }
+    fn mark_seen(&mut self, nick: &str) {
+        if self.last_seen.len() >= MAX_SEEN && !self.last_seen.contains_key(nick) {
+            // Forget whoever was seen the longest ago
+            let oldest = self.last_seen.iter().min_by_key(|(_, at)| **at);
+            if let Some(oldest) = oldest.map(|(nick, _)| nick.clone()) {
+                self.last_seen.remove(&oldest);
+            }
+        }
+        self.last_seen.insert(nick.to_string(), Instant::now());
+    }
+    // Broadcasts are shared between outboxes, and a buffer lives as long
+    // as its slowest recipient still holds it. Clients keeping more than
+    // `max_retained` bytes of buffers alive are dropped so they're freed.
+    fn evict_laggards(&mut self, registry: &Registry) {
+        let Some(max) = self.config.max_retained else {
+            return;
+        };
+        let mut laggards = std::mem::take(&mut self.laggards);
+        laggards.extend(
+            self.clients
+                .iter()
+                .filter(|(_, c)| c.retained() > max && !c.in_welcome_grace())
+                .map(|(token, _)| *token),
+        );
+        for token in laggards {
+            let retained = self.clients[&token].retained();
+            warn!(
+                "Evicting {}: retaining {retained} bytes",
+                self.clients[&token].nick
+            );
+            self.disconnect(token, DisconnectReason::Lagging, registry);
+        }
+    }
+    // Tells whoever sent `token` private messages it hasn't read yet that
+    // it now has. Anything it does after receiving them counts as reading.
+    fn send_receipts(&mut self, token: Token) {
+        let client = self.clients.get_mut(&token).unwrap();
+        let senders = std::mem::take(&mut client.unread_from);
+        let text = format!("{} read your message", client.nick);
+        for sender in senders {
+            if let Some(sender) = self.clients.get_mut(&sender).filter(|c| c.receipts) {
+                sender.reply(&text);
+            }
+        }
+    }
+    fn default_nick(&mut self) -> String {
+        let (clients, reserved) = (&self.clients, &self.reserved_nicks);
+        let now = Instant::now();
+        self.nicks.next(|nick| {
+            clients.values().any(|c| c.nick == nick)
+                || reserved.get(nick).is_some_and(|until| *until > now)
+        })
+    }
+    // The last line of defence against running out of memory, whatever
+    // the per-client limits: while the outboxes together hold more than
+    // `max_total_queued` bytes, the client holding the most is dropped.
+    fn enforce_memory_cap(&mut self, registry: &Registry) {
+        let Some(max) = self.config.max_total_queued else {
+            return;
+        };
+        while self.queued_total.get() > max {
+            let worst = self
+                .clients
+                .iter()
+                .filter(|(_, c)| c.stats.queued > 0)
+                .max_by_key(|(_, c)| (c.stats.queued, std::cmp::Reverse(c.stalled_since())))
+                .map(|(token, _)| *token);
+            let Some(worst) = worst else {
+                return;
+            };
+            let client = &self.clients[&worst];
+            warn!(
+                "Evicting {}: {} bytes queued, {} in total",
+                client.nick,
+                client.stats.queued,
+                self.queued_total.get()
+            );
+            self.disconnect(worst, DisconnectReason::MemoryPressure, registry);
+        }
+    }
+    // A nick `token` may take, or why it can't
+    fn check_nick(&self, nick: &[u8], token: Token) -> Result<String, &'static str> {
+        let Ok(nick) = core::str::from_utf8(nick) else {
+            return Err("invalid nick");
+        };
+        // Shown in front of every line, so it must not break one up
+        if nick.is_empty() || nick.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            Err("invalid nick, no spaces or control characters")
+        } else if nick.len() > MAX_NICK_LEN {
+            Err("nick too long, 64 bytes at most")
+        } else if self.nick_in_use(nick, token) {
+            Err("nick already in use")
+        } else if self.nick_reserved(nick) {
+            Err("nick reserved, try later")
+        } else {
+            Ok(nick.to_string())
+        }
+    }
+    fn nick_in_use(&self, nick: &str, except: Token) -> bool {
+        self.clients
+            .iter()
+            .any(|(t, c)| *t != except && c.nick == nick)
+    }
+    // Recently released nicks stay reserved for a grace period
+    fn nick_reserved(&self, nick: &str) -> bool {
+        self.reserved_nicks
+            .get(nick)
+            .is_some_and(|until| *until > Instant::now())
+    }
+    fn prune_reserved_nicks(&mut self) {
+        let now = Instant::now();
+        self.reserved_nicks.retain(|_, until| *until > now);
+    }
+    fn prune_invite_tokens(&mut self) {
+        let now = Instant::now();
+        self.invite_tokens.retain(|_, until| *until > now);
+    }
+    // Forgets the /send ids older than the window
+    fn prune_send_ids(&mut self) {
+        let window = self.config.send_id_window;
+        while self
+            .send_ids
+            .front()
+            .is_some_and(|s| s.at.elapsed() >= window)
+        {
+            self.send_ids.pop_front();
+        }
+    }
+    // Whether addresses are shown as they are, rather than hashed with
+    // `--anonymize-ips`, to an admin or not
+    fn shows_raw_ips(&self, admin: bool) -> bool {
+        self.ip_salt.is_none() || (admin && self.config.admins_see_ips)
+    }
+    // An address as shown: hashed with `--anonymize-ips`, the same one
+    // always giving the same hash while the server runs
+    fn show_ip(&self, ip: IpAddr, admin: bool) -> String {
+        match &self.ip_salt {
+            Some(salt) if !self.shows_raw_ips(admin) => {
+                let digest = hmac_sha256(salt.as_bytes(), ip.to_string().as_bytes());
+                format!("ip-{}", hex(&digest[..6]))
+            }
+            _ => ip.to_string(),
+        }
+    }
+    // An address and port as shown in the log, where admins aren't reading
+    fn show_addr(&self, addr: SocketAddr) -> String {
+        match self.shows_raw_ips(false) {
+            true => addr.to_string(),
+            false => format!("{}:{}", self.show_ip(addr.ip(), false), addr.port()),
+        }
+    }
+    // Channels only exist while they have members
+    fn prune_channel(&mut self, name: &str) {
+        if !self
+            .clients
+            .values()
+            .any(|c| c.channel.as_deref() == Some(name))
+        {
+            self.channels.remove(name);
+            self.polls.remove(&Some(name.to_string()));
+        }
+    }
+    // The single path for dropping a client: every disconnect goes through here
+    fn disconnect(&mut self, token: Token, reason: DisconnectReason, registry: &Registry) {
+        let Some(mut client) = self.clients.remove(&token) else {
+            return;
+        };
+        if let Some(notice) = reason
+            .peer_notice()
+            .filter(|_| client.framing != Framing::Http)
+        {
+            // Best effort, the socket is about to be closed
+            let data = match client.framing {
+                // No prompt, there won't be anything else
+                Framing::Line if client.crlf => to_crlf(&client.framer.encode(notice.as_bytes())),
+                Framing::Line => client.framer.encode(notice.as_bytes()),
+                _ => client.render(&Message::system(notice.as_bytes())),
+            };
+            client.write(data);
+            let _ = client.flush_outbox();
+        }
+        let _ = registry.deregister(&mut client.listener);
+        if let Some(count) = self.connections_per_ip.get_mut(&client.ip) {
+            *count -= 1;
+            if *count == 0 {
+                self.connections_per_ip.remove(&client.ip);
+            }
+        }
+        self.pending_input.remove(&token);
+        self.laggards.remove(&token);
+        let was_admin = self.admins.remove(&token);
+        for channel in self.channels.values_mut() {
+            channel.invited.remove(&token);
+        }
+        for c in self.clients.values_mut() {
+            c.unread_from.remove(&token);
+        }
+        // The token may be reused before these are delivered
+        for (_, deliveries) in &mut self.fanout {
+            deliveries.retain(|(t, _)| *t != token);
+        }
+        self.tokens.release(token);
+        let session = session_tag(self.config.session_ids, client.session_id);
+        info!("Disconnected {}{session} ({reason})", client.nick);
+        if self.founder == Some(token) {
+            self.founder = None;
+            self.promote_founder();
+        }
+        // Admin rights come from connecting first, so someone always has them
+        if was_admin && self.admins.is_empty() && self.config.founder_admin {
+            if let Some(oldest) = self.oldest_client() {
+                self.op(oldest);
+            }
+        }
+        // Never joined the chat, nobody to tell
+        if client.framing == Framing::Http {
+            return;
+        }
+        self.mark_seen(&client.nick);
+        if self.departures.len() == MAX_DEPARTURES {
+            self.departures.pop_front();
+        }
+        self.departures.push_back(Departure {
+            nick: client.nick.clone(),
+            ip: client.ip,
+            reason: reason.to_string(),
+            at: Instant::now(),
+        });
+        if let (true, Some(grace)) = (client.custom_nick, self.config.nick_grace) {
+            self.reserved_nicks
+                .insert(client.nick.clone(), Instant::now() + grace);
+        }
+        self.push_to_channel(
+            client.channel.as_deref(),
+            &Message::system(format!("* {} left ({reason})", client.nick).as_bytes()),
+            Some(token),
+        );
+        if let Some(channel) = &client.channel {
+            self.prune_channel(channel);
+        }
+    }
+    // Takes a client that just connected, unless its address is over
+    // `max_per_ip` or it can't be greeted. Returns the token it was given.
+    fn add_client(
+        &mut self,
+        mut conn: Stream,
+        addr: SocketAddr,
+        framing: Framing,
+        registry: &Registry,
+    ) -> Option<Token> {
+        // Counted only once it's taken, so a failure below leaves no entry
+        let count = self.connections_per_ip.get(&addr.ip()).copied();
+        if count.unwrap_or(0) >= self.config.max_per_ip {
+            // Best effort, the connection is dropped right away
+            let _ = conn.write_all(b"Too many connections from your address\n");
+            info!(
+                "Refused client from {}: too many connections",
+                self.show_addr(addr)
+            );
+            return None;
+        }
+        let mut next_client = self.tokens.allocate();
+        // Inserting over a live client would leak its socket; never expected,
+        // but cheap to rule out
+        while self.clients.contains_key(&next_client) {
+            // Not released: it's the live client's, which releases it when
+            // it disconnects
+            warn!("Token {} is still in use, skipping it", next_client.0);
+            next_client = self.tokens.allocate();
+        }
+        if let Err(e) = registry.register(&mut conn, next_client, Interest::READABLE) {
+            warn!("Can't register client from {}: {e}", self.show_addr(addr));
+            self.tokens.release(next_client);
+            return None;
+        }
+        let mut client = Client {
+            nick: self.default_nick(),
+            custom_nick: false,
+            channel: None,
+            status: Status::Online,
+            receipts: false,
+            unread_from: Default::default(),
+            status_text: None,
+            aliases: Default::default(),
+            listener: conn,
+            ip: addr.ip(),
+            read_buf: Box::new([0; 4096]),
+            read_buf_start: 0,
+            discard_line: false,
+            framing,
+            framer: framing.framer(),
+            negotiating: framing == Framing::Line,
+            #[cfg(feature = "compress")]
+            deflate: None,
+            #[cfg(feature = "compress")]
+            inflated: Vec::new(),
+            #[cfg(feature = "compress")]
+            inflate_rest: false,
+            raw: false,
+            crlf: self.config.crlf_out,
+            readline: false,
+            encoding: Encoding::Utf8,
+            debug: false,
+            prompt: DEFAULT_PROMPT.to_string(),
+            lines: 0,
+            connected_at: Instant::now(),
+            last_line: Instant::now(),
+            session_id: self.next_session_id,
+            last_message: None,
+            last_fortune: None,
+            last_report: None,
+            tarpit: None,
+            egress: self.config.egress_rate.map(TokenBucket::new),
+            messages: self.config.message_rate.map(TokenBucket::new),
+            oper_user: None,
+            request: None,
+            closing: false,
+            failed: None,
+            outbox: Default::default(),
+            stats: Default::default(),
+            queued_total: self.queued_total.clone(),
+            sent_total: self.heartbeat.bytes_sent(),
+            // A fresh connection has an empty send buffer, so the welcome
+            // goes out right away and a failure shows up here
+            writable: true,
+            write_interest: false,
+        };
+        self.next_session_id += 1;
+        if framing == Framing::Line {
+            let mut vars = self.template_vars(&client.nick);
+            // Not in `self.clients` yet
+            vars.clients += 1;
+            client.reply(&template::expand(&self.motd, &vars));
+        }
+        if let Some(kind) = client.failed {
+            info!(
+                "Dropped client from {}: welcome failed ({kind})",
+                self.show_addr(addr)
+            );
+            let _ = registry.deregister(&mut client.listener);
+            self.tokens.release(next_client);
+            return None;
+        }
+        let session = session_tag(self.config.session_ids, client.session_id);
+        let shown = self.show_addr(addr);
+        self.clients.insert(next_client, client);
+        *self.connections_per_ip.entry(addr.ip()).or_default() += 1;
+        self.connects += 1;
+        // Sampled, so churn doesn't flood the log
+        if self.connects.is_multiple_of(self.config.log_connects) {
+            info!("Connected client{session} from {shown}");
+        } else {
+            debug!("Connected client{session} from {shown}");
+        }
+        if framing == Framing::Line && self.founder.is_none() {
+            self.make_founder(next_client);
+        }
+        Some(next_client)
+    }
+    // An event can be both readable and writable; once one half
+    // disconnects the client, the rest of the event is ignored.
+    fn handle_client_event(&mut self, event: &Event, registry: &Registry) {
+        let (readable, writable) = (event.is_readable(), event.is_writable());
+        self.handle_client_ready(event.token(), readable, writable, registry);
+    }
+    fn handle_client_ready(
+        &mut self,
+        token: Token,
+        readable: bool,
+        writable: bool,
+        registry: &Registry,
+    ) {
+        // The client may have been removed by an earlier event of this batch
+        if !self.clients.contains_key(&token) {
+            return;
+        }
+        // mio may report a token more than once per batch. A client over its
+        // line budget resumes in `handle_pending_input` instead of getting a
+        // second share now, and one whose writes failed is about to be dropped.
+        let client = &self.clients[&token];
+        let skip_read = client.failed.is_some() || self.pending_input.contains(&token);
+        if readable && !skip_read {
+            self.handle_readable(token, registry);
+        }
+        if writable {
+            let Some(client) = self.clients.get_mut(&token) else {
+                return;
+            };
+            client.writable = true;
+            match client.try_flush() {
+                FlushStatus::Failed(e) => {
+                    self.disconnect(token, DisconnectReason::Error(e.kind()), registry);
+                }
+                FlushStatus::Drained if client.closing => {
+                    self.disconnect(token, DisconnectReason::Served, registry);
+                }
+                // The interest in WRITABLE stays until it drains
+                FlushStatus::Drained | FlushStatus::Pending => {}
+            }
+        }
+    }
+    // Reads everything available from the client and handles each complete line,
+    // up to `MAX_LINES_PER_EVENT`, or just one with `--fair`. The rest is
+    // deferred to `handle_pending_input`.
+    fn handle_readable(&mut self, token: Token, registry: &Registry) {
+        let mut budget = if self.config.fair {
+            1
+        } else {
+            MAX_LINES_PER_EVENT
+        };
+        loop {
+            let client = self.clients.get_mut(&token).unwrap();
+            let (full, finished) = match client.fill_read_buf() {
+                Ok(ReadStatus::Eof) => (false, Some(DisconnectReason::Eof)),
+                Ok(ReadStatus::Full) => (true, None),
+                Ok(ReadStatus::Drained) => (false, None),
+                Err(e) => (false, Some(DisconnectReason::Error(e.kind()))),
+            };
+            let mut start = 0;
+            while budget > 0 {
+                let Some(client) = self.clients.get_mut(&token) else {
+                    // Left while handling its own command
+                    return;
+                };
+                let (range, next) = match client.next_frame(start) {
+                    Frame::Complete(range, next) => (range, next),
+                    Frame::Ping(range, next) => {
+                        let pong = websocket::encode(websocket::OP_PONG, &client.read_buf[range]);
+                        client.write(pong);
+                        start = next;
+                        continue;
+                    }
+                    Frame::Ignored(next) => {
+                        start = next;
+                        continue;
+                    }
+                    Frame::Close => {
+                        self.disconnect(token, DisconnectReason::Eof, registry);
+                        return;
+                    }
+                    Frame::Partial => break,
+                    Frame::TooLarge => {
+                        self.disconnect(token, DisconnectReason::FrameTooLarge, registry);
+                        return;
+                    }
+                };
+
+                let discard = std::mem::take(&mut client.discard_line);
+                // Other framings are binary, or UTF-8 by definition
+                let mut line = match client.framing {
+                    Framing::Line => client.encoding.decode(&client.read_buf[range]),
+                    _ => client.read_buf[range].to_vec(),
+                };
+                start = next;
+                if client.framing == Framing::Line && line.ends_with(b"\r") {
+                    line.pop();
+                    // Clients ending their first line in CRLF, like telnet,
+                    // get their lines ended the same way
+                    if client.lines == 0 {
+                        client.crlf = true;
+                    }
+                }
+                if !discard {
+                    client.lines += 1;
+                    client.last_line = Instant::now();
+                    self.handle_line(token, &line, registry);
+                    budget -= 1;
+                    #[cfg(feature = "compress")]
+                    if let Some(client) = self.clients.get_mut(&token) {
+                        if std::mem::take(&mut client.inflate_rest) {
+                            if let Err(e) = client.inflate_from(start) {
+                                self.disconnect(token, DisconnectReason::Error(e.kind()), registry);
+                                return;
+                            }
+                            // Taken up again once it's in the read buffer
+                            self.pending_input.insert(token);
+                        }
+                    }
+                }
+            }
+            let Some(client) = self.clients.get_mut(&token) else {
+                return;
+            };
+            client.compact_read_buf(start);
+            if budget == 0 {
+                // Whatever is left, including an EOF, is still there next time
+                self.pending_input.insert(token);
+                return;
+            }
+            if let Some(reason) = finished {
+                self.disconnect(token, reason, registry);
+                return;
+            }
+            if !full {
+                return;
+            }
+        }
+    }
+    // Resumes clients that hit the line budget in a previous pass. With
+    // `--fair` it goes round them a line at a time, so a chatty client's
+    // backlog is interleaved with everyone else's.
+    fn handle_pending_input(&mut self, registry: &Registry) {
+        let rounds = if self.config.fair {
+            MAX_LINES_PER_EVENT
+        } else {
+            1
+        };
+        for _ in 0..rounds {
+            if self.pending_input.is_empty() {
+                return;
+            }
+            for token in std::mem::take(&mut self.pending_input) {
+                if self.clients.contains_key(&token) {
+                    self.handle_readable(token, registry);
+                }
+            }
+        }
+    }
+    // Answers `CAP <name>`, sent before anything else, with `ACK <name>` and
+    // switches the feature on, or `NAK <name>` if it's unknown or unavailable
+    fn negotiate(&mut self, token: Token, cap: &[u8]) {
+        let client = self.clients.get_mut(&token).unwrap();
+        let cap = String::from_utf8_lossy(cap).into_owned();
+        let ack = match cap.as_str() {
+            "binary" | "json" => client.framing == Framing::Line,
+            name => FEATURES
+                .iter()
+                .any(|f| f.name == name && f.built && f.negotiated && !client.has_feature(name)),
+        };
+        let answer = format!("{} {cap}", if ack { "ACK" } else { "NAK" });
+        let mut data = client.framer.encode(answer.as_bytes());
+        if client.crlf && client.framing == Framing::Line {
+            data = to_crlf(&data);
+        }
+        // In the old framing, and uncompressed
+        client.write(data);
+        if !ack {
+            return;
+        }
+        match cap.as_str() {
+            "binary" => client.set_framing(Framing::LengthPrefixed),
+            "json" => client.set_framing(Framing::Json),
+            #[cfg(feature = "compress")]
+            "compress" => {
+                client.deflate = Some(Deflate::new());
+                client.inflate_rest = true;
+            }
+            _ => {}
+        }
+    }
+    fn handle_line(&mut self, token: Token, line: &[u8], registry: &Registry) {
+        let client = self.clients.get_mut(&token).unwrap();
+        if client.negotiating {
+            if let Some(cap) = line.strip_prefix(b"CAP ") {
+                return self.negotiate(token, cap);
+            }
+            client.negotiating = false;
+        }
+        match client.framing {
+            Framing::Http => return self.handle_http(token, line),
+            Framing::Json => return self.handle_request(token, line, registry),
+            _ => {}
+        }
+        if self
+            .config
+            .honeypot
+            .as_deref()
+            .is_some_and(|h| h.as_bytes() == line)
+        {
+            // No reply, so nothing gives the trap away
+            if client.tarpit.is_none() {
+                info!("Tarpitting {} (honeypot)", client.nick);
+                client.tarpit = Some(TARPIT_DELAY);
+            }
+            return;
+        }
+        // `//` escapes a message starting with a slash
+        if let Some(text) = line.strip_prefix(b"/").filter(|t| t.starts_with(b"/")) {
+            self.run_command(token, Command::Message(text), registry);
+            return;
+        }
+        let mut command = Command::parse(line);
+        let expanded;
+        if let Command::Message(msg) = command {
+            // Builtins win over aliases, and an expansion isn't expanded again
+            if let Some(text) = client.expand_alias(msg) {
+                expanded = text;
+                command = Command::parse(&expanded);
+            }
+        }
+        if let Command::Message(msg) = command {
+            if self.config.strict_commands && msg.starts_with(b"/") {
+                let (name, _) = Command::split(msg);
+                let name = String::from_utf8_lossy(name);
+                client.error(&format!(
+                    "unknown command {name}, use //{} to say it",
+                    &name[1..]
+                ));
+                return;
+            }
+        }
+        self.run_command(token, command, registry);
+    }
+    // Answers a JSON mode request. The first reply to the command is the
+    // response; a command that doesn't reply gets a null result.
+    fn handle_request(&mut self, token: Token, line: &[u8], registry: &Registry) {
+        let client = self.clients.get_mut(&token).unwrap();
+        let request = match core::str::from_utf8(line) {
+            Ok(text) => json::parse(text),
+            Err(_) => Err("invalid UTF-8".to_string()),
+        };
+        let request = match request {
+            Ok(request @ json::Value::Object(_)) => request,
+            Ok(_) => return client.write_error(Value::Null, -32600, "invalid request"),
+            Err(e) => return client.write_error(Value::Null, -32700, &e),
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let Some(method) = request.get("method").and_then(Value::as_str) else {
+            return client.write_error(id, -32600, "invalid request");
+        };
+        let params = request.get("params").unwrap_or(&Value::Null);
+        let command = match Command::from_request(method, params) {
+            Ok(command) => command,
+            Err(RequestError::MethodNotFound) => {
+                return client.write_error(id, -32601, &format!("no method {method}"));
+            }
+            Err(RequestError::InvalidParams) => {
+                return client.write_error(id, -32602, "invalid params");
+            }
+        };
+        client.request = Some(id);
+        self.run_command(token, command, registry);
+        // Gone if the command was a quit
+        if let Some(client) = self.clients.get_mut(&token) {
+            if let Some(id) = client.request.take() {
+                client.write_response(id, "result", Value::Null);
+            }
+        }
+    }
+    fn run_command(&mut self, token: Token, command: Command, registry: &Registry) {
+        let client = self.clients.get_mut(&token).unwrap();
+        if client.debug {
+            // Only ever to the client itself, and not as the reply to a request
+            let msg = format!("parsed: {command:?}");
+            client.write(client.render(&Message::system(msg.as_bytes())));
+        }
+        match command {
+            Command::Alias(name, text) => {
+                let (Ok(name), Ok(text)) = (core::str::from_utf8(name), core::str::from_utf8(text))
+                else {
+                    client.error("invalid alias");
+                    return;
+                };
+                let name = name.trim_start_matches('/');
+                if name.is_empty() || name.len() > MAX_ALIAS_NAME_LEN || text.is_empty() {
+                    client.error("usage: /alias <name> <text>");
+                } else if text.len() > MAX_ALIAS_LEN {
+                    client.error(&format!("alias text is limited to {MAX_ALIAS_LEN} bytes"));
+                } else if !client.aliases.contains_key(name) && client.aliases.len() >= MAX_ALIASES
+                {
+                    client.error(&format!("too many aliases, the limit is {MAX_ALIASES}"));
+                } else {
+                    client.aliases.insert(name.to_string(), text.to_string());
+                    client.reply(&format!("/{name} is now an alias"));
+                }
+            }
+            Command::Unalias(name) => {
+                let name = String::from_utf8_lossy(name);
+                let name = name.trim_start_matches('/');
+                if client.aliases.remove(name).is_some() {
+                    client.reply(&format!("/{name} removed"));
+                } else {
+                    client.error(&format!("no alias /{name}"));
+                }
+            }
+            Command::Motd => {
+                let motd = self.expand(&self.motd, token);
+                self.clients.get_mut(&token).unwrap().reply(&motd);
+            }
+            Command::Nick(nick) => {
+                let checked = self.check_nick(nick, token);
+                let client = self.clients.get_mut(&token).unwrap();
+                match checked {
+                    Ok(nick) => {
+                        client.nick = nick;
+                        client.custom_nick = true;
+                        client.reply(&format!("nick changed to {}", client.nick));
+                    }
+                    Err(e) => client.error(e),
+                }
+            }
+            Command::ForceNick(old, new) => {
+                if !self.admins.contains(&token) {
+                    client.error("permission denied");
+                    return;
+                }
+                if new.is_empty() {
+                    client.error("usage: /forcenick <oldnick> <newnick>");
+                    return;
+                }
+                let old = String::from_utf8_lossy(old);
+                let Some((&target, _)) = self.clients.iter().find(|(_, c)| c.nick == old) else {
+                    let client = self.clients.get_mut(&token).unwrap();
+                    client.error(&format!("no such nick {old}"));
+                    return;
+                };
+                let new = match self.check_nick(new, target) {
+                    Ok(new) => new,
+                    Err(e) => {
+                        self.clients.get_mut(&token).unwrap().error(e);
+                        return;
+                    }
+                };
+                let client = self.clients.get_mut(&target).unwrap();
+                client.nick = new.clone();
+                client.custom_nick = true;
+                client.reply(&format!("your nick was changed by an admin to {new}"));
+                let channel = client.channel.clone();
+                let notice = format!("* {old} is now known as {new}");
+                self.push_to_channel(
+                    channel.as_deref(),
+                    &Message::system(notice.as_bytes()),
+                    Some(target),
+                );
+                info!("Renamed {old} to {new} (forced)");
+                let client = self.clients.get_mut(&token).unwrap();
+                client.reply(&format!("renamed {old} to {new}"));
+            }
+            Command::Quit => {
+                self.disconnect(token, DisconnectReason::Quit, registry);
+            }
+            Command::Stats => {
+                let mut total = OutboxStats::default();
+                for c in self.clients.values() {
+                    total.queued += c.stats.queued;
+                    total.peak = total.peak.max(c.stats.peak);
+                    total.flushes += c.stats.flushes;
+                    total.partial_writes += c.stats.partial_writes;
+                }
+                let text = format!("{} clients, {total}", self.clients.len());
+                self.clients.get_mut(&token).unwrap().reply(&text);
+            }
+            Command::Whois(nick) => {
+                let nick = String::from_utf8_lossy(nick);
+                let found = self.clients.values().find(|c| c.nick == nick).map(|c| {
+                    format!(
+                        "{nick}{} ({}) in {}: {}",
+                        session_tag(self.config.session_ids, c.session_id),
+                        c.presence(),
+                        c.channel.as_deref().unwrap_or("the global room"),
+                        c.stats
+                    )
+                });
+                let client = self.clients.get_mut(&token).unwrap();
+                match found {
+                    Some(text) => client.reply(&text),
+                    None => client.error(&format!("no such nick {nick}")),
+                }
+            }
+            Command::Commands => {
+                let admin = self.admins.contains(&token);
+                let named = client.custom_nick;
+                let lines: Vec<String> = COMMANDS
+                    .iter()
+                    .map(|spec| {
+                        let mut line = spec.name.to_string();
+                        match spec.access {
+                            Access::Everyone => {}
+                            // Only asked for with --require-nick
+                            Access::Named if self.config.require_nick && !named => {
+                                line.push_str(" (after /nick)");
+                            }
+                            Access::Named => {}
+                            Access::Admins if admin => line.push_str(" (admin)"),
+                            Access::Admins => line.push_str(" (admins only)"),
+                        }
+                        if let Some(why) = self.disabled_command(spec.name) {
+                            line.push_str(&format!(" (disabled, {why})"));
+                        }
+                        line
+                    })
+                    .collect();
+                let client = self.clients.get_mut(&token).unwrap();
+                client.reply(&lines.join("\n"));
+            }
+            Command::Roster => {
+                if !self.admins.contains(&token) {
+                    client.error("permission denied");
+                    return;
+                }
+                let roster = Value::Array(
+                    self.clients
+                        .values()
+                        .filter(|c| c.framing != Framing::Http)
+                        .map(|c| {
+                            let addr = match c.listener.peer_addr() {
+                                Ok(addr) if self.shows_raw_ips(true) => addr.to_string(),
+                                Ok(addr) => self.show_addr(addr),
+                                Err(_) => self.show_ip(c.ip, true),
+                            };
+                            let channel = match &c.channel {
+                                Some(name) => Value::String(name.clone()),
+                                None => Value::Null,
+                            };
+                            Value::Object(vec![
+                                ("nick".into(), Value::String(c.nick.clone())),
+                                ("addr".into(), Value::String(addr)),
+                                ("channel".into(), channel),
+                                (
+                                    "idle".into(),
+                                    Value::Number(c.last_line.elapsed().as_secs() as f64),
+                                ),
+                                ("status".into(), Value::String(c.presence())),
+                            ])
+                        })
+                        .collect(),
+                );
+                let client = self.clients.get_mut(&token).unwrap();
+                // JSON either way, on one line in plain text mode
+                match client.request.take() {
+                    Some(request) => client.write_response(request, "result", roster),
+                    None => client.reply(&roster.to_string()),
+                }
+            }
+            Command::Maintenance(on) => {
+                if !self.admins.contains(&token) {
+                    client.error("permission denied");
+                    return;
+                }
+                if self.maintenance == on {
+                    client.reply(if on {
+                        "already in maintenance"
+                    } else {
+                        "not in maintenance"
+                    });
+                    return;
+                }
+                self.maintenance = on;
+                info!(
+                    "Maintenance {} by {}",
+                    if on { "on" } else { "off" },
+                    client.nick
+                );
+                let notice: &[u8] = if on {
+                    b"* server in maintenance, messages are paused"
+                } else {
+                    b"* maintenance over, messages are back"
+                };
+                self.push(&Message::system(notice).urgent(), |_, _| true);
+            }
+            Command::Drain => {
+                if !self.admins.contains(&token) {
+                    client.error("permission denied");
+                    return;
+                }
+                if self.draining.is_some() {
+                    client.reply("already draining");
+                    return;
+                }
+                info!("Drain started by {}", client.nick);
+                self.start_drain();
+            }
+            Command::Get(key) => {
+                if !self.admins.contains(&token) {
+                    client.error("permission denied");
+                    return;
+                }
+                let key = String::from_utf8_lossy(key);
+                let keys: Vec<&str> = match key.as_ref() {
+                    "" => Config::TUNABLES.to_vec(),
+                    key => vec![key],
+                };
+                let mut lines = Vec::new();
+                for key in keys {
+                    match self.config.get(key) {
+                        Some(value) => lines.push(format!("{key} = {value}")),
+                        None => {
+                            client.error(&format!("no setting {key}"));
+                            return;
+                        }
+                    }
+                }
+                client.reply(&lines.join("\n"));
+            }
+            Command::Set(key, value) => {
+                if !self.admins.contains(&token) {
+                    client.error("permission denied");
+                    return;
+                }
+                let key = String::from_utf8_lossy(key);
+                let value = String::from_utf8_lossy(value);
+                match self.config.set(&key, &value) {
+                    Ok(()) => {
+                        info!("{} set {key} to {value}", client.nick);
+                        client.reply(&format!("{key} = {value}"));
+                    }
+                    Err(e) => client.error(&e),
+                }
+            }
+            Command::Msg(nick, text) => {
+                if text.is_empty() {
+                    client.error("usage: /msg <nick> <text>");
+                    return;
+                }
+                let nick = String::from_utf8_lossy(nick);
+                let Some((&target, _)) = self.clients.iter().find(|(_, c)| c.nick == nick) else {
+                    let client = self.clients.get_mut(&token).unwrap();
+                    client.error(&format!("no such nick {nick}"));
+                    return;
+                };
+                if let Some(e) = self.talk_refusal(token) {
+                    self.clients.get_mut(&token).unwrap().error(e);
+                    return;
+                }
+                // The same text to someone else isn't a duplicate
+                let hash = hash_bytes(&[nick.as_bytes(), b" ", text].concat());
+                if !self.admit_message(token, hash) {
+                    return;
+                }
+                let client = self.clients.get_mut(&token).unwrap();
+                let sender = client.nick.clone();
+                let wants_receipt = client.receipts;
+                let text = match self.config.max_message_len {
+                    Some(max) => truncate_utf8(text, max),
+                    None => text,
+                };
+                let msg = Message::private(&sender, text);
+                let recipient = self.clients.get_mut(&target).unwrap();
+                recipient.write(recipient.render(&msg));
+                self.heartbeat.message();
+                // Only when both sides opted in
+                if wants_receipt && recipient.receipts && target != token {
+                    recipient.unread_from.insert(token);
+                }
+                self.mark_seen(&sender);
+                self.send_receipts(token);
+            }
+            Command::Read => {
+                if client.unread_from.is_empty() {
+                    client.reply("nothing unread");
+                    return;
+                }
+                self.send_receipts(token);
+                self.clients
+                    .get_mut(&token)
+                    .unwrap()
+                    .reply("marked as read");
+            }
+            Command::Receipts(on) => {
+                client.receipts = on;
+                if !on {
+                    client.unread_from.clear();
+                }
+                client.reply(if on {
+                    "read receipts on"
+                } else {
+                    "read receipts off"
+                });
+            }
+            Command::Buffers => {
+                if !self.admins.contains(&token) {
+                    client.error("permission denied");
+                    return;
+                }
+                let lines: Vec<String> = self
+                    .clients
+                    .values()
+                    .map(|c| {
+                        format!(
+                            "{}: read buffer {}/{BUFLEN}{}, outbox {} items, {} bytes queued",
+                            c.nick,
+                            c.read_buf_start,
+                            if c.discard_line {
+                                " (discarding a long line)"
+                            } else {
+                                ""
+                            },
+                            c.outbox.len(),
+                            c.stats.queued
+                        )
+                    })
+                    .collect();
+                self.clients
+                    .get_mut(&token)
+                    .unwrap()
+                    .reply(&lines.join("\n"));
+            }
+            Command::Lag => {
+                let mut text = format!(
+                    "{} bytes queued for you in {} items",
+                    client.stats.queued,
+                    client.outbox.len()
+                );
+                if let Some(max) = self.config.max_retained {
+                    let retained = client.retained();
+                    // Past the cap the client is disconnected
+                    let near = retained * 10 >= max * 8;
+                    text.push_str(&format!(
+                        ", {retained} of at most {max} bytes held{}",
+                        if near {
+                            ", close to being disconnected"
+                        } else {
+                            ""
+                        }
+                    ));
+                }
+                client.reply(&text);
+            }
+            Command::Recent => {
+                if !self.admins.contains(&token) {
+                    client.error("permission denied");
+                    return;
+                }
+                let now = Instant::now();
+                self.departures.retain(|d| now - d.at < DEPARTURE_TTL);
+                if self.departures.is_empty() {
+                    client.reply("nobody left recently");
+                    return;
+                }
+                let lines: Vec<String> = self
+                    .departures
+                    .iter()
+                    .rev()
+                    .map(|d| {
+                        let ago = format_ago(now - d.at);
+                        let ip = self.show_ip(d.ip, false);
+                        format!("{} from {ip} {ago} ({})", d.nick, d.reason)
+                    })
+                    .collect();
+                let client = self.clients.get_mut(&token).unwrap();
+                client.reply(&lines.join("\n"));
+            }
+            Command::Complete(prefix) => {
+                let prefix = String::from_utf8_lossy(prefix).to_lowercase();
+                let mut matches: Vec<&str> = self
+                    .clients
+                    .values()
+                    .filter(|c| c.framing != Framing::Http)
+                    .map(|c| c.nick.as_str())
+                    .filter(|nick| nick.to_lowercase().starts_with(&prefix))
+                    .collect();
+                matches.sort_unstable_by_key(|nick| nick.to_lowercase());
+                matches.truncate(MAX_COMPLETIONS);
+                let text = if matches.is_empty() {
+                    "no matches".to_string()
+                } else {
+                    matches.join(" ")
+                };
+                self.clients.get_mut(&token).unwrap().reply(&text);
+            }
+            Command::Fortune => {
+                if self.fortunes.is_empty() {
+                    client.error("fortunes are disabled");
+                    return;
+                }
+                if let Some(e) = self.chat_refusal(token) {
+                    self.clients.get_mut(&token).unwrap().error(e);
+                    return;
+                }
+                let client = self.clients.get_mut(&token).unwrap();
+                let now = Instant::now();
+                if client
+                    .last_fortune
+                    .is_some_and(|at| now - at < FORTUNE_COOLDOWN)
+                {
+                    client.error("wait a little before the next fortune");
+                    return;
+                }
+                client.last_fortune = Some(now);
+                let fortune = &self.fortunes[nickgen::random() as usize % self.fortunes.len()];
+                let notice = format!("* {} draws a fortune: {fortune}", client.nick);
+                let nick = client.nick.clone();
+                let channel = client.channel.clone();
+                self.mark_seen(&nick);
+                // Everyone sees it, the one who asked included
+                self.push_to_channel(
+                    channel.as_deref(),
+                    &Message::system(notice.as_bytes()),
+                    None,
+                );
+            }
+            Command::Report(nick, reason) => {
+                let reason: String = String::from_utf8_lossy(reason)
+                    .chars()
+                    .filter(|c| !c.is_control())
+                    .collect();
+                let reason = reason.trim();
+                if nick.is_empty() || reason.is_empty() {
+                    client.error("usage: /report <nick> <reason>");
+                    return;
+                }
+                let now = Instant::now();
+                if client
+                    .last_report
+                    .is_some_and(|at| now - at < REPORT_COOLDOWN)
+                {
+                    client.error("wait a little before the next report");
+                    return;
+                }
+                client.last_report = Some(now);
+                let nick = String::from_utf8_lossy(truncate_utf8(nick, MAX_RENDERED_NICK));
+                let room = client.channel.as_deref().unwrap_or("the global room");
+                let mut report = format!(
+                    "* {} reported {nick} in {room}: {}",
+                    client.nick,
+                    String::from_utf8_lossy(truncate_utf8(reason.as_bytes(), MAX_TOPIC_LEN))
+                );
+                let mut context: Vec<&str> = self
+                    .recent_messages
+                    .iter()
+                    .rev()
+                    .filter(|m| m.channel == client.channel)
+                    .take(REPORT_CONTEXT)
+                    .map(|m| m.line.as_str())
+                    .collect();
+                context.reverse();
+                for line in context {
+                    report.push_str("\n  ");
+                    report.push_str(line);
+                }
+                info!("{report}");
+                let admins: BTreeSet<Token> = self.admins.clone();
+                let notice = Message::system(report.as_bytes()).urgent();
+                self.push(&notice, |k, _| admins.contains(k));
+                self.clients
+                    .get_mut(&token)
+                    .unwrap()
+                    .reply(if admins.is_empty() {
+                        "report logged"
+                    } else {
+                        "report sent to the admins"
+                    });
+            }
+            Command::Op(nick) | Command::Deop(nick) => {
+                if !self.admins.contains(&token) {
+                    client.error("permission denied");
+                    return;
+                }
+                let nick = String::from_utf8_lossy(nick);
+                let Some((&target, _)) = self.clients.iter().find(|(_, c)| c.nick == nick) else {
+                    let client = self.clients.get_mut(&token).unwrap();
+                    client.error(&format!("no such nick {nick}"));
+                    return;
+                };
+                let client = self.clients.get_mut(&token).unwrap();
+                let admin = self.admins.contains(&target);
+                // Either way the notice to everyone is the reply
+                if let Command::Op(_) = command {
+                    if admin {
+                        client.error(&format!("{nick} is already an operator"));
+                        return;
+                    }
+                    self.op(target);
+                    return;
+                }
+                if !admin {
+                    client.error(&format!("{nick} is not an operator"));
+                    return;
+                }
+                // Nobody could /oper back in
+                if self.admins.len() == 1 && self.config.founder_admin {
+                    client.error("can't remove the last operator");
+                    return;
+                }
+                self.admins.remove(&target);
+                let notice = format!("* {nick} is no longer an operator");
+                info!("{}", &notice[2..]);
+                self.push(&Message::system(notice.as_bytes()), |_, _| true);
+            }
+            Command::Search(term) => self.search(token, term),
+            Command::HistoryLimit(arg) => self.history_limit(token, arg),
+            Command::Seen(nick) => {
+                let nick = String::from_utf8_lossy(nick);
+                let text = if self.clients.values().any(|c| c.nick == nick) {
+                    format!("{nick} is online")
+                } else if let Some(at) = self.last_seen.get(nick.as_ref()) {
+                    format!("{nick} was last seen {}", format_ago(at.elapsed()))
+                } else {
+                    format!("never seen {nick}")
+                };
+                self.clients.get_mut(&token).unwrap().reply(&text);
+            }
+            Command::Status(name, text) => {
+                let Some(status) = Status::parse(name) else {
+                    client.error("usage: /status <online|busy|away> [text]");
+                    return;
+                };
+                // Shown inline, so it must stay on one line
+                let text: String = String::from_utf8_lossy(text)
+                    .chars()
+                    .filter(|c| !c.is_control())
+                    .collect();
+                let text = String::from_utf8_lossy(truncate_utf8(text.as_bytes(), MAX_STATUS_LEN));
+                client.status = status;
+                client.status_text = (!text.is_empty()).then(|| text.into_owned());
+                let notice = format!("* {} is now {}", client.nick, client.presence());
+                client.reply(&format!("you are now {}", client.presence()));
+                let channel = client.channel.clone();
+                self.push_to_channel(
+                    channel.as_deref(),
+                    &Message::system(notice.as_bytes()),
+                    Some(token),
+                );
+            }
+            Command::Binary => {
+                // Only as the very first line, so both sides agree on where it starts
+                if client.lines > 1 || client.framing != Framing::Line {
+                    client.error("binary framing must be requested first thing");
+                } else {
+                    client.reply("binary framing on");
+                    client.set_framing(Framing::LengthPrefixed);
+                }
+            }
+            Command::Json => {
+                if client.framing != Framing::Line {
+                    client.error("json mode needs line framing");
+                } else {
+                    client.set_framing(Framing::Json);
+                    client.reply("json mode on");
+                }
+            }
+            Command::Crlf(on) => {
+                client.crlf = on;
+                client.reply(if on {
+                    "CRLF line endings on"
+                } else {
+                    "CRLF line endings off"
+                });
+            }
+            Command::Readline(on) => {
+                client.readline = on;
+                client.reply(if on {
+                    "readline mode on"
+                } else {
+                    "readline mode off"
+                });
+            }
+            Command::Encoding(name) => {
+                let Some(encoding) = core::str::from_utf8(name).ok().and_then(Encoding::parse)
+                else {
+                    client.error("usage: /encoding <utf-8|latin1|cp1252>");
+                    return;
+                };
+                client.encoding = encoding;
+                client.reply(&format!("input encoding set to {}", encoding.name()));
+            }
+            Command::Debug(on) => {
+                client.debug = on;
+                client.reply(if on { "debug on" } else { "debug off" });
+            }
+            Command::Raw(on) => {
+                client.raw = on;
+                client.reply(if on { "raw mode on" } else { "raw mode off" });
+            }
+            Command::SetPrompt(prompt) => match core::str::from_utf8(prompt) {
+                Ok("") => {
+                    client.prompt = DEFAULT_PROMPT.to_string();
+                    client.reply("prompt reset");
+                }
+                Ok(prompt)
+                    if prompt.len() <= MAX_PROMPT_LEN && !prompt.chars().any(char::is_control) =>
+                {
+                    client.prompt = prompt.to_string();
+                    client.reply("prompt changed");
+                }
+                _ => {
+                    client.error(&format!(
+                        "a prompt is up to {MAX_PROMPT_LEN} bytes without control characters"
+                    ));
+                }
+            },
+            Command::Time => {
+                // There is no display timezone, times are always UTC
+                client.reply(&format!("{} (UTC)", format_utc(unix_time())));
+            }
+            Command::Version => {
+                client.reply(&version());
+            }
+            Command::Join(name, key) => {
+                let Some(name) = core::str::from_utf8(name).ok().filter(|n| valid_channel(n))
+                else {
+                    client.error("invalid channel name, use #name");
+                    return;
+                };
+                if client.channel.as_deref() == Some(name) {
+                    client.error(&format!("already in {name}"));
+                    return;
+                }
+                // Emptied channels are gone already, so only live ones count
+                if !self.channels.contains_key(name)
+                    && self.channels.len() >= self.config.max_channels
+                {
+                    client.error("channel limit reached");
+                    return;
+                }
+                let key = key.map(String::from_utf8_lossy);
+                let admin = self.admins.contains(&token);
+                let channel = self.channels.entry(name.to_string()).or_default();
+                if let Err(e) = channel.check_join(token, key.as_deref(), admin) {
+                    self.prune_channel(name);
+                    let client = self.clients.get_mut(&token).unwrap();
+                    client.error(&format!("{name} {e}"));
+                    return;
+                }
+                let topic = self.channels[name]
+                    .topic
+                    .as_ref()
+                    .map(|t| self.expand(&t.text, token));
+                let client = self.clients.get_mut(&token).unwrap();
+                let name = name.to_string();
+                let old = client.channel.replace(name.clone());
+                client.reply(&format!("joined {name}"));
+                if let Some(topic) = topic {
+                    client.reply(&format!("topic: {topic}"));
+                }
+                if let Some(pinned) = &self.channels[&name].pinned {
+                    client.reply(&format!("pinned: {pinned}"));
+                }
+                let notice = format!("* {} joined {name}", client.nick);
+                if let Some(old) = old {
+                    let notice = format!("* {} left {old}", client.nick);
+                    self.push_to_channel(
+                        Some(&old),
+                        &Message::system(notice.as_bytes()),
+                        Some(token),
+                    );
+                    self.prune_channel(&old);
+                }
+                self.push_to_channel(
+                    Some(&name),
+                    &Message::system(notice.as_bytes()),
+                    Some(token),
+                );
+            }
+            Command::Topic(arg) => {
+                let Some(name) = client.channel.clone() else {
+                    client.error("topics are per channel, /join one first");
+                    return;
+                };
+                let nick = client.nick.clone();
+                let admin = self.admins.contains(&token);
+                // Changes are announced as written, variables and all
+                let shown = self.channels[&name]
+                    .topic
+                    .as_ref()
+                    .map(|t| self.expand(&t.text, token));
+                let channel = self.channels.get_mut(&name).unwrap();
+                let client = self.clients.get_mut(&token).unwrap();
+                let notice = match arg {
+                    b"" => {
+                        match shown {
+                            Some(topic) => client.reply(&format!("topic: {topic}")),
+                            None => client.reply(&format!("no topic in {name}")),
+                        }
+                        return;
+                    }
+                    b"history" => {
+                        let now = Instant::now();
+                        let lines: Vec<String> = channel
+                            .topic_history
+                            .iter()
+                            .chain(&channel.topic)
+                            .rev()
+                            .map(|t| {
+                                format!(
+                                    "{} (set by {} {})",
+                                    t.text,
+                                    t.set_by,
+                                    format_ago(now - t.at)
+                                )
+                            })
+                            .collect();
+                        if lines.is_empty() {
+                            client.reply(&format!("no topic in {name}"));
+                        } else {
+                            client.reply(&lines.join("\n"));
+                        }
+                        return;
+                    }
+                    b"undo" => {
+                        if !admin {
+                            client.error("permission denied");
+                            return;
+                        }
+                        let Some(topic) = channel.undo_topic() else {
+                            client.error("no earlier topic");
+                            return;
+                        };
+                        format!("* {nick} restored the topic: {}", topic.text)
+                    }
+                    text => {
+                        // Shown inline, so it must stay on one line
+                        let text: String = String::from_utf8_lossy(text)
+                            .chars()
+                            .filter(|c| !c.is_control())
+                            .collect();
+                        let text =
+                            String::from_utf8_lossy(truncate_utf8(text.as_bytes(), MAX_TOPIC_LEN));
+                        let notice = format!("* {nick} set the topic: {text}");
+                        channel.set_topic(text.into_owned(), nick);
+                        notice
+                    }
+                };
+                info!("{name}: {notice}");
+                client.reply(&notice);
+                self.push_to_channel(
+                    Some(&name),
+                    &Message::system(notice.as_bytes()),
+                    Some(token),
+                );
+            }
+            Command::Pin(text) => {
+                let Some(name) = client.channel.clone() else {
+                    client.error("pins are per channel, /join one first");
+                    return;
+                };
+                let channel = self.channels.get_mut(&name).unwrap();
+                let notice = match text {
+                    None => {
+                        match &channel.pinned {
+                            Some(pinned) => client.reply(&format!("pinned: {pinned}")),
+                            None => client.reply(&format!("nothing pinned in {name}")),
+                        }
+                        return;
+                    }
+                    Some(_) if !self.admins.contains(&token) => {
+                        client.error("permission denied");
+                        return;
+                    }
+                    Some(b"") => {
+                        if channel.pinned.take().is_none() {
+                            client.error(&format!("nothing pinned in {name}"));
+                            return;
+                        }
+                        format!("* {} unpinned the message", client.nick)
+                    }
+                    Some(text) => {
+                        let text = String::from_utf8_lossy(truncate_utf8(text, MAX_TOPIC_LEN));
+                        let notice = format!("* {} pinned: {text}", client.nick);
+                        channel.pinned = Some(text.into_owned());
+                        notice
+                    }
+                };
+                client.reply(&notice);
+                self.push_to_channel(
+                    Some(&name),
+                    &Message::system(notice.as_bytes()),
+                    Some(token),
+                );
+            }
+            Command::Poll(arg) => {
+                let room = client.channel.clone();
+                let place = room.as_deref().unwrap_or("the global room");
+                let notice = match arg {
+                    b"" => {
+                        match self.polls.get(&room) {
+                            Some(poll) => client.reply(&format!("poll: {}", poll.describe())),
+                            None => client.reply(&format!("no poll in {place}")),
+                        }
+                        return;
+                    }
+                    b"close" => {
+                        let Some(poll) = self.polls.get(&room) else {
+                            client.error(&format!("no poll in {place}"));
+                            return;
+                        };
+                        if poll.creator != client.session_id && !self.admins.contains(&token) {
+                            client.error("permission denied");
+                            return;
+                        }
+                        let poll = self.polls.remove(&room).unwrap();
+                        format!("* {} closed the poll: {}", client.nick, poll.results())
+                    }
+                    args => {
+                        if self.polls.contains_key(&room) {
+                            client.error(&format!(
+                                "there's already a poll in {place}, /poll close it first"
+                            ));
+                            return;
+                        }
+                        if let Some(e) = self.chat_refusal(token) {
+                            self.clients.get_mut(&token).unwrap().error(e);
+                            return;
+                        }
+                        let client = self.clients.get_mut(&token).unwrap();
+                        let poll = match Ballot::parse(
+                            &String::from_utf8_lossy(args),
+                            client.session_id,
+                        ) {
+                            Ok(poll) => poll,
+                            Err(e) => {
+                                client.error(&format!(
+                                    "{e}, usage: /poll \"Question?\" <option> <option>..."
+                                ));
+                                return;
+                            }
+                        };
+                        let notice = format!(
+                            "* {} started a poll: {}, /vote <n>",
+                            client.nick,
+                            poll.describe()
+                        );
+                        self.polls.insert(room.clone(), poll);
+                        notice
+                    }
+                };
+                info!("{}: {notice}", room.as_deref().unwrap_or("global"));
+                // Everyone sees it, the one who asked included
+                self.push_to_channel(room.as_deref(), &Message::system(notice.as_bytes()), None);
+            }
+            Command::Vote(n) => {
+                let Some(poll) = self.polls.get_mut(&client.channel) else {
+                    client.error("no poll here");
+                    return;
+                };
+                let Some(n) = n else {
+                    client.error("usage: /vote <n>");
+                    return;
+                };
+                match poll.vote(client.session_id, n) {
+                    Ok(false) => client.reply(&format!("voted for {}", poll.options[n - 1])),
+                    Ok(true) => client.reply(&format!("vote changed to {}", poll.options[n - 1])),
+                    Err(e) => client.error(&e),
+                }
+            }
+            Command::Channels => {
+                let admin = self.admins.contains(&token);
+                let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+                for c in self.clients.values() {
+                    if let Some(name) = c.channel.as_deref() {
+                        *counts.entry(name).or_default() += 1;
+                    }
+                }
+                counts.retain(|name, _| admin || !self.channels[*name].invite_only);
+                let text = if counts.is_empty() {
+                    "no channels".to_string()
+                } else {
+                    let list: Vec<String> = counts
+                        .iter()
+                        .map(|(name, n)| format!("{name} ({n})"))
+                        .collect();
+                    list.join("\n")
+                };
+                self.clients.get_mut(&token).unwrap().reply(&text);
+            }
+            Command::Part => {
+                let Some(old) = client.channel.take() else {
+                    client.error("not in a channel");
+                    return;
+                };
+                client.reply(&format!("left {old}"));
+                let notice = format!("* {} left {old}", client.nick);
+                self.push_to_channel(Some(&old), &Message::system(notice.as_bytes()), Some(token));
+                self.prune_channel(&old);
+            }
+            Command::Oper(user, secret) => {
+                let user = String::from_utf8_lossy(user.unwrap_or_default());
+                let secret = String::from_utf8_lossy(secret);
+                let result = self.auth.as_ref().map(|a| a.authenticate(&user, &secret));
+                if result == Some(AuthResult::Granted) {
+                    self.admins.insert(token);
+                    if !user.is_empty() {
+                        client.oper_user = Some(user.into_owned());
+                    }
+                    client.reply("you are now an admin");
+                } else {
+                    client.error("permission denied");
+                }
+            }
+            Command::InviteToken => {
+                if !self.admins.contains(&token) {
+                    client.error("permission denied");
+                    return;
+                }
+                self.prune_invite_tokens();
+                let client = self.clients.get_mut(&token).unwrap();
+                if self.invite_tokens.len() >= MAX_INVITE_TOKENS {
+                    client.error("too many unused tokens, wait for some to expire");
+                    return;
+                }
+                let secret = match random_token() {
+                    Ok(secret) => secret,
+                    Err(e) => {
+                        error!("Can't mint an invite token: {e}");
+                        client.error("can't mint a token right now");
+                        return;
+                    }
+                };
+                self.invite_tokens
+                    .insert(secret.clone(), Instant::now() + INVITE_TOKEN_TTL);
+                info!("{} minted an invite token", client.nick);
+                client.reply(&format!(
+                    "/login-token {secret} makes someone an admin once, within {}m",
+                    INVITE_TOKEN_TTL.as_secs() / 60
+                ));
+            }
+            Command::LoginToken(secret) => {
+                let secret = String::from_utf8_lossy(secret);
+                // Used up either way, if it was ever valid
+                let valid = self
+                    .invite_tokens
+                    .remove(secret.as_ref())
+                    .is_some_and(|until| until > Instant::now());
+                let client = self.clients.get_mut(&token).unwrap();
+                if valid {
+                    self.admins.insert(token);
+                    info!("{} logged in with an invite token", client.nick);
+                    client.reply("you are now an admin");
+                } else {
+                    client.error("permission denied");
+                }
+            }
+            Command::Invite(nick, name) => {
+                if !self.admins.contains(&token) {
+                    client.error("permission denied");
+                    return;
+                }
+                let nick = String::from_utf8_lossy(nick);
+                let name = String::from_utf8_lossy(name);
+                let inviter = client.nick.clone();
+                let Some((&target, _)) = self.clients.iter().find(|(_, c)| c.nick == nick) else {
+                    let client = self.clients.get_mut(&token).unwrap();
+                    client.error(&format!("no such nick {nick}"));
+                    return;
+                };
+                let Some(channel) = self.channels.get_mut(name.as_ref()) else {
+                    let client = self.clients.get_mut(&token).unwrap();
+                    client.error(&format!("no such channel {name}"));
+                    return;
+                };
+                channel.invited.insert(target);
+                let client = self.clients.get_mut(&target).unwrap();
+                client.reply(&format!("{inviter} invited you to {name}"));
+                let client = self.clients.get_mut(&token).unwrap();
+                client.reply(&format!("invited {nick} to {name}"));
+            }
+            Command::Mode(name, modes) => {
+                if !self.admins.contains(&token) {
+                    client.error("permission denied");
+                    return;
+                }
+                let name = String::from_utf8_lossy(name);
+                let Some(channel) = self.channels.get_mut(name.as_ref()) else {
+                    client.error(&format!("no such channel {name}"));
+                    return;
+                };
+                let (mode, arg) = Command::split(modes);
+                match (mode, arg) {
+                    (b"+i", b"") => channel.invite_only = true,
+                    (b"-i", b"") => channel.invite_only = false,
+                    (b"+k", key) if !key.is_empty() => {
+                        channel.key = Some(String::from_utf8_lossy(key).into_owned());
+                    }
+                    (b"-k", b"") => channel.key = None,
+                    _ => {
+                        client.error("usage: /mode #name +i|-i|+k <key>|-k");
+                        return;
+                    }
+                }
+                client.reply(&format!("{name} mode changed"));
+            }
+            Command::Message(msg) => {
+                if let Some(id) = self.say(token, msg, None) {
+                    self.clients.get_mut(&token).unwrap().answer_msgid(id);
+                }
+            }
+            Command::Reply(parent, msg) => {
+                if !is_recent(&self.recent_messages, parent, &client.channel) {
+                    client.error(&format!("no recent message {parent} here"));
+                    return;
+                }
+                if let Some(id) = self.say(token, msg, Some(parent)) {
+                    self.clients.get_mut(&token).unwrap().answer_msgid(id);
+                }
+            }
+            Command::Send(client_id, parent, msg) => {
+                let client_id = match core::str::from_utf8(client_id) {
+                    Ok(id) if id.len() <= MAX_SEND_ID_LEN && !msg.is_empty() => id.to_string(),
+                    _ => {
+                        client.error("usage: /send <id> <text>");
+                        return;
+                    }
+                };
+                if let Some(parent) = parent {
+                    if !is_recent(&self.recent_messages, parent, &client.channel) {
+                        client.error(&format!("no recent message {parent} here"));
+                        return;
+                    }
+                }
+                // Ids are per nick, which a client keeps across reconnects
+                let nick = client.nick.clone();
+                self.prune_send_ids();
+                let sent = self
+                    .send_ids
+                    .iter()
+                    .find(|s| s.nick == nick && s.client_id == client_id)
+                    .map(|s| s.msgid);
+                let msgid = match sent {
+                    // A replay: acked like the first time, but not sent again
+                    Some(msgid) => msgid,
+                    None => match self.say(token, msg, parent) {
+                        Some(msgid) => {
+                            if self.send_ids.len() == MAX_SEND_IDS {
+                                self.send_ids.pop_front();
+                            }
+                            self.send_ids.push_back(SendId {
+                                nick,
+                                client_id: client_id.clone(),
+                                msgid,
+                                at: Instant::now(),
+                            });
+                            msgid
+                        }
+                        None => return,
+                    },
+                };
+                let client = self.clients.get_mut(&token).unwrap();
+                match client.request.take() {
+                    Some(request) => {
+                        let result = Value::Object(vec![
+                            ("msgid".into(), Value::Number(msgid as f64)),
+                            ("client_id".into(), Value::String(client_id)),
+                            ("duplicate".into(), Value::Bool(sent.is_some())),
+                        ]);
+                        client.write_response(request, "result", result);
+                    }
+                    None => client.reply(&format!("ack {client_id}")),
+                }
+            }
+            Command::React(id, emoji) => {
+                let emoji = match core::str::from_utf8(emoji) {
+                    Ok(e)
+                        if !e.is_empty()
+                            && e.len() <= MAX_REACTION_LEN
+                            && !e.chars().any(|c| c.is_whitespace() || c.is_control()) =>
+                    {
+                        e
+                    }
+                    _ => {
+                        client.error("usage: /react <msgid> <emoji>");
+                        return;
+                    }
+                };
+                if !is_recent(&self.recent_messages, id, &client.channel) {
+                    client.error(&format!("no recent message {id} here"));
+                    return;
+                }
+                let reaction = Value::Object(vec![
+                    ("jsonrpc".into(), Value::String("2.0".into())),
+                    ("method".into(), Value::String("reaction".into())),
+                    (
+                        "params".into(),
+                        Value::Object(vec![
+                            ("msgid".into(), Value::Number(id as f64)),
+                            ("emoji".into(), Value::String(emoji.to_string())),
+                            ("nick".into(), Value::String(client.nick.clone())),
+                        ]),
+                    ),
+                ]);
+                let data = Rc::new(format!("{reaction}\n").into_bytes());
+                let channel = client.channel.clone();
+                client.reply("reaction sent");
+                // Only JSON clients have the ids to make sense of it
+                for c in self.clients.values_mut() {
+                    if c.framing == Framing::Json && c.channel == channel {
+                        c.write(data.clone());
+                    }
+                }
+            }
+        }
+    }
+    fn handle_http(&mut self, token: Token, head: &[u8]) {
+        let client = self.clients.get_mut(&token).unwrap();
+        match http::respond(head) {
+            Response::Close(data) => {
+                client.write(data);
+                client.closing = true;
+            }
+            Response::Upgrade(data) => {
+                client.write(data);
+                client.set_framing(Framing::WebSocket);
+                let motd = self.expand(&self.motd, token);
+                let client = self.clients.get_mut(&token).unwrap();
+                client.reply(&motd);
+                debug!("Upgraded {} to WebSocket", client.nick);
+            }
+        }
+    }
+    // What the variables of a MOTD, topic or greeting stand for, sent to `nick`
+    fn template_vars<'a>(&self, nick: &'a str) -> template::Vars<'a> {
+        template::Vars {
+            nick,
+            clients: self
+                .clients
+                .values()
+                .filter(|c| c.framing != Framing::Http)
+                .count(),
+            uptime: self.started.elapsed(),
+            version: env!("CARGO_PKG_VERSION"),
+        }
+    }
+    // `text` with its variables expanded for the client
+    fn expand(&self, text: &str, token: Token) -> String {
+        template::expand(text, &self.template_vars(&self.clients[&token].nick))
+    }
+    // Makes `token` the founder, when the server has one
+    fn make_founder(&mut self, token: Token) {
+        if self.config.founder_greeting.is_none() && !self.config.founder_admin {
+            return;
+        }
+        self.founder = Some(token);
+        if self.config.founder_admin {
+            self.admins.insert(token);
+        }
+        let greeting = self.config.founder_greeting.as_deref();
+        let greeting = self.expand(
+            greeting.unwrap_or("you are the founder of this server"),
+            token,
+        );
+        let client = self.clients.get_mut(&token).unwrap();
+        client.reply(&greeting);
+        info!("{} is the founder", client.nick);
+    }
+    // Hands the role of a departed founder to the client connected longest
+    fn promote_founder(&mut self) {
+        if let Some(token) = self.oldest_client() {
+            self.make_founder(token);
+        }
+    }
+    fn oldest_client(&self) -> Option<Token> {
+        self.clients
+            .iter()
+            .filter(|(_, c)| c.framing != Framing::Http)
+            .min_by_key(|(_, c)| c.connected_at)
+            .map(|(token, _)| *token)
+    }
+    // Makes `token` an admin and tells everyone
+    fn op(&mut self, token: Token) {
+        self.admins.insert(token);
+        let notice = format!("* {} is now an operator", self.clients[&token].nick);
+        info!("{}", &notice[2..]);
+        self.push(&Message::system(notice.as_bytes()), |_, _| true);
+    }
+    // Whether the client is free of the message rate limit. Like everyone
+    // else it is still disconnected when too far behind on its output.
+    fn exempt(&self, token: Token) -> bool {
+        let client = &self.clients[&token];
+        self.config
+            .exempt
+            .iter()
+            .any(|pattern| match pattern.strip_prefix("user:") {
+                Some(user) => client
+                    .oper_user
+                    .as_deref()
+                    .is_some_and(|u| wildcard_match(user, u)),
+                None => wildcard_match(pattern, &client.nick),
+            })
+    }
+    // Why a command can't be used with this server's configuration, if it can't
+    fn disabled_command(&self, name: &str) -> Option<&'static str> {
+        match name {
+            "/fortune" if self.fortunes.is_empty() => Some("no fortunes"),
+            "/oper" if self.auth.is_none() => Some("no admin credentials"),
+            "/search" | "/history-limit" if self.config.db.is_none() => Some("no history"),
+            _ => None,
+        }
+    }
+    // Why the client may not say anything right now, if it may not
+    fn chat_refusal(&self, token: Token) -> Option<&'static str> {
+        let client = &self.clients[&token];
+        if client.channel.is_none() && self.config.no_global {
+            return Some("join a channel first (/join #name)");
+        }
+        self.talk_refusal(token)
+    }
+    // Like `chat_refusal`, for private messages, which need no room
+    fn talk_refusal(&self, token: Token) -> Option<&'static str> {
+        let client = &self.clients[&token];
+        if self.config.require_nick && !client.custom_nick {
+            return Some("set a nick with /nick first");
+        }
+        // Dropped rather than held, so nothing floods in when it ends
+        if self.maintenance && !self.admins.contains(&token) {
+            return Some("server in maintenance");
+        }
+        if self.quiet && !self.admins.contains(&token) {
+            return Some("quiet hours, only admins can talk");
+        }
+        None
+    }
+    // Broadcasts a chat message from the client to its room, optionally in
+    // reply to an earlier one. Returns its id unless it was refused.
+    fn say(&mut self, token: Token, msg: &[u8], reply_to: Option<u64>) -> Option<u64> {
+        let msg = if self.config.trim_messages {
+            msg.trim_ascii()
+        } else {
+            msg
+        };
+        // Nothing to see, like a stray enter or a line of spaces
+        if msg.is_empty() {
+            return None;
+        }
+        if let Some(e) = self.chat_refusal(token) {
+            self.clients.get_mut(&token).unwrap().error(e);
+            return None;
+        }
+        if !self.admit_message(token, hash_bytes(msg)) {
+            return None;
+        }
+        let client = self.clients.get_mut(&token).unwrap();
+        let msg = match self.config.max_message_len {
+            Some(max) => truncate_utf8(msg, max),
+            None => msg,
+        };
+        let nick = client.nick.clone();
+        let channel = client.channel.clone();
+        self.mark_seen(&nick);
+        self.send_receipts(token);
+        let mut msg = Message::chat(&nick, msg);
+        msg.reply_to = reply_to;
+        Some(self.send_chat(msg, channel, Some(token)))
+    }
+    // Counts a message against `--message-rate` and `--slow-mode` and, by
+    // its `hash`, against the previous one for `--dedup-window`. Tells the
+    // client and returns false if it's refused.
+    fn admit_message(&mut self, token: Token, hash: u64) -> bool {
+        let exempt = self.exempt(token);
+        let client = self.clients.get_mut(&token).unwrap();
+        // Follow the rate as changed with /set since the client connected
+        client.messages = match (self.config.message_rate, client.messages.take()) {
+            (Some(rate), Some(mut bucket)) => {
+                bucket.set_rate(rate);
+                Some(bucket)
+            }
+            (rate, _) => rate.map(TokenBucket::new),
+        };
+        let now = Instant::now();
+        if !exempt {
+            if let Some(bucket) = &mut client.messages {
+                if bucket.available() == 0 {
+                    client.error("slow down, too many messages");
+                    return false;
+                }
+            }
+            let wait = self.config.slow_mode.unwrap_or_default();
+            if client.last_message.is_some_and(|(_, at)| now - at < wait) {
+                client.error("slow mode is on, wait before sending again");
+                return false;
+            }
+            if let Some(bucket) = &mut client.messages {
+                bucket.consume(1);
+            }
+        }
+        let last = client.last_message.replace((hash, now));
+        if let Some(window) = self.config.dedup_window {
+            if last.is_some_and(|(h, at)| h == hash && now - at < window) {
+                client.error("duplicate message suppressed");
+                return false;
+            }
+        }
+        true
+    }
+    // Numbers, signs and remembers a chat message, then sends it to
+    // everyone in `channel` but `exclude`
+    fn send_chat(&mut self, msg: Message, channel: Option<String>, exclude: Option<Token>) -> u64 {
+        let id = self.next_message_id;
+        self.next_message_id += 1;
+        let mut msg = msg.with_id(id);
+        if let Some(key) = &self.config.sign_key {
+            msg = msg.signed(key.as_bytes().into());
+        }
+        self.push_to_channel(channel.as_deref(), &msg, exclude);
+        self.heartbeat.message();
+        #[cfg(feature = "sqlite")]
+        if let (Some(history), Some(nick)) = (&mut self.history, msg.nick) {
+            history.record(channel.as_deref(), nick, &String::from_utf8_lossy(msg.text));
+        }
+        // One line per message, even from binary clients
+        let line = format!(
+            "{}> {}",
+            msg.nick.unwrap_or("*"),
+            String::from_utf8_lossy(msg.text).replace('\n', " ")
+        );
+        if let (Some(transcripts), Some(_)) = (&mut self.transcripts, msg.nick) {
+            transcripts.record(
+                channel.as_deref(),
+                &format!("{} {line}", format_utc(unix_time())),
+            );
+        }
+        if self.recent_messages.len() == MAX_RECENT_MESSAGES {
+            self.recent_messages.pop_front();
+        }
+        self.recent_messages
+            .push_back(RecentMessage { id, channel, line });
+        id
+    }
+    /// Says `text` as `nick`, a pseudo-client, to every connected client,
+    /// e.g. to relay from another source. It's rendered, numbered and
+    /// signed like any client's message.
+    ///
+    /// The message is only queued: it reaches the sockets as the caller
+    /// keeps driving the poll loop.
+    // Only embedders and tests call it
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn inject_broadcast(&mut self, nick: &str, text: &str) {
+        let id = self.next_message_id;
+        self.next_message_id += 1;
+        let mut msg = Message::chat(nick, text.as_bytes()).with_id(id);
+        if let Some(key) = &self.config.sign_key {
+            msg = msg.signed(key.as_bytes().into());
+        }
+        self.push(&msg, |_, _| true);
+        self.heartbeat.message();
+    }
+    /// An announcement to every connected client, queued ahead of chatter.
+    ///
+    /// Like `inject_broadcast`, it reaches the sockets as the caller keeps
+    /// driving the poll loop.
+    pub fn inject_system(&mut self, text: &str) {
+        self.push(&Message::system(text.as_bytes()).urgent(), |_, _| true);
+    }
+    // Sends to everyone in `channel` but `exclude`. `None` is the global room.
+    fn push_to_channel(&mut self, channel: Option<&str>, msg: &Message, exclude: Option<Token>) {
+        self.push(msg, |k, c| {
+            Some(*k) != exclude && c.channel.as_deref() == channel
+        });
+    }
+    // Sends to every client for which `to` is true
+    fn push(&mut self, msg: &Message, to: impl Fn(&Token, &Client) -> bool) {
+        // Rendered once per key, then shared by the clients using it
+        let mut rendered: HashMap<RenderKey, Rc<Vec<u8>>> = HashMap::new();
+        let mut deliveries = VecDeque::new();
+        for (token, c) in self
+            .clients
+            .iter()
+            .filter(|(k, c)| c.framing != Framing::Http && to(k, c))
+        {
+            let data = match c.render_key() {
+                Some(key) => rendered
+                    .entry(key)
+                    .or_insert_with(|| Rc::new(c.render(msg)))
+                    .clone(),
+                None => Rc::new(c.render(msg)),
+            };
+            deliveries.push_back((*token, data));
+        }
+        // Urgent ones don't wait behind chatter still being handed out either
+        let at = match msg.priority {
+            Priority::Normal => self.fanout.len(),
+            Priority::High => self
+                .fanout
+                .iter()
+                .position(|(p, _)| *p != Priority::High)
+                .unwrap_or(self.fanout.len()),
+        };
+        self.fanout.insert(at, (msg.priority, deliveries));
+        // Earlier broadcasts still waiting go first, so each client gets
+        // them in order
+        if self.fanout.len() == 1 {
+            let batch = self.config.fanout_batch.unwrap_or(usize::MAX);
+            self.deliver_fanout(batch);
+        }
+    }
+    // Hands queued broadcasts to up to `budget` clients. With `--fanout-batch`
+    // a broadcast to a huge room is spread over several loop iterations.
+    fn deliver_fanout(&mut self, mut budget: usize) {
+        while let Some((priority, deliveries)) = self.fanout.front_mut() {
+            while budget > 0 {
+                let Some((token, data)) = deliveries.pop_front() else {
+                    break;
+                };
+                if let Some(c) = self.clients.get_mut(&token) {
+                    // Queueing more for a client that's already too far
+                    // behind would keep yet another buffer alive, so it's
+                    // marked for eviction instead
+                    let max = self.config.max_retained.unwrap_or(usize::MAX);
+                    if c.retained().saturating_add(data.len()) > max && !c.in_welcome_grace() {
+                        self.laggards.insert(token);
+                    } else {
+                        c.write_with(data, *priority);
+                    }
+                }
+                budget -= 1;
+            }
+            if !deliveries.is_empty() {
+                return;
+            }
+            self.fanout.pop_front();
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> Result<(), ServerError> {
+    let config = Config::from_args(std::env::args().skip(1)).map_err(ServerError::Config)?;
+    logger::init(config.log_level);
+    serve(config)
+}
+
+// Runs the server until it's told to stop
+fn serve(config: Config) -> Result<(), ServerError> {
+    let addr = config.addr;
+    let mut chat = Chat::new(config)?;
+    let mut poll = Poll::new()?;
+
+    let inherited = match chat.config.systemd {
+        true => listener::inherited()?,
+        false => None,
+    };
+    let mut server = match inherited {
+        Some(server) => {
+            info!("Server started at {} (from systemd)", server.local_addr()?);
+            server
+        }
+        None => {
+            let server = listen(addr, &chat.config)?;
+            // The port the system picked, if it was 0
+            info!("Server started at {}", server.local_addr()?);
+            server
+        }
+    };
+    poll.registry()
+        .register(&mut server, SERVER, Interest::READABLE)?;
+    // Wakes the loop once the test is over, kept here until then
+    let self_test = match chat.config.self_test {
+        true => {
+            let waker = Arc::new(Waker::new(poll.registry(), SELF_TEST)?);
+            let test = selftest::spawn(server.local_addr()?, waker.clone());
+            Some((test, waker))
+        }
+        false => None,
+    };
+    // Closed when draining, so new connections are refused
+    let mut server = Some(server);
+
+    let mut web = match chat.config.web {
+        Some(addr) => {
+            let mut web = listen(addr, &chat.config)?;
+            info!("Web client at http://{addr}/");
+            poll.registry()
+                .register(&mut web, WEB, Interest::READABLE)?;
+            Some(web)
+        }
+        None => None,
+    };
+
+    let mut signals = Signals::register(
+        poll.registry(),
+        SIGNALS,
+        &[libc::SIGHUP, libc::SIGINT, libc::SIGTERM, libc::SIGUSR1],
+    )?;
+
+    // Starting inside a window shouldn't let anything through until the first wakeup
+    chat.update_quiet_hours(unix_time());
+
+    let mut events = Events::with_capacity(1024);
+
+    let mut shutdown = false;
+    while !shutdown {
+        match poll.poll(&mut events, chat.poll_timeout()) {
+            // A signal arrived, it's waiting in the self-pipe
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => result?,
+        }
+        // Connections are accepted after the whole batch, so a shutdown
+        // signal anywhere in it keeps them out
+        let (mut line_ready, mut web_ready) = (false, false);
+        for event in &events {
+            let token = event.token();
+            if token == SIGNALS {
+                for signal in signals.pending()? {
+                    match signal {
+                        libc::SIGHUP => chat.reload(),
+                        libc::SIGINT | libc::SIGTERM => shutdown = true,
+                        libc::SIGUSR1 => {
+                            info!("Drain started by SIGUSR1");
+                            chat.start_drain();
+                        }
+                        _ => {}
+                    }
+                }
+            } else if token == SELF_TEST {
+                shutdown = true;
+            } else if token == SERVER {
+                line_ready = true;
+            } else if token == WEB {
+                web_ready = true;
+            } else {
+                chat.handle_client_event(event, poll.registry());
+            }
+        }
+        // Connections that came while accepting was paused sent no event of
+        // their own
+        if chat
+            .accept_paused
+            .is_some_and(|until| Instant::now() >= until)
+        {
+            chat.accept_paused = None;
+            (line_ready, web_ready) = (true, true);
+        }
+        // Once shutdown started they're left for `refuse_clients`
+        if let (true, false, Some(server)) = (line_ready, shutdown, &server) {
+            accept_clients(&mut chat, server, Framing::Line, poll.registry());
+        }
+        if let (true, false, Some(web)) = (web_ready, shutdown, &web) {
+            accept_clients(&mut chat, web, Framing::Http, poll.registry());
+        }
+        chat.handle_pending_input(poll.registry());
+        if let Some(batch) = chat.config.fanout_batch {
+            chat.deliver_fanout(batch);
+        }
+        chat.release_held();
+        chat.expire_stalled(poll.registry());
+        chat.expire_handshakes(poll.registry());
+        chat.evict_laggards(poll.registry());
+        chat.enforce_memory_cap(poll.registry());
+        chat.reap(poll.registry());
+        chat.prune_reserved_nicks();
+        chat.prune_invite_tokens();
+        chat.tokens.reclaim();
+        chat.heartbeat();
+        chat.update_quiet_hours(unix_time());
+        chat.flush_history();
+        chat.flush_transcripts();
+        if chat.draining.is_some() && (server.is_some() || web.is_some()) {
+            server = None;
+            web = None;
+        }
+        if chat.drained() {
+            shutdown = true;
+        }
+    }
+
+    info!("Shutting down");
+    // No client is added from here on; whoever is still waiting to be
+    // accepted is told and closed rather than left hanging
+    for mut listener in [server.take(), web.take()].into_iter().flatten() {
+        poll.registry().deregister(&mut listener)?;
+        refuse_clients(&listener);
+    }
+    // Queued before the drain starts, so the drain knows everything it has to deliver
+    chat.inject_system("* server shutting down");
+    chat.deliver_fanout(usize::MAX);
+    drain(&mut chat, &mut poll, &mut events)?;
+    if let Some((test, _waker)) = self_test {
+        // Closing the clients' sockets, so a test cut short by another
+        // reason to shut down fails rather than waits
+        drop(chat);
+        match test.join() {
+            Ok(Ok(())) => info!("Self-test passed"),
+            Ok(Err(e)) => return Err(ServerError::SelfTest(e)),
+            Err(_) => return Err(ServerError::SelfTest("the test panicked".into())),
+        }
+    }
+    Ok(())
+}
+
+// A message as a JSON-RPC notification: `message` for chat, `notice` for
+// the server's own
+fn render_notification(msg: &Message) -> Vec<u8> {
+    let text = |b: &[u8]| Value::String(String::from_utf8_lossy(b).into_owned());
+    let mut params = vec![("text".to_string(), text(msg.text))];
+    if let Some(id) = msg.id {
+        params.push(("msgid".into(), Value::Number(id as f64)));
+    }
+    if let Some(nick) = msg.nick {
+        params.insert(0, ("nick".into(), Value::String(nick.to_string())));
+        // Of `nick> text`, built from the two fields as they're sent
+        let shown = format!("{nick}> {}", String::from_utf8_lossy(msg.text));
+        if let Some(sig) = msg.signature(shown.as_bytes()) {
+            params.push(("sig".into(), Value::String(sig)));
+        }
+    }
+    if let Some(parent) = msg.reply_to {
+        params.push(("reply_to".into(), Value::Number(parent as f64)));
+    }
+    if msg.private {
+        params.push(("private".into(), Value::Bool(true)));
+    }
+    let method = if msg.nick.is_some() {
+        "message"
+    } else {
+        "notice"
+    };
+    let notification = Value::Object(vec![
+        ("jsonrpc".into(), Value::String("2.0".into())),
+        ("method".into(), Value::String(method.into())),
+        ("params".into(), Value::Object(params)),
+    ]);
+    format!("{notification}\n").into_bytes()
+}
+
+// Accepts every pending connection on `server`, unless accepting is paused. Line
+// clients are greeted right away, web ones only once they upgrade to a WebSocket.
+fn accept_clients(chat: &mut Chat, server: &TcpListener, framing: Framing, registry: &Registry) {
+    while chat.accept_paused.is_none() {
+        match server.accept() {
+            Ok((conn, addr)) => {
+                chat.add_client(Stream::Tcp(conn), addr, framing, registry);
+            }
+            Err(e) if is_would_block(&e) => return,
+            Err(e) if !chat.accept_failed(&e) => return,
+            Err(_) => {}
+        }
+    }
+}
+
+// Closes every pending connection on `server` with a notice
+fn refuse_clients(server: &TcpListener) {
+    let mut refused = 0;
+    while let Ok((mut conn, _)) = server.accept() {
+        // Best effort, the connection is dropped right away
+        let _ = conn.write_all(b"server shutting down\n");
+        refused += 1;
+    }
+    if refused > 0 {
+        info!("Refused {refused} pending connections: shutting down");
+    }
+}
+
+// Delivers what is queued until every outbox is empty or `shutdown_timeout`
+// passes. Input isn't read anymore, so the outboxes can only shrink.
+fn drain(chat: &mut Chat, poll: &mut Poll, events: &mut Events) -> Result<(), io::Error> {
+    let deadline = Instant::now() + chat.config.shutdown_timeout;
+    loop {
+        // Tarpitted and shaped output is due on the clock, not on an event
+        chat.release_held();
+        // Dropped silently, a leave notice would only add to the outboxes
+        chat.clients.retain(|_, c| c.failed.is_none());
+        let queued: usize = chat.clients.values().map(|c| c.stats.queued).sum();
+        if queued == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            warn!("Shutdown deadline passed, {queued} bytes undelivered");
+            return Ok(());
+        }
+        chat.update_interests(poll.registry());
+        let wake = chat
+            .clients
+            .values()
+            .filter_map(Client::release_at)
+            .fold(deadline, Instant::min);
+        match poll.poll(events, Some(wake.saturating_duration_since(now))) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => result?,
+        }
+        for event in events.iter().filter(|e| e.is_writable()) {
+            let Some(client) = chat.clients.get_mut(&event.token()) else {
+                continue;
+            };
+            client.writable = true;
+            if let FlushStatus::Failed(_) = client.flush_outbox() {
+                // Dropped silently, a leave notice would only add to the outboxes
+                chat.clients.remove(&event.token());
+            }
+        }
+    }
+}
+
+// Appends a nick for a message prefix, cut to `MAX_RENDERED_NICK` bytes
+// whatever let a longer one through
+fn push_nick(body: &mut Vec<u8>, nick: &str) {
+    let clamped = truncate_utf8(nick.as_bytes(), MAX_RENDERED_NICK);
+    body.extend_from_slice(clamped);
+    if clamped.len() < nick.len() {
+        body.extend_from_slice("…".as_bytes());
+    }
+}
+
+// How a client's session id is shown after its nick, if it is
+fn session_tag(show: bool, id: u64) -> String {
+    if show {
+        format!(" #{id}")
+    } else {
+        String::new()
+    }
+}
+
+// Whether message `id` is among the recent ones, and was said in `channel`
+fn is_recent(recent: &VecDeque<RecentMessage>, id: u64, channel: &Option<String>) -> bool {
+    recent.iter().any(|m| m.id == id && m.channel == *channel)
+}
+
+// Every line feed made a CRLF, leaving those already preceded by CR alone
+fn to_crlf(text: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(text.len() + 8);
+    for (i, b) in text.iter().enumerate() {
+        if *b == b'\n' && (i == 0 || text[i - 1] != b'\r') {
+            out.push(b'\r');
+        }
+        out.push(*b);
+    }
+    out
+}
+
+// Seconds since the Unix epoch
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// RFC 3339, like "2024-05-01T12:00:00Z", from seconds since the Unix epoch
+fn format_utc(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Days to a civil date, from http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+// Like "3m ago", in the largest unit that fits
+fn format_ago(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    match secs {
+        0..=59 => format!("{secs}s ago"),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+fn valid_channel(name: &str) -> bool {
+    name.len() <= 32
+        && name.strip_prefix('#').is_some_and(|n| {
+            !n.is_empty()
+                && n.bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+}
+
+// A listening socket on `addr`, set up as configured
+fn listen(addr: SocketAddr, config: &Config) -> Result<TcpListener, ServerError> {
+    listener::bind(addr, config.reuse_addr, config.backlog).map_err(|e| ServerError::Bind(addr, e))
+}
+
+fn load_motd(config: &Config) -> Result<String, io::Error> {
+    match &config.motd {
+        Some(path) => Ok(std::fs::read_to_string(path)?.trim_end().to_string()),
+        None => Ok(DEFAULT_MOTD.to_string()),
+    }
+}
+
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+struct Feature {
+    name: &'static str,
+    built: bool,
+    // Clients switch it on with `CAP <name>`
+    negotiated: bool,
+}
+
+// Every feature in Cargo.toml, for /version and `CAP` negotiation
+const FEATURES: [Feature; 3] = [
+    Feature {
+        name: "sqlite",
+        built: cfg!(feature = "sqlite"),
+        negotiated: false,
+    },
+    Feature {
+        name: "compress",
+        built: cfg!(feature = "compress"),
+        negotiated: true,
+    },
+    Feature {
+        name: "test-transport",
+        built: cfg!(feature = "test-transport"),
+        negotiated: false,
+    },
+];
+
+fn features() -> Vec<&'static str> {
+    FEATURES
+        .iter()
+        .filter(|f| f.built)
+        .map(|f| f.name)
+        .collect()
+}
+
+fn version() -> String {
+    let mut version = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    let features = features();
+    if !features.is_empty() {
+        version.push_str(&format!(" ({})", features.join(", ")));
+    }
+    version
+}
+
+fn is_would_block(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock
+}
+
+// The kernel is short of buffers, retrying later may work
+fn is_transient(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOBUFS)
+}
+
+// The peer went away, the usual way for a connection to end
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memstream::MemoryStream;
+
+    // A server whose clients are memory streams, read and flushed directly
+    // rather than through the event loop
+    struct Harness {
+        chat: Chat,
+        poll: Poll,
+        next_port: u16,
+    }
+
+    impl Harness {
+        fn new(args: &[&str]) -> Self {
+            let config = Config::from_args(args.iter().map(|a| a.to_string())).unwrap();
+            Self {
+                chat: Chat::new(config).unwrap(),
+                poll: Poll::new().unwrap(),
+                next_port: 40000,
+            }
+        }
+        fn connect(&mut self) -> Token {
+            self.connect_from([127, 0, 0, 1].into()).unwrap()
+        }
+        fn connect_from(&mut self, ip: IpAddr) -> Option<Token> {
+            self.connect_stream(MemoryStream::new(), ip)
+        }
+        fn connect_stream(&mut self, stream: MemoryStream, ip: IpAddr) -> Option<Token> {
+            self.connect_framed(stream, ip, Framing::Line)
+        }
+        // A client of the web listener, which starts with an HTTP request
+        fn connect_web(&mut self) -> Token {
+            let ip = [127, 0, 0, 1].into();
+            self.connect_framed(MemoryStream::new(), ip, Framing::Http)
+                .unwrap()
+        }
+        fn connect_framed(
+            &mut self,
+            stream: MemoryStream,
+            ip: IpAddr,
+            framing: Framing,
+        ) -> Option<Token> {
+            self.next_port += 1;
+            let addr = SocketAddr::new(ip, self.next_port);
+            let stream = Stream::Memory(stream);
+            let registry = self.poll.registry();
+            self.chat.add_client(stream, addr, framing, registry)
+        }
+        fn stream(&mut self, token: Token) -> &mut MemoryStream {
+            match &mut self.chat.clients.get_mut(&token).unwrap().listener {
+                Stream::Memory(stream) => stream,
+                Stream::Tcp(_) => unreachable!(),
+            }
+        }
+        // Sends a line and handles it
+        fn send(&mut self, token: Token, line: &str) {
+            self.stream(token)
+                .push_input(format!("{line}\n").as_bytes());
+            self.chat.handle_readable(token, self.poll.registry());
+        }
+        // Everything written to the client since the last call
+        fn output(&mut self, token: Token) -> String {
+            String::from_utf8_lossy(&self.stream(token).take_output()).into_owned()
+        }
+        // A client that picked `nick`, its output so far discarded
+        fn join(&mut self, nick: &str) -> Token {
+            let token = self.connect();
+            self.send(token, &format!("/nick {nick}"));
+            self.output(token);
+            token
+        }
+    }
+
+    #[test]
+    fn chat_round_trip() {
+        let mut h = Harness::new(&[]);
+        let alice = h.connect();
+        assert!(h.output(alice).contains("Welcome to Simple Chat!"));
+        let bob = h.join("bob");
+        h.send(alice, "/nick alice");
+        assert!(h.output(alice).contains("nick changed to alice"));
+        h.send(alice, "hello there");
+        assert!(h.output(bob).contains("alice> hello there"));
+        // The sender doesn't get its own message back
+        assert!(!h.output(alice).contains("hello there"));
+    }
+
+    #[test]
+    fn end_of_stream_disconnects() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        h.stream(alice).push_eof();
+        h.chat.handle_readable(alice, h.poll.registry());
+        assert!(!h.chat.clients.contains_key(&alice));
+    }
+
+    #[test]
+    fn line_split_across_reads() {
+        let mut h = Harness::new(&[]);
+        let alice = h.connect();
+        h.output(alice);
+        h.stream(alice).push_input(b"/nick al");
+        h.stream(alice).push_would_block();
+        h.stream(alice).push_input(b"ice\n");
+        h.chat.handle_readable(alice, h.poll.registry());
+        assert!(!h.output(alice).contains("nick changed"));
+        h.chat.handle_readable(alice, h.poll.registry());
+        assert!(h.output(alice).contains("nick changed to alice"));
+    }
+
+    #[test]
+    fn short_writes_deliver_everything() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.stream(bob).limit_writes(Some(3));
+        h.send(alice, "a message longer than a few bytes");
+        assert!(h
+            .output(bob)
+            .contains("alice> a message longer than a few bytes"));
+    }
+
+    #[test]
+    fn blocked_output_waits_for_writable() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.stream(bob).block_writes_after(Some(0));
+        h.send(alice, "are you there?");
+        assert_eq!(h.output(bob), "");
+        assert!(!h.chat.clients[&bob].writable);
+        h.stream(bob).block_writes_after(None);
+        let client = h.chat.clients.get_mut(&bob).unwrap();
+        client.writable = true;
+        assert!(matches!(client.try_flush(), FlushStatus::Drained));
+        assert!(h.output(bob).contains("alice> are you there?"));
+    }
+
+    #[test]
+    fn system_message_reaches_everyone() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(bob, "/join #elsewhere");
+        h.output(bob);
+        h.chat.inject_system("* server shutting down");
+        for token in [alice, bob] {
+            assert!(h.output(token).contains("* server shutting down"));
+        }
+    }
+
+    #[test]
+    fn injected_broadcast_reaches_everyone() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(bob, "/join #elsewhere");
+        let json = h.join("json");
+        h.send(json, "/json");
+        for token in [alice, bob, json] {
+            h.output(token);
+        }
+        h.chat.inject_broadcast("relay", "hello from irc");
+        for token in [alice, bob] {
+            assert!(h.output(token).starts_with("relay> hello from irc\n"));
+        }
+        let output = h.output(json);
+        assert!(output.contains(r#""method":"message""#), "{output}");
+        assert!(output.contains(r#""nick":"relay""#), "{output}");
+        assert!(output.contains(r#""text":"hello from irc""#), "{output}");
+    }
+
+    #[test]
+    fn quiet_hours_refuse_chat_inside_the_window() {
+        let mut h = Harness::new(&["--quiet-hours", "22:00-07:00"]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        let day = 19_000 * 86400;
+        h.chat.update_quiet_hours(day + 21 * 3600 + 3599);
+        h.send(alice, "still allowed");
+        assert!(h.output(bob).contains("alice> still allowed"));
+        h.chat.update_quiet_hours(day + 22 * 3600);
+        assert!(h.output(bob).contains("quiet hours until 07:00 UTC"));
+        h.send(alice, "too late");
+        assert!(!h.output(bob).contains("too late"));
+        assert!(h.output(alice).contains("quiet hours"));
+        // Past midnight it's still on, and it ends at 07:00
+        h.chat.update_quiet_hours(day + 86400 + 3600);
+        assert!(h.chat.quiet);
+        h.chat.update_quiet_hours(day + 86400 + 7 * 3600);
+        assert!(h.output(bob).contains("quiet hours over"));
+        h.send(alice, "good morning");
+        assert!(h.output(bob).contains("alice> good morning"));
+    }
+
+    #[test]
+    fn port_in_use_is_a_bind_error() {
+        let config = Config::default();
+        let first = listen("127.0.0.1:0".parse().unwrap(), &config).unwrap();
+        let addr = first.local_addr().unwrap();
+        match listen(addr, &config) {
+            Err(ServerError::Bind(failed, e)) => {
+                assert_eq!(failed, addr);
+                assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+            }
+            Err(e) => panic!("expected a bind error, got {e}"),
+            Ok(_) => panic!("bound {addr} twice"),
+        }
+    }
+
+    #[test]
+    fn self_test_passes_against_a_fresh_server() {
+        let config = Config {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            self_test: true,
+            heartbeat_interval: None,
+            ..Config::default()
+        };
+        if let Err(e) = serve(config) {
+            panic!("{e}");
+        }
+    }
+
+    #[test]
+    fn self_test_fails_when_messages_dont_get_through() {
+        // The test message arrives cut short, if at all
+        let config = Config {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            self_test: true,
+            heartbeat_interval: None,
+            max_message_len: Some(4),
+            ..Config::default()
+        };
+        match serve(config) {
+            Err(ServerError::SelfTest(e)) => assert!(e.contains("never got"), "{e}"),
+            Err(e) => panic!("expected a self-test failure, got {e}"),
+            Ok(()) => panic!("the self-test passed"),
+        }
+    }
+
+    // Checks the signature at the end of each line of `output` against
+    // the bytes in front of it, returning how many lines were signed
+    fn verify_signatures(output: &[u8], key: &[u8]) -> usize {
+        let mut signed = 0;
+        for line in output.split(|b| *b == b'\n') {
+            let line = line.strip_prefix(CLEAR_LINE).unwrap_or(line);
+            let Some(at) = line.windows(6).position(|w| w == b" [sig:") else {
+                continue;
+            };
+            let sig = &line[at + 6..at + 6 + 64];
+            assert_eq!(sig, hex(&hmac_sha256(key, &line[..at])).as_bytes());
+            signed += 1;
+        }
+        signed
+    }
+
+    #[test]
+    fn signature_covers_the_bytes_sent() {
+        let mut h = Harness::new(&["--sign", "secret"]);
+        let nick = "a".repeat(MAX_RENDERED_NICK + 8);
+        let speaker = h.join(&nick);
+        let plain = h.join("plain");
+        let raw = h.join("raw");
+        h.send(raw, "/raw on");
+        h.send(raw, "/crlf on");
+        h.output(raw);
+        h.send(speaker, "\x1b[1mbold\x1b[0m text");
+        for token in [plain, raw] {
+            let output = h.stream(token).take_output();
+            assert_eq!(verify_signatures(&output, b"secret"), 1);
+        }
+    }
+
+    #[test]
+    fn json_signature_covers_nick_and_text() {
+        let mut h = Harness::new(&["--sign", "secret"]);
+        let speaker = h.join("alice");
+        let json = h.join("json");
+        h.send(json, "/json");
+        h.output(json);
+        h.send(speaker, "hello");
+        let output = h.output(json);
+        let expected = hex(&hmac_sha256(b"secret", b"alice> hello"));
+        assert!(
+            output.contains(&format!("\"sig\":\"{expected}\"")),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn long_nicks_are_refused() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let longest = "n".repeat(MAX_NICK_LEN);
+        h.send(alice, &format!("/nick {longest}n"));
+        assert!(h.output(alice).contains("nick too long, 64 bytes at most"));
+        assert_eq!(h.chat.clients[&alice].nick, "alice");
+        h.send(alice, &format!("/nick {longest}"));
+        assert_eq!(h.chat.clients[&alice].nick, longest);
+    }
+
+    #[test]
+    fn private_messages_are_held_to_the_chat_rules() {
+        let mut h = Harness::new(&["--require-nick", "--dedup-window", "60000"]);
+        let bob = h.join("bob");
+        let guest = h.connect();
+        h.output(guest);
+        h.send(guest, "/msg bob psst");
+        assert!(h.output(guest).contains("set a nick with /nick first"));
+        assert!(!h.output(bob).contains("psst"));
+        let alice = h.join("alice");
+        h.send(alice, "/msg bob psst");
+        assert!(h.output(bob).contains("alice (private)> psst"));
+        h.send(alice, "/msg bob psst");
+        assert!(h.output(alice).contains("duplicate message suppressed"));
+        assert!(!h.output(bob).contains("psst"));
+        // The same words said to someone else aren't a duplicate
+        let guest_nick = h.chat.clients[&guest].nick.clone();
+        h.send(alice, &format!("/msg {guest_nick} psst"));
+        assert!(h.output(guest).contains("alice (private)> psst"));
+        h.chat.maintenance = true;
+        h.send(alice, "/msg bob hello");
+        assert!(h.output(alice).contains("server in maintenance"));
+        assert!(!h.output(bob).contains("hello"));
+    }
+
+    #[test]
+    fn private_messages_count_against_the_rate() {
+        let mut h = Harness::new(&["--message-rate", "2"]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(alice, "one");
+        h.send(alice, "/msg bob two");
+        h.send(alice, "/msg bob three");
+        assert!(h.output(alice).contains("slow down, too many messages"));
+        let output = h.output(bob);
+        assert!(output.contains("two") && !output.contains("three"));
+    }
+
+    #[test]
+    fn recent_is_for_admins_and_anonymized() {
+        let mut h = Harness::new(&["--anonymize-ips"]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(bob, "/quit");
+        h.send(alice, "/recent");
+        assert!(h.output(alice).contains("permission denied"));
+        h.chat.admins.insert(alice);
+        h.send(alice, "/recent");
+        let output = h.output(alice);
+        assert!(output.contains("bob from ip-"), "{output}");
+        assert!(!output.contains("127.0.0.1"));
+    }
+
+    #[test]
+    fn recent_stays_anonymized_when_admins_see_ips() {
+        let mut h = Harness::new(&["--anonymize-ips", "--admins-see-ips"]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(bob, "/quit");
+        h.chat.admins.insert(alice);
+        h.send(alice, "/recent");
+        let output = h.output(alice);
+        assert!(output.contains("bob from ip-"), "{output}");
+        assert!(!output.contains("127.0.0.1"));
+    }
+
+    #[test]
+    fn connections_per_ip_only_count_clients_taken() {
+        let mut h = Harness::new(&["--max-per-ip", "1"]);
+        let ip: IpAddr = [10, 0, 0, 1].into();
+        let mut broken = MemoryStream::new();
+        broken.fail_writes(Some(io::ErrorKind::ConnectionReset));
+        assert_eq!(h.connect_stream(broken, ip), None);
+        assert!(h.chat.connections_per_ip.is_empty());
+        let first = h.connect_from(ip).unwrap();
+        assert_eq!(h.connect_from(ip), None);
+        assert_eq!(h.chat.connections_per_ip[&ip], 1);
+        h.send(first, "/quit");
+        assert!(h.chat.connections_per_ip.is_empty());
+        assert!(h.connect_from(ip).is_some());
+    }
+
+    #[test]
+    fn drain_releases_shaped_output() {
+        let mut h = Harness::new(&["--egress-rate", "20000", "--shutdown-timeout", "5"]);
+        let alice = h.join("alice");
+        let text = "x".repeat(50_000);
+        h.chat.inject_system(&text);
+        assert!(h.chat.clients[&alice].stats.queued > 0);
+        let started = Instant::now();
+        let mut events = Events::with_capacity(16);
+        drain(&mut h.chat, &mut h.poll, &mut events).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(4));
+        assert_eq!(h.chat.clients[&alice].stats.queued, 0);
+        assert!(h.output(alice).contains(&text));
+    }
+
+    #[test]
+    fn drain_gives_up_on_a_client_that_never_reads() {
+        let mut h = Harness::new(&["--shutdown-timeout", "1"]);
+        let alice = h.join("alice");
+        h.stream(alice).block_writes_after(Some(0));
+        h.chat.inject_system("going down");
+        let queued = h.chat.clients[&alice].stats.queued;
+        assert!(queued > 0);
+        let started = Instant::now();
+        let mut events = Events::with_capacity(16);
+        let logged = capture_logs(|| drain(&mut h.chat, &mut h.poll, &mut events).unwrap());
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(3));
+        let warning = format!("Shutdown deadline passed, {queued} bytes undelivered");
+        assert!(logged.contains(&(log::Level::Warn, warning)), "{logged:?}");
+    }
+
+    #[test]
+    fn accepting_pauses_when_out_of_file_descriptors() {
+        let mut h = Harness::new(&[]);
+        let logged = capture_logs(|| {
+            let aborted = io::Error::from_raw_os_error(libc::ECONNABORTED);
+            assert!(h.chat.accept_failed(&aborted));
+            assert!(h.chat.accept_paused.is_none());
+            let emfile = io::Error::from_raw_os_error(libc::EMFILE);
+            assert!(!h.chat.accept_failed(&emfile));
+        });
+        assert_eq!(logged.len(), 2);
+        assert!(logged.iter().all(|(level, _)| *level == log::Level::Warn));
+        let paused = h.chat.accept_paused.unwrap();
+        assert!(paused > Instant::now());
+        assert!(h.chat.poll_timeout().unwrap() <= ACCEPT_BACKOFF);
+        // Nothing is accepted meanwhile, nor does accepting fail
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let _conn = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        accept_clients(&mut h.chat, &listener, Framing::Line, h.poll.registry());
+        assert!(h.chat.clients.is_empty());
+        h.chat.accept_paused = None;
+        accept_clients(&mut h.chat, &listener, Framing::Line, h.poll.registry());
+        assert_eq!(h.chat.clients.len(), 1);
+    }
+
+    #[test]
+    fn every_declared_feature_is_reported() {
+        let manifest = include_str!("../Cargo.toml");
+        let section = manifest.split("[features]").nth(1).unwrap();
+        let declared: Vec<&str> = section
+            .lines()
+            .take_while(|line| !line.starts_with('['))
+            .filter_map(|line| line.split_once(" = "))
+            .map(|(name, _)| name.trim())
+            .collect();
+        let known: Vec<&str> = FEATURES.iter().map(|f| f.name).collect();
+        assert_eq!(declared, known);
+        assert_eq!(
+            features().contains(&"test-transport"),
+            cfg!(feature = "test-transport")
+        );
+    }
+
+    #[test]
+    fn disconnected_clients_are_removed_and_their_token_reused() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.stream(bob).push_eof();
+        h.chat.handle_readable(bob, h.poll.registry());
+        assert!(!h.chat.clients.contains_key(&bob));
+        assert!(h.output(alice).contains("* bob left (connection closed)"));
+        h.chat.tokens.reclaim();
+        assert_eq!(h.connect(), bob);
+    }
+
+    #[test]
+    fn stalled_clients_time_out() {
+        let mut h = Harness::new(&["--send-timeout", "30"]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.stream(bob).block_writes_after(Some(0));
+        h.send(alice, "anyone?");
+        h.chat.expire_stalled(h.poll.registry());
+        assert!(h.chat.clients.contains_key(&bob));
+        let item = h.chat.clients.get_mut(&bob).unwrap().outbox.front_mut();
+        item.unwrap().queued_at -= Duration::from_secs(30);
+        h.chat.expire_stalled(h.poll.registry());
+        assert!(!h.chat.clients.contains_key(&bob));
+        assert!(h.output(alice).contains("* bob left (send timeout)"));
+    }
+
+    #[test]
+    fn version_names_the_build() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        h.send(alice, "/version");
+        let output = h.output(alice);
+        assert!(output.contains(&version()), "{output}");
+        assert!(output.contains(env!("CARGO_PKG_VERSION")));
+    }
+
+    #[test]
+    fn disconnect_reasons_are_told() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        let carol = h.join("carol");
+        h.send(bob, "/quit");
+        assert!(h.output(alice).contains("* bob left (quit)"));
+        h.stream(carol)
+            .fail_writes(Some(io::ErrorKind::ConnectionReset));
+        h.send(alice, "still there?");
+        h.chat.reap(h.poll.registry());
+        assert!(!h.chat.clients.contains_key(&carol));
+        assert!(h
+            .output(alice)
+            .contains("* carol left (error: connection reset)"));
+        let reasons: Vec<&str> = h
+            .chat
+            .departures
+            .iter()
+            .map(|d| d.reason.as_str())
+            .collect();
+        assert_eq!(reasons, ["quit", "error: connection reset"]);
+    }
+
+    #[test]
+    fn lines_past_the_budget_wait_their_turn() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        let lines: String = (0..MAX_LINES_PER_EVENT + 10)
+            .map(|n| format!("line {n}\n"))
+            .collect();
+        h.stream(alice).push_input(lines.as_bytes());
+        h.chat.handle_readable(alice, h.poll.registry());
+        let output = h.output(bob);
+        assert!(output.contains(&format!("line {}", MAX_LINES_PER_EVENT - 1)));
+        assert!(!output.contains(&format!("line {}", MAX_LINES_PER_EVENT)));
+        assert!(h.chat.pending_input.contains(&alice));
+        h.chat.handle_pending_input(h.poll.registry());
+        let output = h.output(bob);
+        assert!(output.contains(&format!("line {}", MAX_LINES_PER_EVENT + 9)));
+        assert!(h.chat.pending_input.is_empty());
+    }
+
+    #[test]
+    fn no_global_room_needs_a_channel() {
+        let mut h = Harness::new(&["--no-global"]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        let carol = h.join("carol");
+        h.send(alice, "hello?");
+        assert!(h.output(alice).contains("join a channel first"));
+        assert!(!h.output(bob).contains("hello?"));
+        h.send(alice, "/join #rust");
+        h.send(bob, "/join #rust");
+        h.send(carol, "/join #go");
+        h.output(alice);
+        h.send(alice, "hello rust");
+        assert!(h.output(bob).contains("alice> hello rust"));
+        assert!(!h.output(carol).contains("hello rust"));
+    }
+
+    #[test]
+    fn long_messages_are_cut_between_characters() {
+        let mut h = Harness::new(&["--max-message-len", "4"]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(alice, "aéééé");
+        let output = h.output(bob);
+        assert!(output.contains("alice> aé\n"), "{output:?}");
+    }
+
+    #[test]
+    fn aliases_expand_to_their_text() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(alice, "/alias gm good morning");
+        assert!(h.output(alice).contains("/gm is now an alias"));
+        h.send(alice, "/gm everyone");
+        assert!(h.output(bob).contains("alice> good morning everyone"));
+        // An alias for a command runs it
+        h.send(alice, "/alias v /version");
+        h.send(alice, "/v");
+        assert!(h.output(alice).contains(env!("CARGO_PKG_VERSION")));
+        // Builtins win over aliases
+        h.send(alice, "/alias nick hijacked");
+        h.send(alice, "/nick alicia");
+        assert!(h.output(alice).contains("nick changed to alicia"));
+        h.send(alice, "/unalias gm");
+        assert!(h.output(alice).contains("/gm removed"));
+        h.send(alice, "/unalias gm");
+        assert!(h.output(alice).contains("no alias /gm"));
+    }
+
+    #[test]
+    fn outbox_stats_count_partial_writes() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.stream(bob).limit_writes(Some(4));
+        h.send(alice, "hello");
+        let stats = &h.chat.clients[&bob].stats;
+        assert_eq!(stats.queued, 0);
+        assert!(stats.partial_writes > 0);
+        assert!(stats.peak > 0);
+        h.send(alice, "/whois bob");
+        let output = h.output(alice);
+        assert!(output.contains("bob (online) in the global room: queued 0 bytes"));
+        h.send(alice, "/stats");
+        assert!(h.output(alice).contains("2 clients, queued 0 bytes"));
+    }
+
+    #[test]
+    fn nick_of_a_departed_user_is_held_for_the_grace() {
+        let mut h = Harness::new(&["--nick-grace", "60"]);
+        let alice = h.join("alice");
+        let other = h.join("other");
+        h.send(alice, "/quit");
+        h.send(other, "/nick alice");
+        assert!(h.output(other).contains("nick reserved, try later"));
+        // Once it runs out, anyone can have it
+        *h.chat.reserved_nicks.get_mut("alice").unwrap() = Instant::now();
+        h.chat.prune_reserved_nicks();
+        h.send(other, "/nick alice");
+        assert!(h.output(other).contains("nick changed to alice"));
+    }
+
+    #[test]
+    fn binary_framing_carries_any_bytes() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.connect();
+        h.send(bob, "/binary");
+        assert!(h.output(bob).contains("binary framing on"));
+        let frame = LengthPrefixedFramer { max_len: 64 }.encode(b"/nick bob");
+        h.stream(bob).push_input(&frame);
+        h.chat.handle_readable(bob, h.poll.registry());
+        h.output(bob);
+        h.send(alice, "hello");
+        let expected = LengthPrefixedFramer { max_len: 64 }.encode(b"alice> hello");
+        assert_eq!(h.stream(bob).take_output(), expected);
+        // Too late once something else was said
+        h.send(alice, "/binary");
+        assert!(h.output(alice).contains("must be requested first thing"));
+    }
+
+    #[test]
+    fn prompt_is_per_client() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        let carol = h.join("carol");
+        h.send(bob, "/setprompt chat%");
+        assert!(h.output(bob).contains("prompt changed"));
+        h.send(alice, "hi");
+        assert!(h.output(bob).ends_with("alice> hi\nchat%"));
+        assert!(h.output(carol).ends_with("alice> hi\n> "));
+        h.send(bob, "/setprompt \x1b[2J");
+        assert!(h.output(bob).contains("without control characters"));
+        h.send(bob, "/setprompt");
+        assert!(h.output(bob).contains("prompt reset"));
+        h.send(alice, "again");
+        assert!(h.output(bob).ends_with("alice> again\n> "));
+    }
+
+    #[test]
+    fn rapid_duplicates_are_suppressed() {
+        let mut h = Harness::new(&["--dedup-window", "1000"]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(alice, "spam");
+        h.send(alice, "spam");
+        assert!(h.output(alice).contains("duplicate message suppressed"));
+        assert_eq!(h.output(bob).matches("alice> spam").count(), 1);
+        h.send(alice, "not spam");
+        assert!(h.output(bob).contains("alice> not spam"));
+        // Past the window, the same words go through again
+        h.send(alice, "spam");
+        let client = h.chat.clients.get_mut(&alice).unwrap();
+        let (hash, at) = client.last_message.unwrap();
+        client.last_message = Some((hash, at - Duration::from_secs(2)));
+        h.send(alice, "spam");
+        assert_eq!(h.output(bob).matches("alice> spam").count(), 2);
+    }
+
+    // A file under the temp dir, unique to this process
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("smallchat-{name}-{}", std::process::id()))
+    }
+
+    #[test]
+    fn motd_is_shown_on_connect_and_on_request() {
+        let path = temp_path("motd");
+        std::fs::write(&path, "Be nice\n").unwrap();
+        let mut h = Harness::new(&["--motd", path.to_str().unwrap()]);
+        let alice = h.connect();
+        assert!(h.output(alice).contains("Be nice"));
+        h.send(alice, "/motd");
+        assert!(h.output(alice).contains("Be nice"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reload_picks_up_a_new_motd() {
+        let path = temp_path("reload");
+        std::fs::write(&path, "Old news\n").unwrap();
+        let mut h = Harness::new(&["--motd", path.to_str().unwrap()]);
+        let alice = h.join("alice");
+        std::fs::write(&path, "Fresh news\n").unwrap();
+        h.chat.reload();
+        h.send(alice, "/motd");
+        assert!(h.output(alice).contains("Fresh news"));
+        // A file that can't be read leaves the MOTD as it was
+        std::fs::remove_file(&path).unwrap();
+        h.chat.reload();
+        h.send(alice, "/motd");
+        assert!(h.output(alice).contains("Fresh news"));
+    }
+
+    #[test]
+    fn readable_and_writable_stops_once_the_client_is_gone() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.stream(bob).block_writes_after(Some(0));
+        h.send(alice, "queued for bob");
+        h.stream(bob).push_input(b"/quit\n");
+        h.chat
+            .handle_client_ready(bob, true, true, h.poll.registry());
+        assert!(!h.chat.clients.contains_key(&bob));
+        // A later event of the same batch for it is ignored
+        h.chat
+            .handle_client_ready(bob, true, true, h.poll.registry());
+        assert!(h.output(alice).contains("* bob left (quit)"));
+    }
+
+    #[test]
+    fn writable_flushes_what_was_stuck() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.stream(bob).block_writes_after(Some(0));
+        h.send(alice, "stuck");
+        h.stream(bob).block_writes_after(None);
+        h.chat
+            .handle_client_ready(bob, false, true, h.poll.registry());
+        assert!(h.output(bob).contains("alice> stuck"));
+        assert!(h.chat.clients[&bob].writable);
+    }
+
+    #[test]
+    fn channel_modes_guard_joins() {
+        let mut h = Harness::new(&[]);
+        let admin = h.join("admin");
+        let bob = h.join("bob");
+        h.chat.admins.insert(admin);
+        h.send(admin, "/join #ops");
+        h.send(admin, "/mode #ops +k sesame");
+        h.send(bob, "/join #ops");
+        assert!(h.output(bob).contains("#ops needs the right key"));
+        h.send(bob, "/join #ops sesame");
+        assert!(h.output(bob).contains("joined #ops"));
+        h.send(bob, "/part");
+        h.send(admin, "/mode #ops -k");
+        h.send(admin, "/mode #ops +i");
+        h.output(bob);
+        h.send(bob, "/join #ops");
+        assert!(h.output(bob).contains("#ops is invite-only"));
+        h.send(admin, "/invite bob #ops");
+        assert!(h.output(bob).contains("admin invited you to #ops"));
+        h.send(bob, "/join #ops");
+        assert!(h.output(bob).contains("joined #ops"));
+    }
+
+    #[test]
+    fn honeypot_holds_output_back() {
+        let mut h = Harness::new(&["--honeypot", "/admin-login"]);
+        let alice = h.join("alice");
+        let bot = h.join("bot");
+        h.send(bot, "/admin-login");
+        // No reply gives the trap away
+        assert_eq!(h.output(bot), "");
+        assert_eq!(h.chat.clients[&bot].tarpit, Some(TARPIT_DELAY));
+        h.send(alice, "hello");
+        assert_eq!(h.output(bot), "");
+        h.chat.release_held();
+        assert_eq!(h.output(bot), "");
+        let item = h.chat.clients.get_mut(&bot).unwrap().outbox.front_mut();
+        item.unwrap().queued_at -= TARPIT_DELAY;
+        h.chat.release_held();
+        assert!(h.output(bot).contains("alice> hello"));
+    }
+
+    #[test]
+    fn raw_mode_is_plain_text() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(bob, "/raw on");
+        h.output(bob);
+        h.send(alice, "\x1b[1mloud\x1b[0m");
+        assert_eq!(h.output(bob), "alice: loud\n");
+        // Nicks that didn't come through /nick are stripped too
+        h.chat
+            .inject_broadcast("\x1b]0;pwned\x07\x1b[31mrelay", "\x1b]0;title\x07hi");
+        assert_eq!(h.output(bob), "relay: hi\n");
+        h.send(bob, "/raw off");
+        h.output(bob);
+        h.send(alice, "again");
+        assert_eq!(h.output(bob), "alice> again\n> ");
+    }
+
+    #[test]
+    fn channels_lists_rooms_with_members() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        let carol = h.join("carol");
+        h.send(alice, "/channels");
+        assert!(h.output(alice).contains("no channels"));
+        h.send(bob, "/join #rust");
+        h.send(carol, "/join #rust");
+        h.send(carol, "/join #go");
+        h.send(alice, "/channels");
+        let output = h.output(alice);
+        assert!(output.contains("#go (1)\n#rust (1)"), "{output}");
+        h.send(bob, "/part");
+        assert!(h.output(bob).contains("left #rust"));
+        // Empty rooms are forgotten
+        assert!(!h.chat.channels.contains_key("#rust"));
+        // Invite-only rooms are hidden from everyone but admins
+        h.chat.channels.get_mut("#go").unwrap().invite_only = true;
+        h.send(alice, "/channels");
+        assert!(h.output(alice).contains("no channels"));
+    }
+
+    #[test]
+    fn write_errors_are_told_apart() {
+        let enobufs = io::Error::from_raw_os_error(libc::ENOBUFS);
+        assert!(is_transient(&enobufs) && !is_disconnect(&enobufs));
+        let epipe = io::Error::from_raw_os_error(libc::EPIPE);
+        assert!(is_disconnect(&epipe) && !is_transient(&epipe));
+        assert!(is_disconnect(&io::ErrorKind::ConnectionReset.into()));
+    }
+
+    #[test]
+    fn broken_pipe_drops_the_client() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.stream(bob).fail_writes(Some(io::ErrorKind::BrokenPipe));
+        h.send(alice, "hello");
+        assert_eq!(h.chat.clients[&bob].failed, Some(io::ErrorKind::BrokenPipe));
+        h.chat.reap(h.poll.registry());
+        assert!(!h.chat.clients.contains_key(&bob));
+        assert!(h.output(alice).contains("* bob left (error: broken pipe)"));
+    }
+
+    #[test]
+    fn only_unexpected_write_errors_are_logged_as_errors() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        let carol = h.join("carol");
+        h.stream(bob)
+            .fail_writes(Some(io::ErrorKind::ConnectionReset));
+        h.stream(carol)
+            .fail_writes(Some(io::ErrorKind::PermissionDenied));
+        let logs = capture_logs(|| h.send(alice, "hello"));
+        let level = |nick: &str| {
+            logs.iter()
+                .find(|(_, line)| line.contains(nick))
+                .map(|(level, _)| *level)
+        };
+        assert_eq!(level("bob"), Some(log::Level::Debug));
+        assert_eq!(level("carol"), Some(log::Level::Error));
+    }
+
+    // A text frame as a browser sends it, masked with zeros
+    fn ws_frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x81, 0x80 | payload.len() as u8, 0, 0, 0, 0];
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn web_client_chats_over_websocket() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let page = h.connect_web();
+        h.stream(page)
+            .push_input(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        h.chat.handle_readable(page, h.poll.registry());
+        assert!(h.output(page).starts_with("HTTP/1.1 200 OK"));
+        assert!(h.chat.clients[&page].closing);
+        let web = h.connect_web();
+        h.stream(web).push_input(
+            b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        );
+        h.chat.handle_readable(web, h.poll.registry());
+        let output = h.output(web);
+        assert!(output.starts_with("HTTP/1.1 101 Switching Protocols"));
+        assert!(output.contains("Welcome to Simple Chat!"));
+        h.stream(web).push_input(&ws_frame(b"/nick web"));
+        h.stream(web).push_input(&ws_frame(b"hi from the browser"));
+        h.chat.handle_readable(web, h.poll.registry());
+        assert!(h.output(alice).contains("web> hi from the browser"));
+        h.output(web);
+        h.send(alice, "hello web");
+        let frame = websocket::encode(websocket::OP_TEXT, b"alice> hello web");
+        assert_eq!(h.stream(web).take_output(), frame);
+    }
+
+    #[test]
+    fn admins_can_rename_others() {
+        let mut h = Harness::new(&[]);
+        let admin = h.join("admin");
+        let bob = h.join("bob");
+        let carol = h.join("carol");
+        h.send(bob, "/forcenick carol troll");
+        assert!(h.output(bob).contains("permission denied"));
+        h.chat.admins.insert(admin);
+        h.send(admin, "/forcenick carol bob");
+        assert!(h.output(admin).contains("nick already in use"));
+        for bad in ["\x1b[2J", " ", "car ol"] {
+            h.send(admin, &format!("/forcenick carol {bad}"));
+            assert!(h
+                .output(admin)
+                .contains("invalid nick, no spaces or control characters"));
+        }
+        assert_eq!(h.chat.clients[&carol].nick, "carol");
+        assert!(!h.output(bob).contains("known as"));
+        h.send(admin, "/forcenick carol caroline");
+        assert!(h.output(admin).contains("renamed carol to caroline"));
+        assert!(h
+            .output(carol)
+            .contains("your nick was changed by an admin to caroline"));
+        assert!(h.output(bob).contains("* carol is now known as caroline"));
+        assert_eq!(h.chat.clients[&carol].nick, "caroline");
+    }
+
+    #[test]
+    fn json_requests_get_responses() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let json = h.join("json");
+        h.send(json, "/json");
+        h.output(json);
+        h.send(json, r#"{"id":1,"method":"nick","params":{"nick":"bob"}}"#);
+        let output = h.output(json);
+        assert!(
+            output.contains(r#""id":1,"result":"nick changed to bob""#),
+            "{output}"
+        );
+        assert_eq!(h.chat.clients[&json].nick, "bob");
+        // A nick can't forge lines for the clients it's shown to
+        for (id, nick) in [(4, r"a\nb"), (5, ""), (6, r"a\u001b[2Jb"), (7, "a b")] {
+            h.send(
+                json,
+                &format!(r#"{{"id":{id},"method":"nick","params":{{"nick":"{nick}"}}}}"#),
+            );
+            let output = h.output(json);
+            assert!(
+                output.contains(&format!(r#""id":{id},"error""#)),
+                "{output}"
+            );
+        }
+        assert_eq!(h.chat.clients[&json].nick, "bob");
+        h.send(json, r#"{"id":2,"method":"nope"}"#);
+        assert!(h.output(json).contains(r#""id":2,"error":{"code":-32601"#));
+        h.send(json, r#"{"id":3,"method":"nick"}"#);
+        assert!(h.output(json).contains(r#""id":3,"error":{"code":-32602"#));
+        h.send(json, "{oops");
+        assert!(h
+            .output(json)
+            .contains(r#""id":null,"error":{"code":-32700"#));
+        h.send(alice, "hello");
+        let output = h.output(json);
+        assert!(output.contains(r#""nick":"alice""#), "{output}");
+        assert!(output.contains(r#""text":"hello""#), "{output}");
+    }
+
+    #[test]
+    fn failed_welcome_leaves_no_client() {
+        let mut h = Harness::new(&[]);
+        let alice = h.connect();
+        let ip: IpAddr = [10, 0, 0, 1].into();
+        let mut broken = MemoryStream::new();
+        broken.fail_writes(Some(io::ErrorKind::BrokenPipe));
+        assert_eq!(h.connect_stream(broken, ip), None);
+        assert_eq!(h.chat.clients.len(), 1);
+        assert!(!h.chat.connections_per_ip.contains_key(&ip));
+        // Its token goes to the next client, which is welcomed as usual
+        h.chat.tokens.reclaim();
+        let bob = h.connect();
+        assert_eq!(bob, Token(alice.0 + 1));
+        assert!(!h.chat.clients[&bob].write_interest);
+        assert!(!h.output(bob).is_empty());
+    }
+
+    #[test]
+    fn writable_interest_only_while_output_is_stuck() {
+        let mut h = Harness::new(&[]);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _peer = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (conn, addr) = listener.accept().unwrap();
+        conn.set_nonblocking(true).unwrap();
+        let conn = Stream::Tcp(mio::net::TcpStream::from_std(conn));
+        let registry = h.poll.registry();
+        let token = h
+            .chat
+            .add_client(conn, addr, Framing::Line, registry)
+            .unwrap();
+        let mut events = Events::with_capacity(16);
+        let mut poll_writable = |h: &mut Harness| {
+            h.poll
+                .poll(&mut events, Some(Duration::from_millis(50)))
+                .unwrap();
+            events.iter().any(|e| e.token() == token && e.is_writable())
+        };
+        // The welcome went out right away, so an idle client isn't polled
+        // for writability
+        assert!(!h.chat.clients[&token].write_interest);
+        assert!(!poll_writable(&mut h));
+        let client = h.chat.clients.get_mut(&token).unwrap();
+        client.writable = false;
+        client.reply("stuck");
+        h.chat.update_interests(h.poll.registry());
+        assert!(h.chat.clients[&token].write_interest);
+        assert!(poll_writable(&mut h));
+        h.chat
+            .handle_client_ready(token, false, true, h.poll.registry());
+        h.chat.update_interests(h.poll.registry());
+        assert!(!h.chat.clients[&token].write_interest);
+        assert!(!poll_writable(&mut h));
+    }
+
+    #[test]
+    fn status_is_broadcast_and_shown_by_whois() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(bob, "/status busy in a \x07meeting");
+        assert!(h.output(bob).contains("you are now busy: in a meeting"));
+        assert!(h.output(alice).contains("* bob is now busy: in a meeting"));
+        h.send(alice, "/whois bob");
+        assert!(h
+            .output(alice)
+            .contains("bob (busy: in a meeting) in the global room"));
+        h.send(bob, "/status asleep");
+        assert!(h.output(bob).contains("usage: /status"));
+        h.send(bob, "/status online");
+        assert!(h.output(alice).contains("* bob is now online"));
+    }
+
+    #[test]
+    fn reactions_reach_json_clients() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let json = h.join("json");
+        h.send(json, "/json");
+        h.output(json);
+        h.send(alice, "lunch?");
+        let output = h.output(json);
+        let notification = json::parse(output.trim()).unwrap();
+        let params = notification.get("params").unwrap();
+        let id = params.get("msgid").and_then(Value::as_u64).unwrap();
+        h.send(alice, &format!("/react {id} 👍"));
+        assert!(h.output(alice).contains("reaction sent"));
+        let output = h.output(json);
+        assert!(output.contains(r#""method":"reaction""#), "{output}");
+        assert!(
+            output.contains(&format!(r#""msgid":{id},"emoji":"👍","nick":"alice""#)),
+            "{output}"
+        );
+        h.send(alice, &format!("/react {} 👍", id + 1));
+        assert!(h
+            .output(alice)
+            .contains(&format!("no recent message {}", id + 1)));
+        let long = "x".repeat(MAX_REACTION_LEN + 1);
+        h.send(alice, &format!("/react {id} {long}"));
+        assert!(h.output(alice).contains("usage: /react"));
+        assert_eq!(h.output(json), "");
+    }
+
+    #[test]
+    fn egress_is_shaped_over_several_wakes() {
+        let mut h = Harness::new(&["--egress-rate", "10000"]);
+        let alice = h.join("alice");
+        let text = "x".repeat(15_000);
+        let started = Instant::now();
+        h.chat.inject_system(&text);
+        let mut sent = h.output(alice).len();
+        assert!(sent <= 10_000, "{sent} bytes sent at once");
+        let mut wakes = 0;
+        while let Some(at) = h.chat.clients[&alice].release_at() {
+            std::thread::sleep(at.saturating_duration_since(Instant::now()));
+            h.chat.release_held();
+            sent += h.output(alice).len();
+            wakes += 1;
+        }
+        assert!(sent > text.len());
+        assert!(wakes > 1);
+        assert!(started.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[test]
+    fn seen_tells_online_offline_and_unknown() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(alice, "/seen bob");
+        assert!(h.output(alice).contains("bob is online"));
+        h.send(bob, "/quit");
+        *h.chat.last_seen.get_mut("bob").unwrap() -= Duration::from_secs(180);
+        h.send(alice, "/seen bob");
+        assert!(h.output(alice).contains("bob was last seen 3m ago"));
+        h.send(alice, "/seen carol");
+        assert!(h.output(alice).contains("never seen carol"));
+    }
+
+    #[test]
+    fn seen_forgets_the_oldest_past_the_cap() {
+        let mut h = Harness::new(&[]);
+        h.chat.mark_seen("first");
+        *h.chat.last_seen.get_mut("first").unwrap() -= Duration::from_secs(1);
+        for n in 1..MAX_SEEN {
+            h.chat.mark_seen(&format!("nick{n}"));
+        }
+        h.chat.mark_seen("one more");
+        assert_eq!(h.chat.last_seen.len(), MAX_SEEN);
+        assert!(!h.chat.last_seen.contains_key("first"));
+    }
+
+    #[test]
+    fn laggards_are_evicted_and_their_buffers_freed() {
+        let mut h = Harness::new(&["--max-retained", "1000"]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.chat.clients.get_mut(&bob).unwrap().connected_at -= WELCOME_GRACE;
+        h.stream(bob).block_writes_after(Some(0));
+        for n in 0..20 {
+            h.send(alice, &format!("message {n} {}", "x".repeat(100)));
+        }
+        assert!(h.chat.clients[&bob].retained() <= 1000);
+        h.chat.evict_laggards(h.poll.registry());
+        assert!(!h.chat.clients.contains_key(&bob));
+        assert!(h.output(alice).contains("* bob left (lagging behind)"));
+        // Nothing is kept alive for it any longer
+        assert_eq!(h.chat.queued_total.get(), 0);
+    }
+
+    #[test]
+    fn maintenance_pauses_all_but_admins() {
+        let mut h = Harness::new(&[]);
+        let admin = h.join("admin");
+        let bob = h.join("bob");
+        h.chat.admins.insert(admin);
+        h.send(bob, "/maintenance on");
+        assert!(h.output(bob).contains("permission denied"));
+        h.send(admin, "/maintenance on");
+        assert!(h.output(bob).contains("* server in maintenance"));
+        h.output(admin);
+        h.send(bob, "anyone?");
+        assert!(h.output(bob).contains("server in maintenance"));
+        assert!(!h.output(admin).contains("anyone?"));
+        h.send(admin, "back in five");
+        assert!(h.output(bob).contains("admin> back in five"));
+        h.send(admin, "/maintenance off");
+        assert!(h.output(bob).contains("* maintenance over"));
+        h.send(bob, "anyone?");
+        assert!(h.output(admin).contains("bob> anyone?"));
+    }
+
+    #[test]
+    fn crlf_clients_get_crlf_lines() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let crlf = h.join("crlf");
+        h.send(crlf, "/crlf on");
+        h.output(crlf);
+        h.send(alice, "one\ttwo");
+        assert!(h.output(crlf).starts_with("alice> one\ttwo\r\n"));
+        h.send(crlf, "hi");
+        assert!(h.output(alice).starts_with("crlf> hi\n"));
+        h.send(crlf, "/crlf off");
+        h.output(crlf);
+        h.send(alice, "three");
+        assert!(h.output(crlf).starts_with("alice> three\n"));
+
+        let mut h = Harness::new(&["--crlf-out"]);
+        let alice = h.connect();
+        let output = h.output(alice);
+        assert!(!output.is_empty());
+        assert!(!output.replace("\r\n", "").contains('\n'), "{output:?}");
+    }
+
+    #[test]
+    fn oper_checks_the_configured_backend() {
+        let mut h = Harness::new(&["--admin-password", "hunter2"]);
+        let alice = h.join("alice");
+        h.send(alice, "/oper hunter3");
+        assert!(h.output(alice).contains("permission denied"));
+        assert!(!h.chat.admins.contains(&alice));
+        h.send(alice, "/oper hunter2");
+        assert!(h.output(alice).contains("you are now an admin"));
+        assert!(h.chat.admins.contains(&alice));
+
+        let path = temp_path("users");
+        std::fs::write(&path, format!("bob:{}\n", hex(&hmac::sha256(b"hunter2")))).unwrap();
+        let mut h = Harness::new(&["--admin-users", path.to_str().unwrap()]);
+        let bob = h.join("bob");
+        h.send(bob, "/oper alice hunter2");
+        assert!(h.output(bob).contains("permission denied"));
+        h.send(bob, "/oper bob hunter2");
+        assert!(h.output(bob).contains("you are now an admin"));
+        assert_eq!(h.chat.clients[&bob].oper_user.as_deref(), Some("bob"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn topic_history_and_undo() {
+        let mut h = Harness::new(&[]);
+        let admin = h.join("admin");
+        let bob = h.join("bob");
+        h.chat.admins.insert(admin);
+        for token in [admin, bob] {
+            h.send(token, "/join #room");
+        }
+        h.send(admin, "/topic first");
+        h.send(bob, "/topic second");
+        h.send(bob, "/topic third");
+        h.output(admin);
+        h.send(bob, "/topic history");
+        let output = h.output(bob);
+        let third = output.find("third (set by bob").unwrap();
+        let second = output.find("second (set by bob").unwrap();
+        let first = output.find("first (set by admin").unwrap();
+        assert!(third < second && second < first, "{output}");
+        h.send(bob, "/topic undo");
+        assert!(h.output(bob).contains("permission denied"));
+        h.send(admin, "/topic undo");
+        assert!(h.output(bob).contains("* admin restored the topic: second"));
+        h.send(admin, "/topic undo");
+        h.send(admin, "/topic undo");
+        assert!(h.output(admin).contains("no earlier topic"));
+        h.send(bob, "/topic");
+        assert!(h.output(bob).contains("topic: first"));
+    }
+
+    #[test]
+    fn large_item_drains_over_many_partial_writes() {
+        let mut h = Harness::new(&[]);
+        let alice = h.connect();
+        h.output(alice);
+        h.stream(alice).limit_writes(Some(1000));
+        let data: Vec<u8> = (0..50_000).map(|i| (i % 251) as u8).collect();
+        let client = h.chat.clients.get_mut(&alice).unwrap();
+        client.stats = Default::default();
+        client.writable = false;
+        client.write(data.clone());
+        client.writable = true;
+        assert!(matches!(client.try_flush(), FlushStatus::Drained));
+        // The last write takes exactly what's left
+        assert_eq!(client.stats.partial_writes, 49);
+        assert_eq!((client.stats.queued, client.stats.retained), (0, 0));
+        assert!(client.outbox.is_empty());
+        assert_eq!(h.stream(alice).take_output(), data);
+    }
+
+    #[test]
+    fn default_nicks_follow_the_scheme() {
+        let mut h = Harness::new(&["--default-nick", "prefix:anon"]);
+        let first = h.connect();
+        assert_eq!(h.chat.clients[&first].nick, "anon1");
+        h.send(first, "/nick anon2");
+        let second = h.connect();
+        assert_eq!(h.chat.clients[&second].nick, "anon3");
+    }
+
+    #[test]
+    fn set_changes_take_effect_right_away() {
+        let mut h = Harness::new(&[]);
+        let admin = h.join("admin");
+        let bob = h.join("bob");
+        h.send(bob, "/set max-message-len 5");
+        assert!(h.output(bob).contains("permission denied"));
+        h.chat.admins.insert(admin);
+        h.send(admin, "/set max-message-len 5");
+        assert!(h.output(admin).contains("max-message-len = 5"));
+        h.send(admin, "/set max-message-len lots");
+        assert!(h
+            .output(admin)
+            .contains("invalid value for max-message-len: lots"));
+        h.send(admin, "/set motd hi");
+        assert!(h.output(admin).contains("no setting motd"));
+        h.send(admin, "/get max-message-len");
+        assert!(h.output(admin).contains("max-message-len = 5"));
+        h.send(bob, "hello there");
+        let output = h.output(admin);
+        assert!(output.contains("bob> hello\n"), "{output}");
+    }
+
+    #[test]
+    fn memory_cap_evicts_the_largest_outbox_first() {
+        let mut h = Harness::new(&["--max-total-queued", "1000"]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        let carol = h.join("carol");
+        let text = "x".repeat(290);
+        h.stream(bob).block_writes_after(Some(0));
+        h.send(alice, &text);
+        h.stream(carol).block_writes_after(Some(0));
+        h.send(alice, &text);
+        h.chat.enforce_memory_cap(h.poll.registry());
+        assert_eq!(h.chat.clients.len(), 3);
+        h.send(alice, &text);
+        assert!(h.chat.queued_total.get() > 1000);
+        h.chat.enforce_memory_cap(h.poll.registry());
+        assert!(!h.chat.clients.contains_key(&bob));
+        assert!(h.chat.clients.contains_key(&carol));
+        assert!(h.chat.queued_total.get() <= 1000);
+    }
+
+    #[test]
+    fn fanout_is_spread_over_passes_in_order() {
+        let mut h = Harness::new(&["--fanout-batch", "2", "--max-per-ip", "10"]);
+        let alice = h.join("alice");
+        let listeners: Vec<Token> = (0..5).map(|n| h.join(&format!("user{n}"))).collect();
+        h.chat.deliver_fanout(usize::MAX);
+        for &token in &listeners {
+            h.output(token);
+        }
+        h.send(alice, "one");
+        h.send(alice, "two");
+        let mut received: HashMap<Token, String> = HashMap::new();
+        let mut passes = 0;
+        while !h.chat.fanout.is_empty() {
+            let before: usize = received.values().map(String::len).sum();
+            for &token in &listeners {
+                received
+                    .entry(token)
+                    .or_default()
+                    .push_str(&h.output(token));
+            }
+            let after: usize = received.values().map(String::len).sum();
+            // A pass hands each broadcast to two clients at most
+            assert!(after - before <= 2 * "alice> one\n".len() + 2 * "> ".len());
+            h.chat.deliver_fanout(2);
+            passes += 1;
+        }
+        assert!(passes >= 4, "{passes} passes");
+        for &token in &listeners {
+            received
+                .entry(token)
+                .or_default()
+                .push_str(&h.output(token));
+            let output = &received[&token];
+            let one = output.find("alice> one").unwrap();
+            let two = output.find("alice> two").unwrap();
+            assert!(one < two, "{output}");
+        }
+    }
+
+    #[test]
+    fn buffers_report_a_partial_line() {
+        let mut h = Harness::new(&[]);
+        let admin = h.join("admin");
+        let bob = h.join("bob");
+        h.send(admin, "/buffers");
+        assert!(h.output(admin).contains("permission denied"));
+        h.chat.admins.insert(admin);
+        h.stream(bob).push_input(b"half a li");
+        h.chat.handle_readable(bob, h.poll.registry());
+        h.send(admin, "/buffers");
+        let output = h.output(admin);
+        assert!(
+            output.contains(&format!("bob: read buffer 9/{BUFLEN}, outbox 0 items")),
+            "{output}"
+        );
+        // Past a whole buffer the line is dropped, and so is its tail
+        h.stream(bob).push_input(&[b'x'; BUFLEN]);
+        h.chat.handle_readable(bob, h.poll.registry());
+        h.send(admin, "/buffers");
+        let output = h.output(admin);
+        assert!(
+            output.contains(&format!(
+                "bob: read buffer 9/{BUFLEN} (discarding a long line)"
+            )),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn strict_commands_refuse_unknown_ones() {
+        let mut h = Harness::new(&["--strict-commands"]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(alice, "/foo bar");
+        assert!(h
+            .output(alice)
+            .contains("unknown command /foo, use //foo to say it"));
+        assert!(!h.output(bob).contains("foo"));
+        h.send(alice, "//foo bar");
+        assert!(h.output(bob).contains("alice> /foo bar"));
+
+        // Without it, an unknown command is said as is
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(alice, "/foo bar");
+        assert!(h.output(bob).contains("alice> /foo bar"));
+    }
+
+    #[test]
+    fn pins_are_shown_on_join_until_unpinned() {
+        let mut h = Harness::new(&[]);
+        let admin = h.join("admin");
+        let bob = h.join("bob");
+        h.chat.admins.insert(admin);
+        h.send(admin, "/join #room");
+        h.send(admin, "/pin Read the rules first");
+        assert!(h
+            .output(admin)
+            .contains("* admin pinned: Read the rules first"));
+        h.send(bob, "/join #room");
+        assert!(h.output(bob).contains("pinned: Read the rules first"));
+        h.send(bob, "/pin mine now");
+        assert!(h.output(bob).contains("permission denied"));
+        h.send(bob, "/pinned");
+        assert!(h.output(bob).contains("pinned: Read the rules first"));
+        h.send(admin, "/unpin");
+        assert!(h.output(bob).contains("* admin unpinned the message"));
+        h.send(bob, "/pinned");
+        assert!(h.output(bob).contains("nothing pinned in #room"));
+        h.send(bob, "/part");
+        h.send(bob, "/join #room");
+        assert!(!h.output(bob).contains("pinned:"));
+    }
+
+    thread_local! {
+        static LOGGED: std::cell::RefCell<Option<Vec<(log::Level, String)>>> =
+            const { std::cell::RefCell::new(None) };
+    }
+
+    // Keeps what's logged on the thread of a `capture_logs` call
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+        fn log(&self, record: &log::Record) {
+            LOGGED.with(|logged| {
+                if let Some(logged) = logged.borrow_mut().as_mut() {
+                    logged.push((record.level(), record.args().to_string()));
+                }
+            });
+        }
+        fn flush(&self) {}
+    }
+
+    // Runs `f`, returning the records it logged
+    fn capture_logs(f: impl FnOnce()) -> Vec<(log::Level, String)> {
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&CaptureLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        LOGGED.with(|logged| *logged.borrow_mut() = Some(Vec::new()));
+        f();
+        LOGGED.with(|logged| logged.borrow_mut().take().unwrap())
+    }
+
+    #[test]
+    fn connect_logs_are_sampled() {
+        let connects = |args: &[&str]| {
+            let mut h = Harness::new(args);
+            let logged = capture_logs(|| {
+                for n in 0..10 {
+                    h.connect_from([10, 0, 0, n].into()).unwrap();
+                }
+            });
+            let count = |level| {
+                logged
+                    .iter()
+                    .filter(|(l, text)| *l == level && text.starts_with("Connected client"))
+                    .count()
+            };
+            (count(log::Level::Info), count(log::Level::Debug))
+        };
+        assert_eq!(connects(&[]), (10, 0));
+        assert_eq!(connects(&["--log-connects", "1/5"]), (2, 8));
+    }
+
+    #[test]
+    fn repeated_events_in_a_batch_read_once() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        let lines: String = (0..MAX_LINES_PER_EVENT * 3)
+            .map(|n| format!("line {n}\n"))
+            .collect();
+        h.stream(alice).push_input(lines.as_bytes());
+        h.chat
+            .handle_client_ready(alice, true, false, h.poll.registry());
+        // Reported again, it waits for its turn in `handle_pending_input`
+        h.chat
+            .handle_client_ready(alice, true, false, h.poll.registry());
+        let output = h.output(bob);
+        assert!(!output.contains(&format!("line {}", MAX_LINES_PER_EVENT)));
+        // Once its writes failed, it isn't read until it's dropped
+        h.stream(bob)
+            .fail_writes(Some(io::ErrorKind::ConnectionReset));
+        h.send(alice, "fails");
+        h.stream(bob).push_input(b"hello\n");
+        h.chat
+            .handle_client_ready(bob, true, false, h.poll.registry());
+        h.chat.reap(h.poll.registry());
+        assert!(!h.chat.clients.contains_key(&bob));
+        assert!(!h.output(alice).contains("bob> hello"));
+        h.chat
+            .handle_client_ready(bob, true, true, h.poll.registry());
+    }
+
+    #[test]
+    fn urgent_messages_jump_the_backlog() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        // The first message is cut short, then the socket is full
+        h.stream(bob).limit_writes(Some(10));
+        h.stream(bob).block_writes_after(Some(1));
+        for n in 1..=3 {
+            h.send(alice, &format!("message {n}"));
+        }
+        h.chat.inject_system("* announcement");
+        h.chat.inject_system("* another one");
+        h.stream(bob).limit_writes(None);
+        h.stream(bob).block_writes_after(None);
+        let client = h.chat.clients.get_mut(&bob).unwrap();
+        client.writable = true;
+        client.try_flush();
+        assert_eq!(
+            h.output(bob),
+            "alice> message 1\n> * announcement\n> * another one\n> \
+             alice> message 2\n> alice> message 3\n> "
+        );
+    }
+
+    #[test]
+    fn complete_matches_nick_prefixes() {
+        let mut h = Harness::new(&["--max-per-ip", "100"]);
+        let alice = h.join("alice");
+        for nick in ["Bob", "bobby", "carol", "BOBO"] {
+            h.join(nick);
+        }
+        h.output(alice);
+        h.send(alice, "/complete bO");
+        assert!(h.output(alice).starts_with("Bob bobby BOBO\n"));
+        h.send(alice, "/complete");
+        assert!(h.output(alice).starts_with("alice Bob bobby BOBO carol\n"));
+        h.send(alice, "/complete dave");
+        assert!(h.output(alice).starts_with("no matches\n"));
+        for n in 0..MAX_COMPLETIONS {
+            h.join(&format!("zed{n:02}"));
+        }
+        h.output(alice);
+        h.send(alice, "/complete zed");
+        let output = h.output(alice);
+        let line = output.lines().next().unwrap();
+        assert_eq!(line.split(' ').count(), MAX_COMPLETIONS);
+        h.join("zed");
+        h.output(alice);
+        h.send(alice, "/complete zed");
+        assert!(h.output(alice).starts_with("zed zed00 "));
+    }
+
+    #[test]
+    fn require_nick_holds_chat_until_a_nick_is_set() {
+        let mut h = Harness::new(&["--require-nick"]);
+        let alice = h.join("alice");
+        let guest = h.connect();
+        h.output(guest);
+        h.send(guest, "hi all");
+        assert!(h.output(guest).contains("set a nick with /nick first"));
+        assert!(!h.output(alice).contains("hi all"));
+        h.send(guest, "/nick bob");
+        h.send(guest, "hi all");
+        assert!(h.output(alice).contains("bob> hi all"));
+    }
+
+    #[test]
+    fn each_flush_outcome_is_acted_on() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.stream(bob).block_writes_after(Some(0));
+        h.send(alice, "one");
+        // Would block: kept, waiting for the next WRITABLE event
+        h.chat
+            .handle_client_ready(bob, false, true, h.poll.registry());
+        assert!(!h.chat.clients[&bob].writable);
+        assert!(!h.chat.clients[&bob].outbox.is_empty());
+        // Drained
+        h.stream(bob).block_writes_after(None);
+        h.chat
+            .handle_client_ready(bob, false, true, h.poll.registry());
+        assert!(h.chat.clients[&bob].outbox.is_empty());
+        assert!(h.output(bob).contains("alice> one"));
+        // Fatal: disconnected right away, the way any client leaves
+        h.stream(bob).block_writes_after(Some(0));
+        h.send(alice, "two");
+        h.stream(bob)
+            .fail_writes(Some(io::ErrorKind::ConnectionReset));
+        h.chat
+            .handle_client_ready(bob, false, true, h.poll.registry());
+        assert!(!h.chat.clients.contains_key(&bob));
+        assert!(h
+            .output(alice)
+            .contains("* bob left (error: connection reset)"));
+    }
+
+    #[test]
+    fn fortunes_come_from_the_file_and_are_rate_limited() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        h.send(alice, "/fortune");
+        assert!(h.output(alice).contains("fortunes are disabled"));
+
+        let path = temp_path("fortunes");
+        std::fs::write(&path, "Fortune one\n\n  Fortune two  \nFortune three\n").unwrap();
+        let mut h = Harness::new(&["--fortunes", path.to_str().unwrap()]);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            h.chat.fortunes,
+            ["Fortune one", "Fortune two", "Fortune three"]
+        );
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        for _ in 0..20 {
+            h.chat.clients.get_mut(&alice).unwrap().last_fortune = None;
+            h.send(alice, "/fortune");
+            let output = h.output(bob);
+            let fortune = output
+                .lines()
+                .find_map(|line| line.strip_prefix("* alice draws a fortune: "))
+                .unwrap();
+            assert!(h.chat.fortunes.iter().any(|f| f == fortune), "{fortune}");
+        }
+        h.send(alice, "/fortune");
+        assert!(h
+            .output(alice)
+            .contains("wait a little before the next fortune"));
+    }
+
+    #[test]
+    fn founder_is_greeted_and_replaced_by_the_oldest() {
+        let mut h = Harness::new(&["--founder-greeting", "Welcome, {nick}", "--founder-admin"]);
+        let alice = h.connect();
+        assert_eq!(h.chat.founder, Some(alice));
+        assert!(h.chat.admins.contains(&alice));
+        let nick = h.chat.clients[&alice].nick.clone();
+        assert!(h.output(alice).contains(&format!("Welcome, {nick}")));
+        h.send(alice, "/nick alice");
+        let bob = h.join("bob");
+        let carol = h.join("carol");
+        h.chat.clients.get_mut(&bob).unwrap().connected_at -= Duration::from_secs(1);
+        assert!(!h.output(bob).contains("Welcome"));
+        assert!(!h.chat.admins.contains(&bob));
+        h.send(alice, "/quit");
+        assert_eq!(h.chat.founder, Some(bob));
+        assert!(h.chat.admins.contains(&bob));
+        assert!(h.output(bob).contains("Welcome, bob"));
+        assert!(!h.output(carol).contains("Welcome"));
+
+        // Without either option there's no founder
+        let mut h = Harness::new(&[]);
+        let alice = h.connect();
+        assert_eq!(h.chat.founder, None);
+        assert!(!h.chat.admins.contains(&alice));
+    }
+
+    #[test]
+    fn replies_carry_their_parent_id() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        for token in [alice, bob] {
+            h.send(token, "/json");
+            h.output(token);
+        }
+        h.send(
+            alice,
+            r#"{"id":1,"method":"message","params":{"text":"lunch?"}}"#,
+        );
+        let response = json::parse(h.output(alice).trim()).unwrap();
+        let parent = response
+            .get("result")
+            .and_then(|r| r.get("msgid"))
+            .and_then(Value::as_u64)
+            .unwrap();
+        h.output(bob);
+        let reply = format!(
+            r#"{{"id":2,"method":"message","params":{{"text":"sure","reply_to":{parent}}}}}"#
+        );
+        h.send(bob, &reply);
+        assert!(h.output(bob).contains(r#""id":2,"result":{"msgid":"#));
+        let output = h.output(alice);
+        assert!(
+            output.contains(&format!(
+                r#""text":"sure","msgid":{},"reply_to":{parent}"#,
+                parent + 1
+            )),
+            "{output}"
+        );
+        let reply = format!(
+            r#"{{"id":3,"method":"message","params":{{"text":"what?","reply_to":{}}}}}"#,
+            parent + 100
+        );
+        h.send(bob, &reply);
+        let output = h.output(bob);
+        assert!(output.contains(r#""id":3,"error""#), "{output}");
+        assert!(output.contains(&format!("no recent message {}", parent + 100)));
+        assert_eq!(h.output(alice), "");
+    }
+
+    #[test]
+    fn latin1_input_is_broadcast_as_utf8() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(bob, "/encoding latin1");
+        assert!(h.output(bob).contains("input encoding set to latin1"));
+        h.stream(bob).push_input(b"caf\xe9 cr\xe8me\n");
+        h.chat.handle_readable(bob, h.poll.registry());
+        assert!(h.output(alice).contains("bob> café crème\n"));
+        h.send(bob, "/encoding ebcdic");
+        assert!(h.output(bob).contains("usage: /encoding"));
+    }
+
+    #[test]
+    fn op_and_deop_hand_over_admin_rights() {
+        let mut h = Harness::new(&["--founder-admin"]);
+        let alice = h.connect();
+        h.send(alice, "/nick alice");
+        let bob = h.join("bob");
+        h.send(bob, "/op bob");
+        assert!(h.output(bob).contains("permission denied"));
+        h.send(alice, "/deop alice");
+        assert!(h.output(alice).contains("can't remove the last operator"));
+        h.send(alice, "/op bob");
+        assert!(h.output(bob).contains("* bob is now an operator"));
+        h.send(bob, "/deop alice");
+        assert!(h.output(alice).contains("* alice is no longer an operator"));
+        h.send(alice, "/maintenance on");
+        assert!(h.output(alice).contains("permission denied"));
+        h.send(bob, "/maintenance on");
+        assert!(h.output(alice).contains("* server in maintenance"));
+        // The last one leaving makes the oldest client an admin
+        h.send(bob, "/quit");
+        assert!(h.chat.admins.contains(&alice));
+        assert!(h.output(alice).contains("* alice is now an operator"));
+    }
+
+    #[test]
+    fn trimmed_messages_keep_inner_spaces_and_blank_ones_go() {
+        let mut h = Harness::new(&["--trim"]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(alice, "  two  spaces\t ");
+        assert!(h.output(bob).contains("alice> two  spaces\n"));
+        h.send(alice, " \t ");
+        assert!(!h.output(bob).contains("alice>"));
+
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(alice, "  indented ");
+        assert!(h.output(bob).contains("alice>   indented \n"));
+    }
+
+    #[test]
+    fn fair_mode_interleaves_bursts() {
+        let mut h = Harness::new(&["--fair"]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        let carol = h.join("carol");
+        for token in [alice, bob] {
+            h.stream(token).push_input(b"one\ntwo\nthree\n");
+        }
+        for token in [alice, bob] {
+            h.chat.handle_readable(token, h.poll.registry());
+        }
+        h.chat.handle_pending_input(h.poll.registry());
+        let said: Vec<String> = h
+            .output(carol)
+            .lines()
+            .map(|line| line.trim_start_matches("> ").to_string())
+            .filter(|line| !line.is_empty())
+            .collect();
+        assert_eq!(
+            said,
+            [
+                "alice> one",
+                "bob> one",
+                "alice> two",
+                "bob> two",
+                "alice> three",
+                "bob> three"
+            ]
+        );
+    }
+
+    // Reads lines from a server until one contains `needle`
+    fn expect_line(reader: &mut io::BufReader<std::net::TcpStream>, needle: &str) {
+        reader
+            .get_ref()
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut line = String::new();
+        while !line.contains(needle) {
+            line.clear();
+            match reader.read_line(&mut line) {
+                Ok(0) => panic!("disconnected waiting for {needle:?}"),
+                Ok(_) => {}
+                Err(e) => panic!("never got {needle:?}: {e}"),
+            }
+        }
+    }
+
+    #[test]
+    fn draining_refuses_new_clients_and_serves_the_others() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = Config {
+            addr,
+            founder_admin: true,
+            heartbeat_interval: None,
+            ..Config::default()
+        };
+        let server = std::thread::spawn(move || serve(config).map_err(|e| e.to_string()));
+        let connect = || {
+            let started = Instant::now();
+            loop {
+                match std::net::TcpStream::connect(addr) {
+                    Ok(stream) => return stream,
+                    Err(_) if started.elapsed() < Duration::from_secs(5) => {
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+                    Err(e) => panic!("can't connect: {e}"),
+                }
+            }
+        };
+        let mut admin = connect();
+        let mut admin_reader = io::BufReader::new(admin.try_clone().unwrap());
+        expect_line(&mut admin_reader, "you are the founder");
+        let mut bob = connect();
+        let mut bob_reader = io::BufReader::new(bob.try_clone().unwrap());
+        bob.write_all(b"/nick bob\n").unwrap();
+        expect_line(&mut bob_reader, "nick changed to bob");
+        admin.write_all(b"/drain\n").unwrap();
+        expect_line(&mut bob_reader, "* server draining");
+        // The listener is closed by the end of that loop pass
+        let started = Instant::now();
+        while std::net::TcpStream::connect(addr).is_ok() {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "still accepting"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        admin.write_all(b"still here\n").unwrap();
+        expect_line(&mut bob_reader, "> still here");
+        // It exits once the last client is gone
+        admin.write_all(b"/quit\n").unwrap();
+        bob.write_all(b"/quit\n").unwrap();
+        assert_eq!(server.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn partial_first_lines_time_out() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let slow = h.connect();
+        h.stream(slow).push_input(b"/nick slo");
+        h.chat.handle_readable(slow, h.poll.registry());
+        let deadline = h.chat.handshake_deadline().unwrap();
+        assert_eq!(
+            deadline,
+            h.chat.clients[&slow].connected_at + Duration::from_secs(10)
+        );
+        h.chat.expire_handshakes(h.poll.registry());
+        assert!(h.chat.clients.contains_key(&slow));
+        h.chat.clients.get_mut(&slow).unwrap().connected_at -= Duration::from_secs(10);
+        // Those done with their first line are left alone
+        h.chat.clients.get_mut(&alice).unwrap().connected_at -= Duration::from_secs(10);
+        h.chat.expire_handshakes(h.poll.registry());
+        assert!(!h.chat.clients.contains_key(&slow));
+        assert!(h.chat.clients.contains_key(&alice));
+        assert!(h.chat.handshake_deadline().is_none());
+    }
+
+    #[test]
+    fn session_ids_are_unique_across_reused_tokens() {
+        let mut h = Harness::new(&["--session-ids"]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        let first = h.chat.clients[&bob].session_id;
+        assert!(first > h.chat.clients[&alice].session_id);
+        h.send(bob, "/quit");
+        h.chat.tokens.reclaim();
+        let again = h.connect();
+        assert_eq!(again, bob);
+        let second = h.chat.clients[&again].session_id;
+        assert!(second > first);
+        h.send(again, "/nick bob");
+        h.send(alice, "/whois bob");
+        assert!(h.output(alice).contains(&format!("bob #{second} (online)")));
+        let logged = capture_logs(|| h.send(again, "/quit"));
+        assert!(logged
+            .iter()
+            .any(|(_, text)| text == &format!("Disconnected bob #{second} (quit)")));
+    }
+
+    #[test]
+    fn channel_messages_reach_only_its_members() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        let carol = h.join("carol");
+        let dave = h.join("dave");
+        for token in [alice, bob, carol] {
+            h.send(token, "/join #room");
+        }
+        for token in [alice, bob, carol, dave] {
+            h.output(token);
+            h.stream(token).block_writes_after(Some(0));
+        }
+        h.chat.push_to_channel(
+            Some("#room"),
+            &Message::system(b"* for the room"),
+            Some(alice),
+        );
+        assert!(h.chat.clients[&alice].outbox.is_empty());
+        assert!(h.chat.clients[&dave].outbox.is_empty());
+        // One buffer, shared by the members it went to
+        let data = |token| h.chat.clients[&token].outbox[0].data.clone();
+        assert!(Rc::ptr_eq(&data(bob), &data(carol)));
+        assert!(data(bob).starts_with(b"* for the room\n"));
+        h.chat
+            .push_to_channel(None, &Message::system(b"* for the global room"), None);
+        assert_eq!(h.chat.clients[&dave].outbox.len(), 1);
+        assert_eq!(h.chat.clients[&bob].outbox.len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn search_finds_recorded_messages() {
+        let mut h = Harness::new(&["--db", ":memory:"]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(alice, "the build is green");
+        h.send(bob, "/search green");
+        assert!(h.output(bob).contains("0s ago alice> the build is green"));
+        h.send(bob, "/search red");
+        assert!(h.output(bob).contains("nothing found for red"));
+    }
+
+    #[test]
+    fn search_needs_a_history() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        h.send(alice, "/search green");
+        assert!(h.output(alice).contains("history is disabled"));
+    }
+
+    #[test]
+    fn lag_reports_what_is_queued() {
+        let mut h = Harness::new(&["--max-retained", "1000"]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(bob, "/lag");
+        assert!(h
+            .output(bob)
+            .contains("0 bytes queued for you in 0 items, 0 of at most 1000"));
+        h.stream(bob).block_writes_after(Some(0));
+        h.send(alice, &"x".repeat(800));
+        let queued = h.chat.clients[&bob].stats.queued;
+        h.send(bob, "/lag");
+        h.stream(bob).block_writes_after(None);
+        let client = h.chat.clients.get_mut(&bob).unwrap();
+        client.writable = true;
+        client.try_flush();
+        let output = h.output(bob);
+        assert!(
+            output.contains(&format!("{queued} bytes queued for you in 1 items")),
+            "{output}"
+        );
+        assert!(output.contains("close to being disconnected"));
+    }
+
+    #[test]
+    fn replayed_send_ids_are_acked_but_not_repeated() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(alice, "/send a1 hello");
+        assert!(h.output(alice).contains("ack a1"));
+        assert!(h.output(bob).contains("alice> hello"));
+        // Reconnected and replaying
+        h.send(alice, "/quit");
+        let alice = h.join("alice");
+        h.send(alice, "/send a1 hello");
+        assert!(h.output(alice).contains("ack a1"));
+        assert!(!h.output(bob).contains("hello"));
+        // The same id from someone else is theirs
+        h.send(bob, "/send a1 hello");
+        assert!(h.output(alice).contains("bob> hello"));
+        // Past the window it's sent again
+        for sent in &mut h.chat.send_ids {
+            sent.at -= h.chat.config.send_id_window;
+        }
+        h.send(alice, "/send a1 hello");
+        assert!(h.output(bob).contains("alice> hello"));
+    }
+
+    #[test]
+    fn remembered_send_ids_are_bounded() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        for n in 0..MAX_SEND_IDS + 10 {
+            h.send(alice, &format!("/send id{n} message {n}"));
+        }
+        assert_eq!(h.chat.send_ids.len(), MAX_SEND_IDS);
+        assert_eq!(h.chat.send_ids[0].client_id, "id10");
+    }
+
+    #[test]
+    fn exempt_nicks_may_burst() {
+        let mut h = Harness::new(&[
+            "--message-rate",
+            "2",
+            "--exempt",
+            "bot-*",
+            "--exempt",
+            "user:feeds",
+            "--admin-password",
+            "secret",
+        ]);
+        let alice = h.join("alice");
+        let bot = h.join("bot-ci");
+        let feeds = h.join("news");
+        h.send(feeds, "/oper feeds secret");
+        for n in 0..5 {
+            for token in [alice, bot, feeds] {
+                h.send(token, &format!("message {n}"));
+            }
+        }
+        for token in [bot, feeds] {
+            assert!(!h.output(token).contains("slow down"));
+        }
+        let output = h.output(alice);
+        assert!(output.contains("slow down, too many messages"));
+        assert!(output.contains("bot-ci> message 4"));
+        assert!(output.contains("news> message 4"));
+    }
+
+    #[test]
+    fn time_replies_with_an_iso_utc_timestamp() {
+        assert_eq!(format_utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_utc(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_utc(1_700_000_000), "2023-11-14T22:13:20Z");
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        h.output(alice);
+        let before = format_utc(unix_time());
+        h.send(alice, "/time");
+        let after = format_utc(unix_time());
+        let out = h.output(alice);
+        let line = out.lines().next().unwrap();
+        let stamp = line
+            .split_whitespace()
+            .find(|word| word.ends_with('Z'))
+            .unwrap();
+        assert!(line.ends_with(&format!("{stamp} (UTC)")), "{line:?}");
+        assert_eq!(stamp.len(), "1970-01-01T00:00:00Z".len());
+        // ISO timestamps sort as the times they stand for
+        assert!(before.as_str() <= stamp && stamp <= after.as_str());
+    }
+
+    #[test]
+    fn broadcasts_evict_slow_recipients_and_reach_fast_ones() {
+        let mut h = Harness::new(&["--max-retained", "1000", "--max-per-ip", "10"]);
+        let alice = h.join("alice");
+        let fast: Vec<Token> = ["bob", "carol"].iter().map(|n| h.join(n)).collect();
+        let slow: Vec<Token> = ["dave", "erin"].iter().map(|n| h.join(n)).collect();
+        for &token in &slow {
+            h.chat.clients.get_mut(&token).unwrap().connected_at -= WELCOME_GRACE;
+            h.stream(token).block_writes_after(Some(0));
+        }
+        for &token in &fast {
+            h.output(token);
+        }
+        for n in 0..20 {
+            h.send(alice, &format!("message {n} {}", "x".repeat(100)));
+        }
+        for &token in &slow {
+            assert!(h.chat.laggards.contains(&token));
+            assert!(h.chat.clients[&token].retained() <= 1000);
+        }
+        // A buffer still queued for a slow client, shared with the others
+        let held = h.chat.clients[&slow[0]].outbox.back().unwrap().data.clone();
+        h.chat.evict_laggards(h.poll.registry());
+        for &token in &slow {
+            assert!(!h.chat.clients.contains_key(&token));
+        }
+        assert_eq!(Rc::strong_count(&held), 1);
+        for &token in &fast {
+            let out = h.output(token);
+            for n in 0..20 {
+                assert!(out.contains(&format!("alice> message {n} ")), "{out}");
+            }
+        }
+        assert_eq!(h.chat.queued_total.get(), 0);
+    }
+
+    #[test]
+    fn line_endings_follow_the_first_line() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let telnet = h.connect();
+        h.send(telnet, "/nick tel\r");
+        let lf = h.connect();
+        h.send(lf, "/nick lf");
+        // Only the first line counts
+        h.send(lf, "/me waves\r");
+        h.output(telnet);
+        h.output(lf);
+        h.send(alice, "hi");
+        assert!(h.output(telnet).starts_with("alice> hi\r\n"));
+        assert!(h.output(lf).starts_with("alice> hi\n"));
+        // The CR isn't part of the line
+        assert_eq!(h.chat.clients[&telnet].nick, "tel");
+        h.output(alice);
+        h.send(telnet, "hello\r");
+        assert!(h.output(alice).starts_with("tel> hello\n"));
+    }
+
+    #[test]
+    fn roster_lists_every_client_as_json() {
+        let mut h = Harness::new(&["--max-per-ip", "10"]);
+        let admin = h.join("admin");
+        let bob = h.join("bob");
+        h.send(bob, "/join #rust");
+        h.join("carol");
+        h.send(bob, "/roster");
+        assert!(h.output(bob).contains("permission denied"));
+        h.chat.admins.insert(admin);
+        h.output(admin);
+        h.send(admin, "/roster");
+        let out = h.output(admin);
+        let start = out.find('[').unwrap();
+        let end = out.rfind(']').unwrap();
+        let Value::Array(roster) = json::parse(&out[start..=end]).unwrap() else {
+            panic!("not an array: {out}");
+        };
+        let mut nicks: Vec<&str> = roster
+            .iter()
+            .map(|c| c.get("nick").and_then(Value::as_str).unwrap())
+            .collect();
+        nicks.sort_unstable();
+        assert_eq!(nicks, ["admin", "bob", "carol"]);
+        let bob = roster
+            .iter()
+            .find(|c| c.get("nick").and_then(Value::as_str) == Some("bob"))
+            .unwrap();
+        assert_eq!(bob.get("channel").and_then(Value::as_str), Some("#rust"));
+        for field in ["addr", "idle", "status"] {
+            assert!(bob.get(field).is_some(), "no {field}");
+        }
+        // JSON clients get it as the result
+        h.send(admin, "/json");
+        h.output(admin);
+        h.send(admin, r#"{"id":7,"method":"roster"}"#);
+        let out = h.output(admin);
+        let response = json::parse(out.lines().next().unwrap()).unwrap();
+        let Some(Value::Array(roster)) = response.get("result") else {
+            panic!("no roster: {out}");
+        };
+        assert_eq!(roster.len(), 3);
+    }
+
+    #[test]
+    fn new_clients_are_not_evicted_during_the_welcome_grace() {
+        let mut h = Harness::new(&["--max-retained", "20"]);
+        let alice = h.join("alice");
+        let mut stream = MemoryStream::new();
+        stream.block_writes_after(Some(0));
+        let new = h.connect_stream(stream, [127, 0, 0, 1].into()).unwrap();
+        for n in 0..5 {
+            h.send(alice, &format!("message {n}"));
+        }
+        let retained = h.chat.clients[&new].retained();
+        assert!(retained > 20);
+        h.chat.evict_laggards(h.poll.registry());
+        assert!(h.chat.clients.contains_key(&new));
+        // Nothing was dropped for it meanwhile
+        assert_eq!(h.chat.clients[&new].retained(), retained);
+        let end = h.chat.welcome_grace_end().unwrap();
+        assert!(end <= h.chat.clients[&new].connected_at + WELCOME_GRACE);
+        h.chat.clients.get_mut(&new).unwrap().connected_at -= WELCOME_GRACE;
+        assert_eq!(h.chat.welcome_grace_end(), None);
+        h.chat.evict_laggards(h.poll.registry());
+        assert!(!h.chat.clients.contains_key(&new));
+    }
+
+    #[test]
+    fn commands_mark_admin_only_and_disabled_ones() {
+        let mut h = Harness::new(&[]);
+        let admin = h.join("admin");
+        let bob = h.join("bob");
+        h.chat.admins.insert(admin);
+        h.send(bob, "/commands");
+        let out = h.output(bob);
+        let lines: Vec<&str> = out.lines().filter(|l| l.starts_with('/')).collect();
+        assert_eq!(lines.len(), COMMANDS.len(), "{out}");
+        assert!(lines.contains(&"/nick"));
+        assert!(lines.contains(&"/roster (admins only)"));
+        assert!(lines.contains(&"/drain (admins only)"));
+        // Without credentials or a history, these can't work here
+        assert!(lines.contains(&"/oper (disabled, no admin credentials)"));
+        assert!(lines.contains(&"/search (disabled, no history)"));
+        h.send(admin, "/commands");
+        let out = h.output(admin);
+        assert!(out.lines().any(|l| l == "/roster (admin)"), "{out}");
+        assert!(!out.contains("admins only"));
+        assert!(!out.contains("after /nick"));
+    }
+
+    #[test]
+    fn commands_mark_those_needing_a_nick_with_require_nick() {
+        let mut h = Harness::new(&["--require-nick"]);
+        let anon = h.connect();
+        h.output(anon);
+        h.send(anon, "/commands");
+        let out = h.output(anon);
+        assert!(out.lines().any(|l| l == "/msg (after /nick)"), "{out}");
+        assert!(out.lines().any(|l| l == "/nick"), "{out}");
+        h.send(anon, "/nick alice");
+        h.output(anon);
+        h.send(anon, "/commands");
+        let out = h.output(anon);
+        assert!(out.lines().any(|l| l == "/msg"), "{out}");
+    }
+
+    #[test]
+    fn caps_are_acked_or_naked_before_chatting() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.connect();
+        h.output(bob);
+        h.send(bob, "CAP telepathy");
+        assert!(h.output(bob).starts_with("NAK telepathy\n"));
+        h.send(bob, "CAP json");
+        assert!(h.output(bob).starts_with("ACK json\n"));
+        assert!(h.chat.clients[&bob].framing == Framing::Json);
+        // Once chatting, a CAP line is just a message
+        h.send(alice, "CAP json");
+        assert!(h.output(bob).contains(r#""text":"CAP json""#));
+        assert!(h.chat.clients[&alice].framing == Framing::Line);
+    }
+
+    #[test]
+    fn only_built_in_negotiated_features_are_acked() {
+        let mut h = Harness::new(&[]);
+        for feature in FEATURES {
+            let client = h.connect();
+            h.output(client);
+            h.send(client, &format!("CAP {}", feature.name));
+            let answer = if feature.built && feature.negotiated {
+                "ACK"
+            } else {
+                "NAK"
+            };
+            let output = h.output(client);
+            assert!(
+                output.starts_with(&format!("{answer} {}\n", feature.name)),
+                "{output}"
+            );
+        }
+    }
+
+    #[cfg(feature = "compress")]
+    #[test]
+    fn compress_cap_deflates_both_ways() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.connect();
+        h.output(bob);
+        let mut deflate = Deflate::new();
+        // What follows the CAP line in the same read is compressed already
+        let mut input = b"CAP compress\n".to_vec();
+        input.extend(deflate.compress(b"/nick bob\n"));
+        h.stream(bob).push_input(&input);
+        h.chat.handle_readable(bob, h.poll.registry());
+        // Inflated, it's taken up on the next pass
+        h.chat.handle_pending_input(h.poll.registry());
+        assert_eq!(h.chat.clients[&bob].nick, "bob");
+        let out = h.stream(bob).take_output();
+        let rest = out.strip_prefix(b"ACK compress\n").unwrap();
+        let mut inflated = Vec::new();
+        deflate.decompress(rest, &mut inflated, 1 << 20).unwrap();
+        assert!(String::from_utf8_lossy(&inflated).contains("nick changed to bob"));
+        h.send(alice, "hi bob");
+        let out = h.stream(bob).take_output();
+        inflated.clear();
+        deflate.decompress(&out, &mut inflated, 1 << 20).unwrap();
+        assert!(inflated.starts_with(b"alice> hi bob\n"));
+        h.output(alice);
+        let data = deflate.compress(b"hi alice\n");
+        h.stream(bob).push_input(&data);
+        h.chat.handle_readable(bob, h.poll.registry());
+        assert!(h.output(alice).starts_with("bob> hi alice\n"));
+    }
+
+    #[cfg(not(feature = "compress"))]
+    #[test]
+    fn compress_cap_needs_the_feature() {
+        let mut h = Harness::new(&[]);
+        let bob = h.connect();
+        h.output(bob);
+        h.send(bob, "CAP compress");
+        assert!(h.output(bob).starts_with("NAK compress\n"));
+    }
+
+    #[test]
+    fn invite_tokens_work_once_and_expire() {
+        let mut h = Harness::new(&["--max-per-ip", "10"]);
+        let admin = h.join("admin");
+        let bob = h.join("bob");
+        let carol = h.join("carol");
+        h.send(bob, "/invite new");
+        assert!(h.output(bob).contains("permission denied"));
+        h.chat.admins.insert(admin);
+        h.send(admin, "/invite new");
+        let out = h.output(admin);
+        let secret = out
+            .split_whitespace()
+            .skip_while(|w| *w != "/login-token")
+            .nth(1)
+            .unwrap()
+            .to_string();
+        h.send(bob, &format!("/login-token {secret}"));
+        assert!(h.output(bob).contains("you are now an admin"));
+        assert!(h.chat.admins.contains(&bob));
+        // Used up
+        h.send(carol, &format!("/login-token {secret}"));
+        assert!(h.output(carol).contains("permission denied"));
+        assert!(!h.chat.admins.contains(&carol));
+        // One past its time is refused, and pruned either way
+        h.send(admin, "/invite new");
+        assert_eq!(h.chat.invite_tokens.len(), 1);
+        let (secret, until) = h.chat.invite_tokens.iter_mut().next().unwrap();
+        *until -= INVITE_TOKEN_TTL;
+        let secret = secret.clone();
+        h.chat.prune_invite_tokens();
+        assert!(h.chat.invite_tokens.is_empty());
+        h.chat
+            .invite_tokens
+            .insert(secret.clone(), Instant::now() - Duration::from_secs(1));
+        h.send(carol, &format!("/login-token {secret}"));
+        assert!(h.output(carol).contains("permission denied"));
+        assert!(h.chat.invite_tokens.is_empty());
+    }
+
+    #[test]
+    fn vectored_writes_span_more_items_than_the_slice_cap() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        h.stream(alice).block_writes_after(Some(0));
+        let mut expected = Vec::new();
+        for n in 0..MAX_IOVECS * 3 {
+            let line = format!("line {n}\n");
+            expected.extend_from_slice(line.as_bytes());
+            h.chat
+                .clients
+                .get_mut(&alice)
+                .unwrap()
+                .write(line.into_bytes());
+        }
+        // Writes stopping partway into an item, a few items in
+        h.stream(alice).block_writes_after(None);
+        h.stream(alice).limit_writes(Some(37));
+        let client = h.chat.clients.get_mut(&alice).unwrap();
+        client.writable = true;
+        assert!(matches!(client.try_flush(), FlushStatus::Drained));
+        h.stream(alice).limit_writes(None);
+        let client = h.chat.clients.get_mut(&alice).unwrap();
+        for n in 0..MAX_IOVECS * 3 {
+            client.write(format!("more {n}\n").into_bytes());
+            expected.extend_from_slice(format!("more {n}\n").as_bytes());
+        }
+        assert!(matches!(client.try_flush(), FlushStatus::Drained));
+        assert_eq!(h.stream(alice).take_output(), expected);
+        assert_eq!(h.stream(alice).most_slices(), MAX_IOVECS);
+    }
+
+    #[test]
+    fn polls_are_voted_on_and_closed_by_their_creator() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(bob, "/vote 1");
+        assert!(h.output(bob).contains("no poll here"));
+        h.send(alice, r#"/poll "Lunch?" pizza sushi"#);
+        assert!(h
+            .output(bob)
+            .contains("* alice started a poll: Lunch? 1) pizza 2) sushi, /vote <n>"));
+        h.send(bob, r#"/poll "Dinner?" a b"#);
+        assert!(h.output(bob).contains("there's already a poll"));
+        h.send(alice, "/vote 1");
+        assert!(h.output(alice).contains("voted for pizza"));
+        h.send(bob, "/vote 1");
+        h.send(bob, "/vote 2");
+        assert!(h.output(bob).contains("vote changed to sushi"));
+        h.send(bob, "/vote 3");
+        assert!(h.output(bob).contains("pick an option from 1 to 2"));
+        h.send(bob, "/poll close");
+        assert!(h.output(bob).contains("permission denied"));
+        h.send(alice, "/poll close");
+        assert!(h
+            .output(bob)
+            .contains("* alice closed the poll: Lunch? pizza: 1, sushi: 1"));
+        h.send(bob, "/poll");
+        assert!(h.output(bob).contains("no poll in the global room"));
+    }
+
+    #[test]
+    fn readline_clears_the_line_before_a_message_and_redraws_the_prompt() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        h.send(bob, "/readline on");
+        assert!(h.output(bob).contains("readline mode on"));
+        h.send(alice, "hi");
+        assert_eq!(h.output(bob), "\r\x1b[Kalice> hi\n> ");
+        // Others still get it plain
+        h.send(bob, "hello");
+        assert_eq!(h.output(alice), "bob> hello\n> ");
+        h.send(bob, "/readline off");
+        h.output(bob);
+        h.send(alice, "bye");
+        assert_eq!(h.output(bob), "alice> bye\n> ");
+    }
+
+    #[test]
+    fn channels_past_the_cap_are_refused_until_one_empties() {
+        let mut h = Harness::new(&["--max-channels", "2"]);
+        let alice = h.join("alice");
+        let bob = h.join("bob");
+        let carol = h.join("carol");
+        h.send(alice, "/join #one");
+        h.send(bob, "/join #two");
+        h.send(carol, "/join #three");
+        assert!(h.output(carol).contains("channel limit reached"));
+        // Existing channels can still be joined
+        h.send(carol, "/join #two");
+        assert!(h.output(carol).contains("joined #two"));
+        h.send(alice, "/part");
+        assert!(!h.chat.channels.contains_key("#one"));
+        h.send(carol, "/join #three");
+        assert!(h.output(carol).contains("joined #three"));
+        assert_eq!(h.chat.channels.len(), 2);
+    }
+
+    #[test]
+    fn reports_reach_only_admins_and_are_rate_limited() {
+        let mut h = Harness::new(&["--max-per-ip", "10"]);
+        let admin = h.join("admin");
+        let bob = h.join("bob");
+        let carol = h.join("carol");
+        let troll = h.join("troll");
+        h.send(carol, "/report troll spam");
+        assert!(h.output(carol).contains("report logged"));
+        h.chat.clients.get_mut(&carol).unwrap().last_report = None;
+        h.chat.admins.insert(admin);
+        h.send(troll, "buy my stuff");
+        h.output(admin);
+        h.output(bob);
+        h.send(carol, "/report troll spamming ads");
+        assert!(h.output(carol).contains("report sent to the admins"));
+        let out = h.output(admin);
+        assert!(
+            out.contains("* carol reported troll in the global room: spamming ads"),
+            "{out}"
+        );
+        // With what was said just before
+        assert!(out.contains("buy my stuff"));
+        assert!(!h.output(bob).contains("reported"));
+        h.send(carol, "/report troll again");
+        assert!(h
+            .output(carol)
+            .contains("wait a little before the next report"));
+        assert!(!h.output(admin).contains("again"));
+        // The cooldown is per reporter
+        h.send(bob, "/report troll rude");
+        assert!(h.output(admin).contains("* bob reported troll"));
+    }
+
+    #[test]
+    fn pending_connections_are_refused_at_shutdown() {
+        let server = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let waiting: Vec<std::net::TcpStream> = (0..3)
+            .map(|_| std::net::TcpStream::connect(addr).unwrap())
+            .collect();
+        let logs = capture_logs(|| refuse_clients(&server));
+        assert!(logs
+            .iter()
+            .any(|(_, line)| line == "Refused 3 pending connections: shutting down"));
+        for mut conn in waiting {
+            conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut text = String::new();
+            conn.read_to_string(&mut text).unwrap();
+            assert_eq!(text, "server shutting down\n");
+        }
+        // Nothing is left waiting
+        assert!(server.accept().is_err());
+    }
+
+    #[test]
+    fn anonymized_ips_are_stable_and_distinct() {
+        let h = Harness::new(&["--anonymize-ips"]);
+        let (a, b): (IpAddr, IpAddr) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let shown = h.chat.show_ip(a, false);
+        assert!(shown.starts_with("ip-") && shown.len() == "ip-".len() + 12);
+        assert_eq!(h.chat.show_ip(a, false), shown);
+        assert_ne!(h.chat.show_ip(b, false), shown);
+        // Admins get the hash too, unless allowed to see them
+        assert_eq!(h.chat.show_ip(a, true), shown);
+        let addr = SocketAddr::new(a, 4242);
+        assert_eq!(h.chat.show_addr(addr), format!("{shown}:4242"));
+        // A new salt each run
+        let other = Harness::new(&["--anonymize-ips"]);
+        assert_ne!(other.chat.show_ip(a, false), shown);
+        let h = Harness::new(&["--anonymize-ips", "--admins-see-ips"]);
+        assert_eq!(h.chat.show_ip(a, true), "10.0.0.1");
+        assert!(h.chat.show_ip(a, false).starts_with("ip-"));
+        let h = Harness::new(&[]);
+        assert_eq!(h.chat.show_ip(a, false), "10.0.0.1");
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn history_limit_is_set_per_room_by_admins() {
+        let mut h = Harness::new(&["--db", ":memory:"]);
+        let admin = h.join("admin");
+        let bob = h.join("bob");
+        h.send(bob, "/history-limit 1");
+        assert!(h.output(bob).contains("permission denied"));
+        h.chat.admins.insert(admin);
+        h.send(admin, "/join #ops");
+        h.send(admin, "/history-limit");
+        assert!(h.output(admin).contains("#ops keeps every message"));
+        for n in 0..3 {
+            h.send(admin, &format!("note {n}"));
+        }
+        h.send(admin, "/history-limit 2");
+        assert!(h
+            .output(admin)
+            .contains("#ops now keeps the latest 2 messages"));
+        h.send(admin, "/search note");
+        let out = h.output(admin);
+        assert!(out.contains("note 2") && out.contains("note 1"), "{out}");
+        assert!(!out.contains("note 0"));
+        h.send(admin, "/history-limit lots");
+        assert!(h.output(admin).contains("usage: /history-limit <messages>"));
+        // The global room keeps its own
+        h.send(admin, "/part");
+        h.send(admin, "/history-limit");
+        assert!(h
+            .output(admin)
+            .contains("the global room keeps every message"));
+    }
+
+    #[test]
+    fn motd_and_topics_are_expanded_for_each_client() {
+        let path = temp_path("motd-template");
+        std::fs::write(&path, "Hi {nick}, {clients} here {oops}\n").unwrap();
+        let mut h = Harness::new(&["--motd", path.to_str().unwrap()]);
+        let alice = h.join("alice");
+        h.send(alice, "/motd");
+        assert!(h.output(alice).contains("Hi alice, 1 here {oops}"));
+        let bob = h.join("bob");
+        h.send(bob, "/motd");
+        assert!(h.output(bob).contains("Hi bob, 2 here {oops}"));
+        h.send(alice, "/join #room");
+        h.send(alice, "/topic welcome {nick}, on v{version}");
+        h.send(bob, "/join #room");
+        let out = h.output(bob);
+        let version = env!("CARGO_PKG_VERSION");
+        assert!(
+            out.contains(&format!("welcome bob, on v{version}")),
+            "{out}"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tokens_of_live_clients_are_skipped() {
+        let mut h = Harness::new(&[]);
+        let alice = h.join("alice");
+        // As if released by mistake while alice is still connected
+        h.chat.tokens.release(alice);
+        h.chat.tokens.reclaim();
+        let mut bob = None;
+        let logs = capture_logs(|| bob = h.connect_from([127, 0, 0, 1].into()));
+        let bob = bob.unwrap();
+        assert_ne!(bob, alice);
+        assert!(logs.contains(&(
+            log::Level::Warn,
+            format!("Token {} is still in use, skipping it", alice.0)
+        )));
+        assert_eq!(h.chat.clients[&alice].nick, "alice");
+        h.send(alice, "hi");
+        assert!(h.output(bob).contains("alice> hi"));
+    }
+
+    #[test]
+    fn set_rate_limits_apply_to_connected_clients() {
+        let mut h = Harness::new(&["--message-rate", "5"]);
+        let admin = h.join("admin");
+        let bob = h.join("bob");
+        h.chat.admins.insert(admin);
+        h.send(bob, "one");
+        h.send(admin, "/set message-rate 1");
+        h.send(bob, "two");
+        h.send(bob, "three");
+        assert!(h.output(bob).contains("slow down, too many messages"));
+        assert!(!h.output(admin).contains("bob> three"));
+        h.send(admin, "/set message-rate off");
+        h.send(admin, "/set slow-mode 60");
+        // Counted from the last message that went through
+        h.send(bob, "four");
+        assert!(h.output(bob).contains("slow mode is on"));
+        let client = h.chat.clients.get_mut(&bob).unwrap();
+        let (hash, at) = client.last_message.unwrap();
+        client.last_message = Some((hash, at - Duration::from_secs(60)));
+        h.send(bob, "four");
+        assert!(h.output(admin).contains("bob> four"));
+        h.send(bob, "five");
+        assert!(h.output(bob).contains("slow mode is on"));
+        assert!(!h.output(admin).contains("bob> five"));
+        h.send(admin, "/set slow-mode off");
+        h.send(bob, "six");
+        assert!(h.output(admin).contains("bob> six"));
+    }
 }
@@ -0,0 +1,112 @@
+// A stand-in for a socket, to drive the read and flush paths byte by byte
+// without a network: input is queued with `push_input`, output collected
+// with `take_output`, and `WouldBlock` and end of stream happen on demand.
+use std::collections::VecDeque;
+use std::io::{self, IoSlice, Read, Write};
+
+// What the next read returns, in order
+enum Input {
+    Data(Vec<u8>),
+    WouldBlock,
+    Eof,
+}
+
+#[derive(Default)]
+pub struct MemoryStream {
+    input: VecDeque<Input>,
+    output: Vec<u8>,
+    // Bytes each write takes at most, `None` for all of them
+    write_limit: Option<usize>,
+    // Writes left before they start failing with `WouldBlock`
+    writes_left: Option<usize>,
+    // What every write fails with, like a peer that reset the connection
+    write_error: Option<io::ErrorKind>,
+    // Most buffers offered to a single vectored write
+    most_slices: usize,
+}
+
+impl MemoryStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // Makes `data` readable. It can take several reads if they're short.
+    pub fn push_input(&mut self, data: &[u8]) {
+        self.input.push_back(Input::Data(data.to_vec()));
+    }
+    // The read after the input queued so far fails with `WouldBlock`. With
+    // nothing queued, reads do that anyway.
+    pub fn push_would_block(&mut self) {
+        self.input.push_back(Input::WouldBlock);
+    }
+    // The read after the input queued so far returns `Ok(0)`, and so do
+    // all the following ones
+    pub fn push_eof(&mut self) {
+        self.input.push_back(Input::Eof);
+    }
+    // Everything written since the last call
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+    // Writes take at most `limit` bytes, like a nearly full socket buffer
+    pub fn limit_writes(&mut self, limit: Option<usize>) {
+        self.write_limit = limit;
+    }
+    // After `n` more writes, they fail with `WouldBlock`. `None` lifts it.
+    pub fn block_writes_after(&mut self, n: Option<usize>) {
+        self.writes_left = n;
+    }
+    // Writes fail with `kind` from now on. `None` lifts it.
+    pub fn fail_writes(&mut self, kind: Option<io::ErrorKind>) {
+        self.write_error = kind;
+    }
+    pub fn most_slices(&self) -> usize {
+        self.most_slices
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.input.front_mut() {
+            None => Err(io::ErrorKind::WouldBlock.into()),
+            Some(Input::Eof) => Ok(0),
+            Some(Input::WouldBlock) => {
+                self.input.pop_front();
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+            Some(Input::Data(data)) => {
+                let n = data.len().min(buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                data.drain(..n);
+                if data.is_empty() {
+                    self.input.pop_front();
+                }
+                Ok(n)
+            }
+        }
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(kind) = self.write_error {
+            return Err(kind.into());
+        }
+        match &mut self.writes_left {
+            Some(0) => return Err(io::ErrorKind::WouldBlock.into()),
+            Some(n) => *n -= 1,
+            None => {}
+        }
+        let n = buf.len().min(self.write_limit.unwrap_or(usize::MAX));
+        self.output.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+    // Takes from as many buffers as the limit allows, like `writev`
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.most_slices = self.most_slices.max(bufs.len());
+        let all: Vec<u8> = bufs.iter().flat_map(|b| b.iter().copied()).collect();
+        self.write(&all)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
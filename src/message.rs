@@ -0,0 +1,94 @@
+use crate::hmac::{hex, hmac_sha256};
+use std::rc::Rc;
+
+// Something to send to clients, before it's rendered for each of them
+pub struct Message<'a> {
+    // Who said it, `None` for the server's notices and replies
+    pub nick: Option<&'a str>,
+    pub text: &'a [u8],
+    // The key chat messages are signed with, when the server signs them.
+    // Each rendering is signed as it's sent, see `signature`.
+    pub sign_key: Option<Rc<[u8]>>,
+    // Assigned to chat messages, so JSON clients can refer to them
+    pub id: Option<u64>,
+    // The id of the message this one answers, in a thread
+    pub reply_to: Option<u64>,
+    // Sent with /msg to a single client
+    pub private: bool,
+    pub priority: Priority,
+}
+
+// Where a message goes in each outbox
+#[derive(Clone, Copy, PartialEq)]
+pub enum Priority {
+    Normal,
+    // Announcements, queued ahead of the chatter waiting to be sent
+    High,
+}
+
+impl<'a> Message<'a> {
+    pub fn chat(nick: &'a str, text: &'a [u8]) -> Self {
+        Self {
+            nick: Some(nick),
+            text,
+            sign_key: None,
+            id: None,
+            reply_to: None,
+            private: false,
+            priority: Priority::Normal,
+        }
+    }
+    pub fn private(nick: &'a str, text: &'a [u8]) -> Self {
+        Self {
+            private: true,
+            ..Self::chat(nick, text)
+        }
+    }
+    pub fn with_id(mut self, id: u64) -> Self {
+        self.id = Some(id);
+        self
+    }
+    pub fn urgent(mut self) -> Self {
+        self.priority = Priority::High;
+        self
+    }
+    pub fn signed(mut self, key: Rc<[u8]>) -> Self {
+        if self.nick.is_some() {
+            self.sign_key = Some(key);
+        }
+        self
+    }
+    // Hex HMAC of `shown`, the message exactly as a client gets it, if
+    // it's signed
+    pub fn signature(&self, shown: &[u8]) -> Option<String> {
+        let key = self.sign_key.as_ref()?;
+        Some(hex(&hmac_sha256(key, shown)))
+    }
+    pub fn system(text: &'a [u8]) -> Self {
+        Self {
+            nick: None,
+            text,
+            sign_key: None,
+            id: None,
+            reply_to: None,
+            private: false,
+            priority: Priority::Normal,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_chat_is_signed() {
+        let key: Rc<[u8]> = Rc::from(&b"secret"[..]);
+        let chat = Message::chat("alice", b"hi").signed(key.clone());
+        let expected = hex(&hmac_sha256(b"secret", b"alice> hi"));
+        assert_eq!(chat.signature(b"alice> hi"), Some(expected));
+        assert_ne!(chat.signature(b"alice> hi"), chat.signature(b"alice> ho"));
+        let notice = Message::system(b"* notice").signed(key);
+        assert_eq!(notice.signature(b"* notice"), None);
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+const ADJECTIVES: &[&str] = &[
+    "brave", "calm", "clever", "eager", "fancy", "gentle", "happy", "jolly", "kind", "lucky",
+    "merry", "nimble", "proud", "quick", "quiet", "shy", "sleepy", "swift", "witty", "zany",
+];
+const ANIMALS: &[&str] = &[
+    "badger", "beaver", "crane", "falcon", "ferret", "fox", "gecko", "heron", "koala", "lemur",
+    "lynx", "marten", "moose", "otter", "owl", "panda", "puffin", "raven", "seal", "yak",
+];
+
+// How nicks are made up for clients that haven't picked one
+#[derive(Clone)]
+pub enum NickScheme {
+    // Random, like `brave-otter`
+    Animal,
+    // The prefix followed by a counter, like `guest1`
+    Sequential(String),
+}
+
+impl NickScheme {
+    // `animal`, `guest` or `prefix:<prefix>`
+    pub fn parse(scheme: &str) -> Option<Self> {
+        match scheme {
+            "animal" => Some(NickScheme::Animal),
+            "guest" => Some(NickScheme::Sequential("guest".into())),
+            _ => match scheme.strip_prefix("prefix:") {
+                Some(prefix) if !prefix.is_empty() && !prefix.contains(' ') => {
+                    Some(NickScheme::Sequential(prefix.into()))
+                }
+                _ => None,
+            },
+        }
+    }
+}
+
+pub struct NickGenerator {
+    scheme: NickScheme,
+    // Last number handed out by a sequential scheme
+    counter: u64,
+}
+
+impl NickGenerator {
+    pub fn new(scheme: NickScheme) -> Self {
+        Self { scheme, counter: 0 }
+    }
+    // A nick for which `taken` is false
+    pub fn next(&mut self, taken: impl Fn(&str) -> bool) -> String {
+        for attempt in 0.. {
+            let nick = match &self.scheme {
+                NickScheme::Animal => {
+                    let r = random();
+                    let adjective = ADJECTIVES[r as usize % ADJECTIVES.len()];
+                    let animal = ANIMALS[(r >> 32) as usize % ANIMALS.len()];
+                    // All the pairs may be taken; past a few tries, add a number
+                    if attempt < 8 {
+                        format!("{adjective}-{animal}")
+                    } else {
+                        format!("{adjective}-{animal}{}", (r >> 16) % 10000)
+                    }
+                }
+                NickScheme::Sequential(prefix) => {
+                    self.counter += 1;
+                    format!("{prefix}{}", self.counter)
+                }
+            };
+            if !taken(&nick) {
+                return nick;
+            }
+        }
+        unreachable!()
+    }
+}
+
+// Every `RandomState` is seeded differently, which is random enough for
+// nicks and fortunes
+pub fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn schemes_parse() {
+        assert!(matches!(
+            NickScheme::parse("animal"),
+            Some(NickScheme::Animal)
+        ));
+        assert!(matches!(
+            NickScheme::parse("prefix:anon"),
+            Some(NickScheme::Sequential(prefix)) if prefix == "anon"
+        ));
+        assert!(NickScheme::parse("prefix:").is_none());
+        assert!(NickScheme::parse("prefix:a b").is_none());
+        assert!(NickScheme::parse("user").is_none());
+    }
+
+    #[test]
+    fn sequential_nicks_skip_taken_ones() {
+        let mut nicks = NickGenerator::new(NickScheme::parse("guest").unwrap());
+        assert_eq!(nicks.next(|_| false), "guest1");
+        assert_eq!(nicks.next(|nick| nick == "guest2"), "guest3");
+    }
+
+    #[test]
+    fn animal_nicks_are_unique() {
+        let mut nicks = NickGenerator::new(NickScheme::Animal);
+        let mut taken = HashSet::new();
+        // More than there are pairs, so numbers come in
+        for _ in 0..ADJECTIVES.len() * ANIMALS.len() + 50 {
+            let nick = nicks.next(|nick| taken.contains(nick));
+            let (adjective, animal) = nick.split_once('-').unwrap();
+            assert!(ADJECTIVES.contains(&adjective));
+            let animal = animal.trim_end_matches(|c: char| c.is_ascii_digit());
+            assert!(ANIMALS.contains(&animal));
+            assert!(taken.insert(nick));
+        }
+    }
+}
@@ -0,0 +1,110 @@
+// Daily windows, like 22:00-07:00, during which only admins can talk. Times
+// are UTC, like the rest of the server's clock.
+
+const DAY: u32 = 24 * 60 * 60;
+
+#[derive(Clone, Copy)]
+pub struct QuietHours {
+    // Seconds into the day the window starts and ends at
+    start: u32,
+    end: u32,
+}
+
+impl QuietHours {
+    // `HH:MM-HH:MM`. A window ending before it starts spans midnight.
+    pub fn parse(window: &str) -> Option<Self> {
+        let (start, end) = window.split_once('-')?;
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        (start != end).then_some(Self { start, end })
+    }
+    // Whether `secs` into the day falls inside the window
+    pub fn contains(&self, secs: u32) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&secs)
+        } else {
+            secs >= self.start || secs < self.end
+        }
+    }
+    // Seconds from `secs` into the day until the window next opens or closes
+    pub fn until_change(&self, secs: u32) -> u32 {
+        let until = |at: u32| match (at + DAY - secs) % DAY {
+            0 => DAY,
+            n => n,
+        };
+        until(self.start).min(until(self.end))
+    }
+    // When the window closes, as `HH:MM`
+    pub fn end(&self) -> String {
+        format!("{:02}:{:02}", self.end / 3600, self.end % 3600 / 60)
+    }
+}
+
+// Seconds into the day at `HH:MM`
+fn parse_time(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 3600 + minutes * 60)
+}
+
+// Seconds into the current UTC day, for `secs` since the Unix epoch
+pub fn time_of_day(secs: u64) -> u32 {
+    (secs % DAY as u64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn at(hours: u32, minutes: u32) -> u32 {
+        hours * 3600 + minutes * 60
+    }
+
+    #[test]
+    fn parses_windows() {
+        assert!(QuietHours::parse("22:00-07:00").is_some());
+        assert!(QuietHours::parse("09:30-17:45").is_some());
+        assert!(QuietHours::parse("10:00-10:00").is_none());
+        assert!(QuietHours::parse("24:00-07:00").is_none());
+        assert!(QuietHours::parse("22:60-07:00").is_none());
+        assert!(QuietHours::parse("22:00").is_none());
+        assert_eq!(QuietHours::parse("22:00-07:05").unwrap().end(), "07:05");
+    }
+
+    #[test]
+    fn start_is_inside_and_end_outside() {
+        let window = QuietHours::parse("09:00-17:00").unwrap();
+        assert!(!window.contains(at(9, 0) - 1));
+        assert!(window.contains(at(9, 0)));
+        assert!(window.contains(at(17, 0) - 1));
+        assert!(!window.contains(at(17, 0)));
+    }
+
+    #[test]
+    fn wraps_past_midnight() {
+        let window = QuietHours::parse("22:00-07:00").unwrap();
+        assert!(!window.contains(at(22, 0) - 1));
+        assert!(window.contains(at(22, 0)));
+        assert!(window.contains(DAY - 1));
+        assert!(window.contains(0));
+        assert!(window.contains(at(7, 0) - 1));
+        assert!(!window.contains(at(7, 0)));
+        assert!(!window.contains(at(12, 0)));
+    }
+
+    #[test]
+    fn until_change_counts_to_the_next_edge() {
+        let window = QuietHours::parse("22:00-07:00").unwrap();
+        assert_eq!(window.until_change(at(21, 0)), 3600);
+        // At an edge, the next change is the other one
+        assert_eq!(window.until_change(at(22, 0)), at(9, 0));
+        assert_eq!(window.until_change(at(23, 0)), at(8, 0));
+        assert_eq!(window.until_change(at(7, 0)), at(15, 0));
+        assert_eq!(window.until_change(DAY - 1), at(7, 0) + 1);
+    }
+
+    #[test]
+    fn time_of_day_is_utc() {
+        assert_eq!(time_of_day(0), 0);
+        assert_eq!(time_of_day(19_000 * DAY as u64 + 3661), 3661);
+    }
+}
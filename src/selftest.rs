@@ -0,0 +1,116 @@
+// `--self-test`: two clients connect to the server that just started, pick
+// nicks, and one says something the other must receive. They run on their
+// own thread with blocking sockets, while the event loop serves them as it
+// would anyone else.
+use mio::Waker;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// How long each expected reply may take
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+// Its own room, so `--no-global` doesn't get in the way
+const CHANNEL: &str = "#selftest";
+
+struct TestClient {
+    name: &'static str,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl TestClient {
+    fn connect(addr: SocketAddr, name: &'static str) -> Result<Self, String> {
+        let stream = TcpStream::connect_timeout(&addr, REPLY_TIMEOUT)
+            .map_err(|e| format!("{name} can't connect to {addr}: {e}"))?;
+        let writer = stream
+            .try_clone()
+            .map_err(|e| format!("{name} can't connect: {e}"))?;
+        let mut client = Self {
+            name,
+            reader: BufReader::new(stream),
+            writer,
+        };
+        // Whatever the MOTD says, the server has taken the client once it
+        // answers a command
+        client.send("/version")?;
+        client.expect(env!("CARGO_PKG_VERSION"))?;
+        Ok(client)
+    }
+    fn send(&mut self, line: &str) -> Result<(), String> {
+        self.writer
+            .write_all(format!("{line}\n").as_bytes())
+            .map_err(|e| format!("{} can't send {line:?}: {e}", self.name))
+    }
+    // Reads lines until one contains `needle`
+    fn expect(&mut self, needle: &str) -> Result<(), String> {
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        let mut line = String::new();
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(format!("{} never got {needle:?}", self.name));
+            }
+            let _ = self.reader.get_ref().set_read_timeout(Some(left));
+            line.clear();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return Err(format!("{} was disconnected", self.name)),
+                Ok(_) if line.contains(needle) => return Ok(()),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Err(format!("{} never got {needle:?}", self.name));
+                }
+                Err(e) => return Err(format!("{} can't read: {e}", self.name)),
+            }
+        }
+    }
+}
+
+// Runs the test against the server listening on `addr`, then wakes the
+// event loop with `waker` so it shuts down. The caller keeps a reference:
+// an eventfd closed before the loop polls it may never be reported.
+pub fn spawn(addr: SocketAddr, waker: Arc<Waker>) -> JoinHandle<Result<(), String>> {
+    thread::spawn(move || {
+        let result = run(loopback(addr));
+        let _ = waker.wake();
+        result
+    })
+}
+
+fn run(addr: SocketAddr) -> Result<(), String> {
+    // Tells this run apart from whatever else was said
+    let id = std::process::id();
+    let mut a = TestClient::connect(addr, "first client")?;
+    let mut b = TestClient::connect(addr, "second client")?;
+    for (client, nick) in [
+        (&mut a, format!("selftest{id}a")),
+        (&mut b, format!("selftest{id}b")),
+    ] {
+        client.send(&format!("/nick {nick}"))?;
+        client.expect(&format!("nick changed to {nick}"))?;
+        client.send(&format!("/join {CHANNEL}"))?;
+        client.expect(&format!("joined {CHANNEL}"))?;
+    }
+    let text = format!("self-test {id}");
+    a.send(&text)?;
+    b.expect(&format!("selftest{id}a> {text}"))?;
+    a.send("/quit")?;
+    b.send("/quit")?;
+    Ok(())
+}
+
+// Where to reach a server listening on `addr`, which may be every interface
+fn loopback(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+        _ => addr,
+    }
+}
@@ -0,0 +1,64 @@
+use mio::unix::pipe;
+use mio::{Interest, Registry, Token};
+use std::io::{self, Read};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+// Write end of the self-pipe, for the signal handler
+static PIPE_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    let fd = PIPE_FD.load(Ordering::Relaxed);
+    let byte = signal as u8;
+    // Only async-signal-safe calls in here. If the pipe is full the
+    // signal is already pending anyway.
+    unsafe {
+        libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+    }
+}
+
+// Turns signals into readable events on the poller, via a self-pipe
+pub struct Signals {
+    receiver: pipe::Receiver,
+    _sender: pipe::Sender,
+}
+
+impl Signals {
+    pub fn register(
+        registry: &Registry,
+        token: Token,
+        signals: &[libc::c_int],
+    ) -> io::Result<Self> {
+        let (sender, mut receiver) = pipe::new()?;
+        registry.register(&mut receiver, token, Interest::READABLE)?;
+        PIPE_FD.store(sender.as_raw_fd(), Ordering::Relaxed);
+        for signal in signals {
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_signal as *const () as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                if libc::sigaction(*signal, &action, std::ptr::null_mut()) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(Self {
+            receiver,
+            _sender: sender,
+        })
+    }
+    // The signals received since the last call
+    pub fn pending(&mut self) -> io::Result<Vec<libc::c_int>> {
+        let mut signals = Vec::new();
+        let mut buf = [0; 64];
+        loop {
+            match self.receiver.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => signals.extend(buf[..n].iter().map(|s| *s as libc::c_int)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(signals)
+    }
+}
@@ -0,0 +1,96 @@
+// What a client is read from and written to: a socket, or in tests a
+// `MemoryStream` that the event loop never hears about.
+#[cfg(any(test, feature = "test-transport"))]
+use crate::memstream::MemoryStream;
+use mio::event::Source;
+use mio::net::TcpStream;
+use mio::{Interest, Registry, Token};
+use std::io::{self, IoSlice, Read, Write};
+use std::net::SocketAddr;
+
+pub enum Stream {
+    Tcp(TcpStream),
+    // Only tests build clients on it
+    #[cfg(any(test, feature = "test-transport"))]
+    #[cfg_attr(not(test), allow(dead_code))]
+    Memory(MemoryStream),
+}
+
+impl Stream {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr(),
+            #[cfg(any(test, feature = "test-transport"))]
+            Stream::Memory(_) => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(any(test, feature = "test-transport"))]
+            Stream::Memory(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(any(test, feature = "test-transport"))]
+            Stream::Memory(stream) => stream.write(buf),
+        }
+    }
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write_vectored(bufs),
+            #[cfg(any(test, feature = "test-transport"))]
+            Stream::Memory(stream) => stream.write_vectored(bufs),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(any(test, feature = "test-transport"))]
+            Stream::Memory(stream) => stream.flush(),
+        }
+    }
+}
+
+// A memory stream is never polled: tests read and flush clients directly
+impl Source for Stream {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.register(registry, token, interests),
+            #[cfg(any(test, feature = "test-transport"))]
+            Stream::Memory(_) => Ok(()),
+        }
+    }
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.reregister(registry, token, interests),
+            #[cfg(any(test, feature = "test-transport"))]
+            Stream::Memory(_) => Ok(()),
+        }
+    }
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.deregister(registry),
+            #[cfg(any(test, feature = "test-transport"))]
+            Stream::Memory(_) => Ok(()),
+        }
+    }
+}
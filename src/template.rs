@@ -0,0 +1,107 @@
+// Variables in the MOTD, topics and the founder greeting, expanded for each
+// client as it's sent. Only the names below are known; the text around
+// them, unknown names included, is left as written.
+use std::time::Duration;
+
+pub struct Vars<'a> {
+    // The nick of the client it's sent to
+    pub nick: &'a str,
+    // Clients connected, web ones that haven't upgraded yet aside
+    pub clients: usize,
+    pub uptime: Duration,
+    pub version: &'a str,
+}
+
+impl Vars<'_> {
+    fn get(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "nick" => self.nick.to_string(),
+            "clients" => self.clients.to_string(),
+            "uptime" => format_uptime(self.uptime),
+            "version" => self.version.to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+// Replaces each `{name}` of a known variable with its value, in a single
+// pass: a value is never expanded in turn, so a nick like `{uptime}` shows
+// as is.
+pub fn expand(template: &str, vars: &Vars) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let value = after
+            .find('}')
+            .and_then(|end| Some((vars.get(&after[..end])?, end)));
+        match value {
+            Some((value, end)) => {
+                out.push_str(&value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+// The two largest units, like `3d 4h` or `5m 6s`
+fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{minutes}m {}s", secs % 60),
+        3600..=86399 => format!("{hours}h {minutes}m"),
+        _ => format!("{days}d {hours}h"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(nick: &str) -> Vars<'_> {
+        Vars {
+            nick,
+            clients: 3,
+            uptime: Duration::from_secs(3725),
+            version: "1.2.3",
+        }
+    }
+
+    #[test]
+    fn known_variables_expand_and_the_rest_is_left_as_written() {
+        assert_eq!(
+            expand("Welcome {nick}, {clients} users online", &vars("alice")),
+            "Welcome alice, 3 users online"
+        );
+        assert_eq!(
+            expand("v{version} up {uptime}", &vars("alice")),
+            "v1.2.3 up 1h 2m"
+        );
+        assert_eq!(
+            expand("{unknown} {nick {} {{nick}} {", &vars("bob")),
+            "{unknown} {nick {} {bob} {"
+        );
+        // Values aren't expanded in turn
+        assert_eq!(expand("hi {nick}", &vars("{uptime}")), "hi {uptime}");
+    }
+
+    #[test]
+    fn uptime_shows_the_two_largest_units() {
+        let uptime = |secs| format_uptime(Duration::from_secs(secs));
+        assert_eq!(uptime(0), "0s");
+        assert_eq!(uptime(59), "59s");
+        assert_eq!(uptime(61), "1m 1s");
+        assert_eq!(uptime(3600), "1h 0m");
+        assert_eq!(uptime(86400 * 3 + 3600 * 4 + 59), "3d 4h");
+    }
+}
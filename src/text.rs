@@ -0,0 +1,172 @@
+// Longest prefix of `bytes` that fits in `max` bytes without splitting a
+// UTF-8 character
+pub fn truncate_utf8(bytes: &[u8], max: usize) -> &[u8] {
+    if bytes.len() <= max {
+        return bytes;
+    }
+    let mut end = max;
+    // Continuation bytes look like 0b10xxxxxx
+    while end > 0 && bytes[end] & 0xc0 == 0x80 {
+        end -= 1;
+    }
+    &bytes[..end]
+}
+
+// Drops ANSI escape sequences, for clients that want plain text
+pub fn strip_ansi(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut iter = bytes.iter().copied().peekable();
+    while let Some(b) = iter.next() {
+        if b != 0x1b {
+            out.push(b);
+            continue;
+        }
+        if iter.next_if_eq(&b'[').is_some() {
+            // CSI: parameters, then a final byte in 0x40..=0x7e
+            for b in iter.by_ref() {
+                if (0x40..=0x7e).contains(&b) {
+                    break;
+                }
+            }
+        } else if iter.next_if_eq(&b']').is_some() {
+            // OSC, like a window title: up to BEL or ST (ESC \)
+            while let Some(b) = iter.next() {
+                if b == 0x07 || (b == 0x1b && iter.next_if_eq(&b'\\').is_some()) {
+                    break;
+                }
+            }
+        } else {
+            // Two-byte sequence
+            iter.next();
+        }
+    }
+    out
+}
+
+// Whether `text` matches `pattern`, where `*` stands for any run of characters
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let Some((first, rest)) = pattern.split_once('*') else {
+        return pattern == text;
+    };
+    let Some(mut text) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = rest.split('*').collect();
+    let last = parts.pop().unwrap_or_default();
+    for part in parts {
+        match text.find(part) {
+            Some(i) => text = &text[i + part.len()..],
+            None => return false,
+        }
+    }
+    text.len() >= last.len() && text.ends_with(last)
+}
+
+// What a client's input is decoded from. Everything is UTF-8 once decoded.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    // ISO-8859-1: every byte is the code point of the same value
+    Latin1,
+    // Windows-1252: Latin-1 with printable characters in 0x80..0xa0
+    Cp1252,
+}
+
+// What Windows-1252 has in 0x80..0xa0; the five holes are left as in Latin-1
+const CP1252_HIGH: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
+
+impl Encoding {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "latin1" | "latin-1" | "iso-8859-1" => Some(Encoding::Latin1),
+            "cp1252" | "windows-1252" => Some(Encoding::Cp1252),
+            _ => None,
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Latin1 => "latin1",
+            Encoding::Cp1252 => "cp1252",
+        }
+    }
+    // `bytes` as UTF-8. UTF-8 input is passed through as it is.
+    pub fn decode(self, bytes: &[u8]) -> Vec<u8> {
+        let decode_byte = |b: u8| match self {
+            Encoding::Cp1252 if (0x80..0xa0).contains(&b) => CP1252_HIGH[b as usize - 0x80],
+            _ => b as char,
+        };
+        match self {
+            Encoding::Utf8 => bytes.to_vec(),
+            Encoding::Latin1 | Encoding::Cp1252 => bytes
+                .iter()
+                .map(|&b| decode_byte(b))
+                .collect::<String>()
+                .into_bytes(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_on_character_boundaries() {
+        let text = "héllo wörld".as_bytes();
+        assert_eq!(truncate_utf8(text, 100), text);
+        assert_eq!(truncate_utf8(text, 1), b"h");
+        // `é` takes bytes 1 and 2, so it's dropped whole
+        assert_eq!(truncate_utf8(text, 2), b"h");
+        assert_eq!(truncate_utf8(text, 3), "hé".as_bytes());
+        assert_eq!(truncate_utf8("€".as_bytes(), 2), b"");
+        assert_eq!(truncate_utf8(b"", 0), b"");
+    }
+
+    #[test]
+    fn strips_ansi_sequences() {
+        assert_eq!(strip_ansi(b"\x1b[1;31mred\x1b[0m text"), b"red text");
+        assert_eq!(strip_ansi(b"\x1b[2Jclear"), b"clear");
+        // Two-byte sequences, like ESC c
+        assert_eq!(strip_ansi(b"a\x1bcb"), b"ab");
+        // Cut short at the end
+        assert_eq!(strip_ansi(b"end\x1b[1"), b"end");
+        assert_eq!(strip_ansi(b"plain"), b"plain");
+        // OSC, ended by BEL or ST
+        assert_eq!(strip_ansi(b"a\x1b]0;title\x07b"), b"ab");
+        assert_eq!(strip_ansi(b"a\x1b]8;;http://x\x1b\\link"), b"alink");
+        assert_eq!(strip_ansi(b"a\x1b]0;never ended"), b"a");
+    }
+
+    #[test]
+    fn decodes_legacy_encodings() {
+        let latin1 = Encoding::parse("ISO-8859-1").unwrap();
+        assert_eq!(latin1.decode(b"caf\xe9 \x80"), "café \u{80}".as_bytes());
+        let cp1252 = Encoding::parse("windows-1252").unwrap();
+        assert_eq!(
+            cp1252.decode(b"\x80 caf\xe9 \x81"),
+            "€ café \u{81}".as_bytes()
+        );
+        assert_eq!(Encoding::Utf8.decode(b"caf\xe9"), b"caf\xe9");
+        assert!(Encoding::parse("ebcdic").is_none());
+    }
+
+    #[test]
+    fn wildcards_match_any_run() {
+        assert!(wildcard_match("bot-*", "bot-ci"));
+        assert!(wildcard_match("bot-*", "bot-"));
+        assert!(!wildcard_match("bot-*", "robot-ci"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("*-feed", "news-feed"));
+        assert!(wildcard_match("a*b*c", "a-b-b-c"));
+        assert!(!wildcard_match("a*b*c", "a-c-b"));
+        // A suffix can't reuse what the prefix took
+        assert!(!wildcard_match("ab*ba", "aba"));
+        assert!(wildcard_match("exact", "exact"));
+        assert!(!wildcard_match("exact", "exactly"));
+    }
+}
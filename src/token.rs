@@ -0,0 +1,76 @@
+use mio::Token;
+use std::collections::BTreeSet;
+
+// Tokens below `CLIENT_BASE` are reserved for listeners and other
+// infrastructure sources registered with the poller.
+pub const SERVER: Token = Token(0);
+pub const SIGNALS: Token = Token(1);
+pub const WEB: Token = Token(2);
+pub const SELF_TEST: Token = Token(3);
+const CLIENT_BASE: usize = 16;
+
+// Hands out client tokens, reusing the ones released by disconnected clients.
+pub struct TokenAllocator {
+    next: usize,
+    free: BTreeSet<usize>,
+    // Released during the current poll batch. Later events in the same batch
+    // may still carry these tokens, so they only become reusable in `reclaim`.
+    released: Vec<usize>,
+}
+
+impl TokenAllocator {
+    pub fn new() -> Self {
+        Self {
+            next: CLIENT_BASE,
+            free: Default::default(),
+            released: Default::default(),
+        }
+    }
+    pub fn allocate(&mut self) -> Token {
+        // Lowest free token first, so the set of live tokens stays compact
+        if let Some(t) = self.free.pop_first() {
+            return Token(t);
+        }
+        let t = self.next;
+        self.next += 1;
+        Token(t)
+    }
+    pub fn release(&mut self, token: Token) {
+        debug_assert!(token.0 >= CLIENT_BASE && token.0 < self.next);
+        if token.0 >= CLIENT_BASE {
+            self.released.push(token.0);
+        }
+    }
+    // Call once the events of a poll batch have all been handled
+    pub fn reclaim(&mut self) {
+        self.free.extend(self.released.drain(..));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_tokens_skip_the_reserved_ones() {
+        let mut tokens = TokenAllocator::new();
+        for _ in 0..100 {
+            let token = tokens.allocate();
+            assert!(token.0 >= CLIENT_BASE);
+            assert!(![SERVER, SIGNALS, WEB, SELF_TEST].contains(&token));
+        }
+    }
+
+    #[test]
+    fn released_tokens_are_reused_after_the_batch() {
+        let mut tokens = TokenAllocator::new();
+        let (a, b) = (tokens.allocate(), tokens.allocate());
+        assert_eq!((a, b), (Token(CLIENT_BASE), Token(CLIENT_BASE + 1)));
+        tokens.release(a);
+        // Still in this batch, so it may yet show up in an event
+        assert_eq!(tokens.allocate(), Token(CLIENT_BASE + 2));
+        tokens.reclaim();
+        assert_eq!(tokens.allocate(), a);
+        assert_eq!(tokens.allocate(), Token(CLIENT_BASE + 3));
+    }
+}
@@ -0,0 +1,246 @@
+use log::error;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// How long a line may wait to be written
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+// Bytes buffered for a room, past which they're written right away
+const MAX_BUFFERED: usize = 64 * 1024;
+// Rotated files kept per room, `<room>.log.1` being the newest
+const MAX_ROTATED: usize = 5;
+// Files kept open at once; the least recently written is closed to make
+// room, and reopened in append mode when its room speaks again
+const MAX_OPEN_FILES: usize = 16;
+
+// A room's transcript file and what's waiting to go in it
+struct Transcript {
+    path: PathBuf,
+    // Opened on the first write
+    file: Option<File>,
+    // Bytes in the file so far
+    size: u64,
+    buffered: Vec<u8>,
+    // When it was last written to, on the `Transcripts::clock`
+    used: u64,
+}
+
+// Chat messages appended to a file per room with `--transcript-dir`,
+// `<channel>.log`, or `global.log` for the global room. A file past
+// `max_size` is rotated to `.log.1`, shifting older ones along.
+pub struct Transcripts {
+    dir: PathBuf,
+    max_size: u64,
+    // Rooms with an open file or buffered lines; the others are forgotten
+    rooms: HashMap<String, Transcript>,
+    // When the oldest buffered line was buffered
+    oldest: Option<Instant>,
+    // Counts writes, to find the least recently written file
+    clock: u64,
+}
+
+impl Transcripts {
+    pub fn new(dir: &Path, max_size: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            max_size,
+            rooms: HashMap::new(),
+            oldest: None,
+            clock: 0,
+        })
+    }
+    // Buffers a line for the room, `None` being the global room
+    pub fn record(&mut self, channel: Option<&str>, line: &str) {
+        let name = channel.unwrap_or("global");
+        let dir = &self.dir;
+        let room = self
+            .rooms
+            .entry(name.to_string())
+            .or_insert_with(|| Transcript {
+                path: dir.join(format!("{name}.log")),
+                file: None,
+                size: 0,
+                buffered: Vec::new(),
+                used: 0,
+            });
+        room.buffered.extend_from_slice(line.as_bytes());
+        room.buffered.push(b'\n');
+        self.oldest.get_or_insert_with(Instant::now);
+        if room.buffered.len() >= MAX_BUFFERED {
+            self.flush_room(name);
+        }
+    }
+    // When the buffered lines should be written
+    pub fn flush_at(&self) -> Option<Instant> {
+        Some(self.oldest? + FLUSH_INTERVAL)
+    }
+    // Writes what's buffered. Lines that can't be written are dropped,
+    // rather than kept piling up.
+    pub fn flush(&mut self) {
+        self.oldest = None;
+        let pending: Vec<String> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| !room.buffered.is_empty())
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in pending {
+            self.flush_room(&name);
+        }
+        self.rooms
+            .retain(|_, room| room.file.is_some() || !room.buffered.is_empty());
+    }
+    fn flush_room(&mut self, name: &str) {
+        self.clock += 1;
+        let Some(room) = self.rooms.get_mut(name) else {
+            return;
+        };
+        room.used = self.clock;
+        if let Err(e) = room.flush(self.max_size) {
+            error!("Can't write to {}: {e}", room.path.display());
+        }
+        self.close_idle();
+    }
+    // Closes the least recently written files past `MAX_OPEN_FILES`
+    fn close_idle(&mut self) {
+        let mut open: Vec<&mut Transcript> = self
+            .rooms
+            .values_mut()
+            .filter(|room| room.file.is_some())
+            .collect();
+        if open.len() > MAX_OPEN_FILES {
+            open.sort_unstable_by_key(|room| room.used);
+            let excess = open.len() - MAX_OPEN_FILES;
+            for room in &mut open[..excess] {
+                room.file = None;
+            }
+        }
+    }
+}
+
+impl Transcript {
+    fn flush(&mut self, max_size: u64) -> io::Result<()> {
+        let buffered = std::mem::take(&mut self.buffered);
+        for line in buffered.split_inclusive(|b| *b == b'\n') {
+            if self.file.is_none() {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?;
+                self.size = file.metadata()?.len();
+                self.file = Some(file);
+            }
+            // A line longer than `max_size` still gets a file to itself
+            if self.size > 0 && self.size + line.len() as u64 > max_size {
+                self.rotate()?;
+                self.file = Some(File::create(&self.path)?);
+            }
+            self.file.as_mut().unwrap().write_all(line)?;
+            self.size += line.len() as u64;
+        }
+        Ok(())
+    }
+    // Moves the file out of the way, shifting the older ones along
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        self.size = 0;
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{n}"));
+            PathBuf::from(path)
+        };
+        for n in (1..MAX_ROTATED).rev() {
+            match fs::rename(rotated(n), rotated(n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        fs::rename(&self.path, rotated(1))
+    }
+}
+
+impl Drop for Transcripts {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "smallchat-transcript-{name}-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn open_files_stay_bounded() {
+        let dir = temp_dir("bounded");
+        let mut transcripts = Transcripts::new(&dir, 1 << 20).unwrap();
+        for round in 0..2 {
+            for n in 0..MAX_OPEN_FILES * 4 {
+                let channel = format!("#room{n}");
+                transcripts.record(Some(&channel), &format!("line {round}"));
+                transcripts.flush();
+                let open = transcripts
+                    .rooms
+                    .values()
+                    .filter(|room| room.file.is_some())
+                    .count();
+                assert!(open <= MAX_OPEN_FILES, "{open} files open");
+            }
+        }
+        assert_eq!(transcripts.rooms.len(), MAX_OPEN_FILES);
+        drop(transcripts);
+        // Closed files were reopened in append mode
+        for n in 0..MAX_OPEN_FILES * 4 {
+            let text = fs::read_to_string(dir.join(format!("#room{n}.log"))).unwrap();
+            assert_eq!(text, "line 0\nline 1\n");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flush_keeps_the_cap_with_many_rooms_buffered() {
+        let dir = temp_dir("buffered");
+        let mut transcripts = Transcripts::new(&dir, 1 << 20).unwrap();
+        for n in 0..MAX_OPEN_FILES * 4 {
+            transcripts.record(Some(&format!("#room{n}")), "hello");
+        }
+        transcripts.flush();
+        let open = transcripts
+            .rooms
+            .values()
+            .filter(|room| room.file.is_some())
+            .count();
+        assert_eq!(open, MAX_OPEN_FILES);
+        drop(transcripts);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_past_max_size() {
+        let dir = temp_dir("rotate");
+        let mut transcripts = Transcripts::new(&dir, 10).unwrap();
+        transcripts.record(None, "first one");
+        transcripts.record(None, "second");
+        drop(transcripts);
+        assert_eq!(
+            fs::read_to_string(dir.join("global.log.1")).unwrap(),
+            "first one\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("global.log")).unwrap(),
+            "second\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
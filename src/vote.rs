@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+const MIN_OPTIONS: usize = 2;
+const MAX_OPTIONS: usize = 10;
+const MAX_QUESTION_LEN: usize = 200;
+const MAX_OPTION_LEN: usize = 50;
+
+// A poll started with /poll, open until it's closed. Votes are kept per
+// session rather than per token, which a later connection may get, so
+// voting again changes the vote rather than adding one.
+pub struct Ballot {
+    pub question: String,
+    pub options: Vec<String>,
+    // Session id of the client that started it
+    pub creator: u64,
+    // Index into `options` of each voter's choice
+    votes: HashMap<u64, usize>,
+}
+
+impl Ballot {
+    // Parses `"Question?" opt1 opt2 ...`. A question without quotes is a
+    // single word.
+    pub fn parse(args: &str, creator: u64) -> Result<Self, String> {
+        let args = args.trim();
+        let (question, rest) = match args.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').ok_or("unterminated question")?,
+            None => args.split_once(' ').unwrap_or((args, "")),
+        };
+        let question = question.trim();
+        if question.is_empty() {
+            return Err("the question is empty".into());
+        }
+        if question.len() > MAX_QUESTION_LEN || question.chars().any(char::is_control) {
+            return Err(format!(
+                "the question is limited to {MAX_QUESTION_LEN} bytes on one line"
+            ));
+        }
+        let options: Vec<String> = rest.split_whitespace().map(String::from).collect();
+        if !(MIN_OPTIONS..=MAX_OPTIONS).contains(&options.len()) {
+            return Err(format!(
+                "a poll needs {MIN_OPTIONS} to {MAX_OPTIONS} options"
+            ));
+        }
+        if options
+            .iter()
+            .any(|o| o.len() > MAX_OPTION_LEN || o.chars().any(char::is_control))
+        {
+            return Err(format!("options are limited to {MAX_OPTION_LEN} bytes"));
+        }
+        Ok(Self {
+            question: question.to_string(),
+            options,
+            creator,
+            votes: HashMap::new(),
+        })
+    }
+    // Records the vote for option `n`, counting from 1. Returns whether it
+    // replaced an earlier vote.
+    pub fn vote(&mut self, voter: u64, n: usize) -> Result<bool, String> {
+        if n == 0 || n > self.options.len() {
+            return Err(format!("pick an option from 1 to {}", self.options.len()));
+        }
+        Ok(self.votes.insert(voter, n - 1).is_some())
+    }
+    // The question and its numbered options, on one line
+    pub fn describe(&self) -> String {
+        let options: Vec<String> = self
+            .options
+            .iter()
+            .enumerate()
+            .map(|(i, o)| format!("{}) {o}", i + 1))
+            .collect();
+        format!("{} {}", self.question, options.join(" "))
+    }
+    // The question and how many votes each option got
+    pub fn results(&self) -> String {
+        let mut tally = vec![0; self.options.len()];
+        for &choice in self.votes.values() {
+            tally[choice] += 1;
+        }
+        let results: Vec<String> = self
+            .options
+            .iter()
+            .zip(tally)
+            .map(|(o, n)| format!("{o}: {n}"))
+            .collect();
+        format!("{} {}", self.question, results.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_quoted_and_bare_questions() {
+        let poll = Ballot::parse(r#" "Lunch where?" pizza sushi  tacos "#, 1).unwrap();
+        assert_eq!(poll.question, "Lunch where?");
+        assert_eq!(poll.options, ["pizza", "sushi", "tacos"]);
+        assert_eq!(poll.describe(), "Lunch where? 1) pizza 2) sushi 3) tacos");
+        let poll = Ballot::parse("tabs? yes no", 1).unwrap();
+        assert_eq!(poll.question, "tabs?");
+        assert!(Ballot::parse(r#""Lunch where? pizza sushi"#, 1).is_err());
+        assert!(Ballot::parse(r#""" a b"#, 1).is_err());
+        assert!(Ballot::parse("only one", 1).is_err());
+        let many = format!("q {}", ["o"; MAX_OPTIONS + 1].join(" "));
+        assert!(Ballot::parse(&many, 1).is_err());
+        let long = format!("q a {}", "b".repeat(MAX_OPTION_LEN + 1));
+        assert!(Ballot::parse(&long, 1).is_err());
+    }
+
+    #[test]
+    fn a_second_vote_replaces_the_first() {
+        let mut poll = Ballot::parse("tabs? yes no", 1).unwrap();
+        assert_eq!(poll.vote(1, 1), Ok(false));
+        assert_eq!(poll.vote(2, 1), Ok(false));
+        assert_eq!(poll.vote(2, 2), Ok(true));
+        assert!(poll.vote(3, 0).is_err());
+        assert!(poll.vote(3, 3).is_err());
+        assert_eq!(poll.results(), "tabs? yes: 1, no: 1");
+    }
+}
@@ -0,0 +1,223 @@
+// Just enough of RFC 6455 for the built-in web client: the handshake and
+// unfragmented frames.
+use std::ops::Range;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+pub const OP_PONG: u8 = 0xa;
+
+pub enum Decoded {
+    // Payload and frame length, both relative to the decoded buffer
+    Data(Range<usize>, usize),
+    Ping(Range<usize>, usize),
+    // Pongs and anything else not worth acting on
+    Ignored(usize),
+    Close,
+    Partial,
+    TooLarge,
+}
+
+// Decodes the frame at the start of `buf`, unmasking its payload in place
+pub fn decode(buf: &mut [u8], max_len: usize) -> Decoded {
+    if buf.len() < 2 {
+        return Decoded::Partial;
+    }
+    let opcode = buf[0] & 0x0f;
+    let masked = buf[1] & 0x80 != 0;
+    let (len, mut header) = match buf[1] & 0x7f {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as usize, 4),
+        127 if buf.len() >= 10 => (
+            u64::from_be_bytes(buf[2..10].try_into().unwrap()) as usize,
+            10,
+        ),
+        126 | 127 => return Decoded::Partial,
+        len => (len as usize, 2),
+    };
+    if len > max_len {
+        return Decoded::TooLarge;
+    }
+    let mask = if masked {
+        let Some(mask) = buf.get(header..header + 4) else {
+            return Decoded::Partial;
+        };
+        let mask: [u8; 4] = mask.try_into().unwrap();
+        header += 4;
+        Some(mask)
+    } else {
+        None
+    };
+    let end = header + len;
+    if buf.len() < end {
+        return Decoded::Partial;
+    }
+    if let Some(mask) = mask {
+        for (i, b) in buf[header..end].iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+    }
+    match opcode {
+        OP_TEXT | OP_BINARY => Decoded::Data(header..end, end),
+        OP_PING => Decoded::Ping(header..end, end),
+        OP_CLOSE => Decoded::Close,
+        _ => Decoded::Ignored(end),
+    }
+}
+
+// An unmasked frame, as sent by servers
+pub fn encode(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// The Sec-WebSocket-Accept value for a Sec-WebSocket-Key
+pub fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in padded.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, w) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*w);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    let mut out = [0; 20];
+    for (chunk, h) in out.chunks_mut(4).zip(h) {
+        chunk.copy_from_slice(&h.to_be_bytes());
+    }
+    out
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A frame as a browser sends it, masked
+    fn masked(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = encode(opcode, payload);
+        let header = frame.len() - payload.len();
+        frame[1] |= 0x80;
+        for (i, b) in frame[header..].iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+        frame.splice(header..header, mask);
+        frame
+    }
+
+    #[test]
+    fn accept_key_from_the_rfc() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn decodes_masked_frames() {
+        let mut frame = masked(OP_TEXT, b"Hello");
+        match decode(&mut frame, 1024) {
+            Decoded::Data(range, len) => {
+                assert_eq!(&frame[range], b"Hello");
+                assert_eq!(len, frame.len());
+            }
+            _ => panic!("expected data"),
+        }
+        let mut ping = masked(OP_PING, b"hi");
+        assert!(matches!(decode(&mut ping, 1024), Decoded::Ping(r, 8) if r == (6..8)));
+        let mut close = masked(OP_CLOSE, b"");
+        assert!(matches!(decode(&mut close, 1024), Decoded::Close));
+        let mut pong = masked(OP_PONG, b"");
+        assert!(matches!(decode(&mut pong, 1024), Decoded::Ignored(6)));
+    }
+
+    #[test]
+    fn partial_and_oversized_frames() {
+        let mut frame = masked(OP_TEXT, &[b'x'; 200]);
+        // The extended length says 200, past the limit
+        assert!(matches!(decode(&mut frame, 100), Decoded::TooLarge));
+        for cut in [1, 3, 6, frame.len() - 1] {
+            assert!(matches!(decode(&mut frame[..cut], 1024), Decoded::Partial));
+        }
+        assert!(matches!(decode(&mut frame, 1024), Decoded::Data(r, _) if r.len() == 200));
+    }
+
+    #[test]
+    fn encodes_each_length_form() {
+        assert_eq!(encode(OP_TEXT, b"hi"), [0x81, 2, b'h', b'i']);
+        assert_eq!(encode(OP_TEXT, &[0; 126])[..4], [0x81, 126, 0, 126]);
+        let long = encode(OP_TEXT, &[0; 0x10000]);
+        assert_eq!(long[..10], [0x81, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+    }
+}